@@ -9,9 +9,9 @@ pub fn main() {
     let mut server = Server::new(ADDRESS).unwrap();
     let mut client = Client::connect(ADDRESS).unwrap();
 
-    // Accept the connection from the client. This returns a Client object that can be used to communicate with the client.
+    // Accept the connection from the client. This returns the id of the client and a Client object that can be used to communicate with the client.
     // The client object is wrapped in an Arc<Mutex<Client>> to allow for thread-safe access.
-    let server_client = server.accept().unwrap();
+    let (_, server_client) = server.accept().unwrap();
 
     // Streams are how data is received.
     // You create a stream for the type you want to receive, and then call recv() on the client or server to get the data.
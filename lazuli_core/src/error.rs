@@ -0,0 +1,180 @@
+//! The error type for lazuli.
+//!
+//! Most errors come from the socket, and are kept as the `io::Error` they started as.
+//! Errors that lazuli detects itself get their own variant, so callers can match on why a receive failed.
+
+use std::{fmt, io};
+
+/// An error from sending or receiving data.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LazuliError {
+    /// An error from the socket, or from reading a value.
+    Io(io::Error),
+    /// The checksum in the header does not match the payload.
+    ChecksumMismatch {
+        /// The checksum in the header.
+        expected: u32,
+        /// The checksum of the payload that was received.
+        actual: u32,
+    },
+    /// The header could not be parsed, or does not match the packet.
+    BadHeader(String),
+    /// A packet was received, but it is not the type that was asked for.
+    TypeMismatch {
+        /// The name of the type that was asked for.
+        expected: &'static str,
+        /// The type id of the packet that was received.
+        found: u64,
+    },
+    /// A packet was received for a type that has no stream.
+    UnknownStream(u64),
+    /// A string was received that is not valid UTF-8.
+    Utf8(std::str::Utf8Error),
+    /// The peer's protocol version or registered types do not match ours.
+    HandshakeFailed(String),
+    /// A packet was received for a bounded stream that is full, and whose overflow policy is `Overflow::Error`.
+    /// The packet was dropped.
+    StreamFull {
+        /// The name of the type of the stream.
+        type_name: &'static str,
+        /// The capacity of the stream.
+        capacity: usize,
+    },
+}
+
+impl LazuliError {
+    /// Returns the `io::ErrorKind` that best describes the error.
+    ///
+    /// For `Io`, this is the kind of the underlying error. Errors about bad data are `InvalidData`, `UnknownStream` is `NotFound`,
+    /// and `StreamFull` is `Other`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            LazuliError::Io(e) => e.kind(),
+            LazuliError::UnknownStream(_) => io::ErrorKind::NotFound,
+            LazuliError::StreamFull { .. } => io::ErrorKind::Other,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+
+    /// Makes a copy of the error. `io::Error` can't be cloned, so an `Io` error is copied as its kind and message.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            LazuliError::Io(e) => LazuliError::Io(io::Error::new(e.kind(), e.to_string())),
+            LazuliError::ChecksumMismatch { expected, actual } => LazuliError::ChecksumMismatch {
+                expected: *expected,
+                actual: *actual,
+            },
+            LazuliError::BadHeader(reason) => LazuliError::BadHeader(reason.clone()),
+            LazuliError::TypeMismatch { expected, found } => LazuliError::TypeMismatch {
+                expected,
+                found: *found,
+            },
+            LazuliError::UnknownStream(id) => LazuliError::UnknownStream(*id),
+            LazuliError::Utf8(e) => LazuliError::Utf8(*e),
+            LazuliError::HandshakeFailed(reason) => LazuliError::HandshakeFailed(reason.clone()),
+            LazuliError::StreamFull {
+                type_name,
+                capacity,
+            } => LazuliError::StreamFull {
+                type_name,
+                capacity: *capacity,
+            },
+        }
+    }
+}
+
+impl fmt::Display for LazuliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LazuliError::Io(e) => write!(f, "{}", e),
+            LazuliError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksums do not match (Expected: {:#010x}, Got: {:#010x})",
+                expected, actual
+            ),
+            LazuliError::BadHeader(reason) => write!(f, "Bad header: {}", reason),
+            LazuliError::TypeMismatch { expected, found } => write!(
+                f,
+                "Received packet is not a {} (type_id {})",
+                expected, found
+            ),
+            LazuliError::UnknownStream(id) => write!(f, "No stream for type id {}", id),
+            LazuliError::Utf8(e) => write!(f, "Invalid UTF-8: {}", e),
+            LazuliError::HandshakeFailed(reason) => write!(f, "Handshake failed: {}", reason),
+            LazuliError::StreamFull {
+                type_name,
+                capacity,
+            } => write!(
+                f,
+                "Stream for {} is full (capacity: {})",
+                type_name, capacity
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LazuliError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LazuliError::Io(e) => Some(e),
+            LazuliError::Utf8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LazuliError {
+    fn from(e: io::Error) -> Self {
+        LazuliError::Io(e)
+    }
+}
+
+impl From<std::str::Utf8Error> for LazuliError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        LazuliError::Utf8(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for LazuliError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        LazuliError::Utf8(e.utf8_error())
+    }
+}
+
+/// This lets lazuli errors be returned from functions that return `io::Result`.
+impl From<LazuliError> for io::Error {
+    fn from(e: LazuliError) -> Self {
+        match e {
+            LazuliError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::LazuliError;
+
+    #[test]
+    fn test_io_conversion() {
+        let err: LazuliError = io::Error::new(io::ErrorKind::TimedOut, "timed out").into();
+        assert!(matches!(err, LazuliError::Io(_)));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err: io::Error = err.into();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let err: io::Error = LazuliError::UnknownStream(5).into();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "No stream for type id 5");
+    }
+
+    #[test]
+    fn test_utf8_conversion() {
+        let err: LazuliError = String::from_utf8(vec![0xFF]).unwrap_err().into();
+        assert!(matches!(err, LazuliError::Utf8(_)));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -2,20 +2,100 @@
 //!
 //! The header is used to ensure that the data is sent and received correctly.
 
-use std::{
-    fmt::Debug,
-    hash::{DefaultHasher, Hash, Hasher},
-    mem,
-};
+use std::{fmt::Debug, hash::Hash};
 
-use crate::{hash_type_id, Result, Sendable};
+use crate::{batch_type_id, hash_type_id, keyed_type_id, LazuliError, Result, Sendable};
 
 // RSOCK was the development name for this project.
 // TODO: Maybe change this to lazi or something similar.
 const HEADER: [u8; 5] = *b"RSOCK";
 
+/// The size of a PacketHeader when sent over the network.
+///
+/// The layout is, in order:
+/// - 5 bytes: the magic bytes `RSOCK`
+/// - 1 byte: flags (bit 0: has_checksum, bit 1: compressed, the rest are reserved)
+/// - 4 bytes: checksum (big-endian)
+/// - 4 bytes: payload_size (big-endian)
+/// - 8 bytes: type_id (big-endian)
+pub const HEADER_SIZE: usize = 22;
+
+/// Set in the flags byte if the header has a checksum.
+const FLAG_CHECKSUM: u8 = 1;
+/// Set in the flags byte if the payload is compressed.
+const FLAG_COMPRESSED: u8 = 1 << 1;
+
+/// The lookup table for CRC32 (IEEE 802.3, reflected polynomial 0xEDB88320).
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Calculates the CRC32 (IEEE) checksum of the data.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_parts(&[data])
+}
+
+/// Calculates the CRC32 (IEEE) checksum of the parts, as if they were one slice.
+pub(crate) fn crc32_parts(parts: &[&[u8]]) -> u32 {
+    let mut writer = Crc32Writer::new();
+    for part in parts {
+        writer.update(part);
+    }
+    writer.finish()
+}
+
+/// A writer that calculates the CRC32 (IEEE) checksum of everything written to it, without keeping the data.
+#[derive(Debug)]
+pub(crate) struct Crc32Writer {
+    crc: u32,
+}
+
+impl Crc32Writer {
+    pub(crate) fn new() -> Self {
+        Crc32Writer { crc: 0xFFFFFFFF }
+    }
+
+    /// Adds data to the checksum.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.crc = CRC32_TABLE[((self.crc ^ *byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    /// Returns the checksum of everything written so far.
+    pub(crate) fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl std::io::Write for Crc32Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
-#[repr(C)] // This is important for the safety of the from_bytes_unchecked function.
 /// The header of a packet. When a packet is sent over a socket, it is prepended with this header.
 /// This contains the type_id of the payload, the size of the payload, and a checksum of the payload.
 /// The checksum is used to verify that the payload was received correctly.
@@ -29,9 +109,10 @@ where
     // should always be "RSOCK"
     header: [u8; 5],
     has_checksum: bool,
+    compressed: bool,
     checksum: u32,
     pub payload_size: u32,
-    type_id: u32,
+    type_id: u64,
     // allow for some sort of type safety
     _phantom: std::marker::PhantomData<T>,
 }
@@ -41,6 +122,7 @@ impl<T: Sendable> Debug for PacketHeader<T> {
         f.debug_struct("PacketHeader")
             .field("header", &self.header)
             .field("has_checksum", &self.has_checksum)
+            .field("compressed", &self.compressed)
             .field("checksum", &self.checksum)
             .field("payload_size", &self.payload_size)
             .field("type_id", &self.type_id)
@@ -73,6 +155,7 @@ where
             header: HEADER,
             checksum: 0,
             has_checksum: false,
+            compressed: false,
             payload_size: std::mem::size_of::<T>() as u32,
             type_id: hash_type_id::<T>(),
             _phantom: std::marker::PhantomData,
@@ -90,6 +173,7 @@ where
             header: HEADER,
             checksum: 0,
             has_checksum: false,
+            compressed: false,
             payload_size,
             type_id: hash_type_id::<T>(),
             _phantom: std::marker::PhantomData,
@@ -97,9 +181,15 @@ where
     }
     /// Calculates the checksum of the payload. Sets the checksum field to the calculated checksum.
     pub(crate) fn calculate_checksum(&mut self, payload: &[u8]) {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(payload);
-        self.checksum = hasher.finish() as u32;
+        self.calculate_checksum_parts(&[payload]);
+    }
+    /// Calculates the checksum of a payload that is split into parts. Sets the checksum field to the calculated checksum.
+    pub(crate) fn calculate_checksum_parts(&mut self, parts: &[&[u8]]) {
+        self.set_checksum(crc32_parts(parts));
+    }
+    /// Sets the checksum field to a checksum that was already calculated.
+    pub(crate) fn set_checksum(&mut self, checksum: u32) {
+        self.checksum = checksum;
         self.has_checksum = true;
     }
     /// Verifies the checksum of the payload.
@@ -107,27 +197,86 @@ where
         if !self.has_checksum {
             return true;
         }
-        let mut hasher = DefaultHasher::new();
-        hasher.write(payload);
-        self.checksum == hasher.finish() as u32
-    }
-
-    /// Converts the PacketHeader into a byte array.
-    pub fn to_bytes(&self) -> [u8; mem::size_of::<PacketHeader<UnknownType>>()] {
-        unsafe {
-            // SAFETY: We know that PacketHeader<T> is the same size as PacketHeader<UnknownType>
-            let bytes = std::mem::transmute_copy::<
-                PacketHeader<T>,
-                [u8; mem::size_of::<PacketHeader<UnknownType>>()],
-            >(self);
-            bytes
+        self.verify_checksum_value(crc32(payload))
+    }
+    /// Verifies a checksum that was already calculated for the payload. (e.g. while it was being read)
+    pub(crate) fn verify_checksum_value(&self, checksum: u32) -> bool {
+        !self.has_checksum || self.checksum == checksum
+    }
+
+    /// Converts the PacketHeader into a byte array. See [`HEADER_SIZE`] for the layout.
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..5].copy_from_slice(&self.header);
+        let mut flags = 0;
+        if self.has_checksum {
+            flags |= FLAG_CHECKSUM;
+        }
+        if self.compressed {
+            flags |= FLAG_COMPRESSED;
         }
+        bytes[5] = flags;
+        bytes[6..10].copy_from_slice(&self.checksum.to_be_bytes());
+        bytes[10..14].copy_from_slice(&self.payload_size.to_be_bytes());
+        bytes[14..22].copy_from_slice(&self.type_id.to_be_bytes());
+        bytes
+    }
+
+    /// Returns true if the payload is compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Sets whether the payload is compressed.
+    ///
+    /// This does not compress anything. payload_size has to be updated to the size of the compressed payload separately.
+    pub(crate) fn set_compressed(&mut self, compressed: bool) {
+        self.compressed = compressed;
+    }
+
+    /// Replaces the type_id with the type_id of T combined with key, so the packet goes to the keyed stream for key.
+    ///
+    /// There is no spare field in the header, so the key is mixed into the type_id instead of sent on its own.
+    pub(crate) fn set_key(&mut self, key: u32) {
+        self.type_id = keyed_type_id::<T>(key);
+    }
+
+    /// Replaces the type_id with the batch type_id of T, so the packet is received as a batch of T's. (see `Client::send_batch`)
+    pub(crate) fn set_batch(&mut self) {
+        self.type_id = batch_type_id::<T>();
     }
 
-    /// Gets the type_id of the payload.
-    pub(crate) fn id(&self) -> u32 {
+    /// Erases T from the header, for sending values whose type is only known at runtime. (see [`crate::SendableAny`])
+    pub(crate) fn into_unknown(self) -> PacketHeader<UnknownType> {
+        self.retype()
+    }
+
+    /// Changes T without changing anything in the header, including the type_id.
+    ///
+    /// This is for types that are sent as another type. (e.g. `&T` is sent as a T)
+    pub(crate) fn retype<U: Sendable>(self) -> PacketHeader<U> {
+        PacketHeader {
+            header: self.header,
+            checksum: self.checksum,
+            has_checksum: self.has_checksum,
+            compressed: self.compressed,
+            payload_size: self.payload_size,
+            type_id: self.type_id,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Gets the type_id of the payload. This is the hash of the TypeId of the type that was sent.
+    ///
+    /// Values sent with `Client::send_keyed` have their key mixed in, so it does not match the type on its own.
+    pub fn type_id(&self) -> u64 {
         self.type_id
     }
+
+    /// Gets the checksum of the payload, or None if the packet was sent without one.
+    pub fn checksum(&self) -> Option<u32> {
+        self.has_checksum.then_some(self.checksum)
+    }
 }
 
 impl PacketHeader<UnknownType> {
@@ -135,14 +284,18 @@ impl PacketHeader<UnknownType> {
     /// # Safety
     /// The caller must ensure that the type_id and payload_size are correct.
     /// The caller must also ensure that the type T is the correct type.
-    pub unsafe fn into_ty<U: Copy + Sendable>(self) -> PacketHeader<U> {
-        assert_eq!(self.payload_size, std::mem::size_of::<U>() as u32);
+    ///
+    /// # Panics
+    /// Panics if the type_id is not the type_id of U.
+    pub unsafe fn into_ty<U: Sendable>(self) -> PacketHeader<U> {
+        // The payload size can't be checked here, because it is the size of the payload on the wire, not the size of U in memory.
         assert_eq!(self.type_id, hash_type_id::<U>());
 
         PacketHeader {
             header: self.header,
             checksum: self.checksum,
             has_checksum: self.has_checksum,
+            compressed: self.compressed,
             payload_size: self.payload_size,
             type_id: self.type_id,
             _phantom: std::marker::PhantomData,
@@ -153,20 +306,45 @@ impl PacketHeader<UnknownType> {
     /// This function is unsafe because it creates a PacketHeader from a byte array without checking the checksum.
     /// Use `PacketHeader::from_bytes` if you want to check the checksum.
     pub unsafe fn from_bytes_unchecked(bytes: &[u8]) -> PacketHeader<UnknownType> {
-        assert!(
-            bytes.len() == mem::size_of::<PacketHeader<UnknownType>>(),
-            "bytes.len() = {}",
-            bytes.len()
-        );
+        assert!(bytes.len() == HEADER_SIZE, "bytes.len() = {}", bytes.len());
         assert!(
             bytes.starts_with(&HEADER),
             "Header is not correct (Expected: {:?}, Got: {:?})",
             HEADER,
             &bytes[..5]
         );
-        // Safety: We just checked that the length of bytes is the same as the size of PacketHeader
-        // and that it starts with the HEADER.
-        unsafe { *(bytes.as_ptr() as *const PacketHeader<UnknownType>) }
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        PacketHeader {
+            header: HEADER,
+            has_checksum: bytes[5] & FLAG_CHECKSUM != 0,
+            compressed: bytes[5] & FLAG_COMPRESSED != 0,
+            checksum: u32_at(6),
+            payload_size: u32_at(10),
+            type_id: u64::from_be_bytes(bytes[14..22].try_into().unwrap()),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+    /// Parses a PacketHeader from a byte array, without checking the checksum.
+    ///
+    /// Unlike `from_bytes_unchecked`, this returns an `InvalidData` error instead of panicking
+    /// if the length or the magic bytes are wrong, so it is safe to use on untrusted input.
+    pub fn try_parse(bytes: &[u8]) -> Result<PacketHeader<UnknownType>> {
+        if bytes.len() != HEADER_SIZE {
+            return Err(LazuliError::BadHeader(format!(
+                "Invalid header length (Expected: {}, Got: {})",
+                HEADER_SIZE,
+                bytes.len()
+            )));
+        }
+        if !bytes.starts_with(&HEADER) {
+            return Err(LazuliError::BadHeader(format!(
+                "Header is not correct (Expected: {:?}, Got: {:?})",
+                HEADER,
+                &bytes[..5]
+            )));
+        }
+        // SAFETY: We just checked the length and the magic bytes.
+        Ok(unsafe { PacketHeader::from_bytes_unchecked(bytes) })
     }
     /// Creates a new PacketHeader from a byte array.
     pub fn from_bytes(bytes: &[u8], data: &[u8]) -> Option<PacketHeader<UnknownType>> {
@@ -174,7 +352,7 @@ impl PacketHeader<UnknownType> {
             unsafe { PacketHeader::<UnknownType>::from_bytes_unchecked(bytes) };
         assert_eq!(header.payload_size as usize, data.len());
         let checksum_ok: bool = header.verify_checksum(data);
-        let len_ok: bool = bytes.len() == mem::size_of::<PacketHeader<UnknownType>>();
+        let len_ok: bool = bytes.len() == HEADER_SIZE;
         let header_ok: bool = bytes.starts_with(&HEADER);
         if checksum_ok && len_ok && header_ok {
             Some(header)
@@ -201,10 +379,111 @@ mod tests {
         assert_eq!(header, ty_header);
     }
 
+    #[test]
+    fn test_into_ty_variable_size() {
+        let data = "Hello, World!".to_string();
+        let mut header = data.header();
+        header.calculate_checksum(&data.send());
+        let unknown = PacketHeader::try_parse(&header.to_bytes()).unwrap();
+        let ty_header = unsafe { unknown.into_ty::<String>() };
+        assert_eq!(header, ty_header);
+        assert_eq!(ty_header.payload_size, data.size());
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let mut header: PacketHeader<u32> = unsafe { PacketHeader::new(0x01020304) };
+        header.checksum = 0x0A0B0C0D;
+        header.has_checksum = true;
+        header.type_id = 0x1122334455667788;
+        let bytes = header.to_bytes();
+        assert_eq!(
+            bytes,
+            [
+                b'R', b'S', b'O', b'C', b'K', // magic
+                1,    // has_checksum
+                0x0A, 0x0B, 0x0C, 0x0D, // checksum
+                0x01, 0x02, 0x03, 0x04, // payload_size
+                0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // type_id
+            ]
+        );
+        let parsed = unsafe { PacketHeader::from_bytes_unchecked(&bytes) };
+        assert_eq!(parsed.checksum, 0x0A0B0C0D);
+        assert_eq!(parsed.payload_size, 0x01020304);
+        assert_eq!(parsed.type_id, 0x1122334455667788);
+        assert!(!parsed.is_compressed());
+
+        header.set_compressed(true);
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[5], 0b11);
+        let parsed = unsafe { PacketHeader::from_bytes_unchecked(&bytes) };
+        assert!(parsed.has_checksum);
+        assert!(parsed.is_compressed());
+    }
+
+    #[test]
+    fn test_type_id_is_64_bit() {
+        // These two ids would have collided when type ids were truncated to 32 bits.
+        let ids = [0x0000_0001_DEAD_BEEFu64, 0x0000_0002_DEAD_BEEFu64];
+        assert_eq!(ids[0] as u32, ids[1] as u32);
+        let parsed = ids.map(|id| {
+            let mut header: PacketHeader<u32> = PacketHeader::auto();
+            header.type_id = id;
+            PacketHeader::try_parse(&header.to_bytes())
+                .unwrap()
+                .type_id()
+        });
+        assert_eq!(parsed, ids);
+        assert_ne!(parsed[0], parsed[1]);
+    }
+
+    #[test]
+    fn test_try_parse() {
+        let header: PacketHeader<u32> = PacketHeader::auto();
+        let mut bytes = header.to_bytes();
+        let parsed = PacketHeader::try_parse(&bytes).unwrap();
+        assert_eq!(parsed.payload_size, 4);
+
+        let err = PacketHeader::try_parse(&bytes[..10]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        bytes[0] = b'X';
+        let err = PacketHeader::try_parse(&bytes).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_crc32() {
+        // The standard CRC32 check value.
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32_parts(&[b"1234", b"", b"56789"]), 0xCBF43926);
+        let mut header: PacketHeader<String> = unsafe { PacketHeader::new(0) };
+        header.calculate_checksum(b"123456789");
+        assert_eq!(header.checksum, 0xCBF43926);
+        assert!(header.verify_checksum(b"123456789"));
+        assert!(!header.verify_checksum(b"123456780"));
+    }
+
     #[test]
     fn test_new_auto() {
         let header: PacketHeader<u32> = PacketHeader::auto();
         assert_eq!(header.payload_size, 4);
         assert_eq!(header.type_id, hash_type_id::<u32>());
     }
+
+    #[test]
+    fn test_accessors() {
+        let data = "Hello, World!".to_string();
+        let mut header = data.header();
+        assert_eq!(header.type_id(), hash_type_id::<String>());
+        assert_eq!(header.payload_size, data.size());
+        assert_eq!(header.checksum(), None);
+
+        header.calculate_checksum(&data.send());
+        let parsed = PacketHeader::try_parse(&header.to_bytes()).unwrap();
+        assert_eq!(parsed.type_id(), hash_type_id::<String>());
+        assert_eq!(parsed.payload_size, data.size());
+        assert_eq!(parsed.checksum(), Some(crc32(&data.send())));
+    }
 }
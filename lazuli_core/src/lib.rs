@@ -5,6 +5,7 @@ use std::{
     hash::{DefaultHasher, Hash, Hasher},
 };
 
+mod error;
 pub mod header;
 mod net;
 mod sendable;
@@ -16,8 +17,13 @@ pub(crate) type ArcMutex<T> = std::sync::Arc<std::sync::Mutex<T>>;
 
 /// The result type for rsocks.
 ///
-///`std::io::Error` is used as the error type because it fits almost all use cases, and iis already widely used in the standard library.
-pub type Result<T> = std::result::Result<T, std::io::Error>;
+/// [`LazuliError`] converts from `std::io::Error`, so `?` works on io results.
+pub type Result<T> = std::result::Result<T, LazuliError>;
+
+/// The version of the wire format. This is exchanged by `Client::handshake`, so mismatched builds fail up front.
+///
+/// This should be bumped whenever the header or the encoding of a built-in type changes.
+pub const PROTOCOL_VERSION: u32 = 1;
 
 /// Configures the logging for testing.
 #[cfg(test)]
@@ -36,13 +42,53 @@ pub(crate) fn init_logging() {
 /// Hashes the type_id of T.
 // TODO: After some performance testing, determine if this should just be converted into a mem::transmute::<u128>(TypeId::of::<T>) or something similar.
 #[inline]
-fn hash_type_id<T: 'static>() -> u32 {
+fn hash_type_id<T: 'static>() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    any::TypeId::of::<T>().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes the type_id of T together with a key, for keyed streams.
+///
+/// This is used in place of [`hash_type_id`], so packets for a keyed stream only go to the stream with the same key.
+#[inline]
+fn keyed_type_id<T: 'static>(key: u32) -> u64 {
     let mut hasher = DefaultHasher::new();
     any::TypeId::of::<T>().hash(&mut hasher);
-    hasher.finish() as u32
+    key.hash(&mut hasher);
+    hasher.finish()
 }
 
+/// Hashes the type_id of T for a batch of T's. (see `Client::send_batch`)
+///
+/// This is different from the type_id of `Vec<T>`, so a batch goes to the stream for T instead of a stream for `Vec<T>`.
+#[inline]
+fn batch_type_id<T: 'static>() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    any::TypeId::of::<T>().hash(&mut hasher);
+    "batch".hash(&mut hasher);
+    hasher.finish()
+}
+
+pub use error::LazuliError;
 pub(crate) use header::*;
+#[cfg(feature = "tokio")]
+pub use net::AsyncClient;
 pub use net::Client;
-pub use net::Server;
-pub use sendable::Sendable;
+pub use net::ClientStats;
+pub use net::SocketConfig;
+#[cfg(unix)]
+pub use net::UnixServer;
+pub use net::DEFAULT_MAX_PAYLOAD_SIZE;
+pub use net::{ClientId, Server, ShutdownHandle};
+pub use net::{Socket, Transport};
+pub use net::{UdpClient, MAX_UDP_PAYLOAD};
+pub use sendable::{
+    max_length, set_max_length, CanonicalF32, CanonicalF64, Sendable, SendableAny, SendableBits,
+    SendableBytes, TypeRegistry, DEFAULT_MAX_LENGTH,
+};
+pub use stream::{Overflow, Stream};
+
+/// The rustls crate, so TLS configs can be made with the same version lazuli uses.
+#[cfg(feature = "tls")]
+pub use rustls;
@@ -0,0 +1,232 @@
+//! Contains the AsyncClient struct, which sends and receives data with tokio instead of blocking.
+
+use std::io;
+
+use log::{trace, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    hash_type_id, stream::Stream, LazuliError, PacketHeader, Result, Sendable, UnknownType,
+    HEADER_SIZE,
+};
+
+use super::{
+    compression, connector::StreamConnector, input, StreamCollection, UnhandledPackets,
+    DEFAULT_MAX_PAYLOAD_SIZE,
+};
+
+/// A client for sending and receiving data inside a tokio runtime.
+///
+/// Packets are the same as the ones [`Client`](crate::Client) sends, so the two can talk to each other.
+/// Only the I/O is async. Serialization still happens synchronously in `Sendable`.
+///
+/// The receive methods are not cancel safe. If one is cancelled (e.g. by `select!`) in the middle of a packet,
+/// the rest of the packet is read as the next header, so the connection can't be used afterward.
+pub struct AsyncClient {
+    socket: TcpStream,
+    streams: StreamCollection,
+    unhandled: UnhandledPackets,
+    /// Whether sent packets include a checksum.
+    checksum_enabled: bool,
+    /// The largest payload a received header can claim.
+    max_payload_size: u32,
+}
+
+impl AsyncClient {
+    pub fn from_stream(stream: TcpStream) -> Self {
+        AsyncClient {
+            socket: stream,
+            streams: Default::default(),
+            unhandled: Default::default(),
+            checksum_enabled: true,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+        }
+    }
+
+    pub async fn connect<T: ToSocketAddrs>(addr: T) -> Result<AsyncClient> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Sets whether sent packets include a checksum of their payload. Checksums are enabled by default.
+    pub fn set_checksum_enabled(&mut self, enabled: bool) {
+        self.checksum_enabled = enabled;
+    }
+
+    /// Sets the largest payload a received packet can claim. (see [`Client::set_max_payload_size`](crate::Client::set_max_payload_size))
+    pub fn set_max_payload_size(&mut self, max: u32) {
+        self.max_payload_size = max;
+    }
+
+    /// Sends data to the socket.
+    pub async fn send<T>(&mut self, data: &T) -> Result<()>
+    where
+        T: Sendable + 'static,
+    {
+        let bytes = data.send();
+        trace!("Sending data: {:?}", bytes);
+        let mut p_header = data.header();
+        if self.checksum_enabled {
+            p_header.calculate_checksum(&bytes);
+        }
+        self.socket.write_all(&p_header.to_bytes()).await?;
+        self.socket.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    /// Reads a single packet from the socket, verifies its checksum, and inflates it if it is compressed.
+    async fn read_packet(&mut self) -> Result<(PacketHeader<UnknownType>, Vec<u8>)> {
+        let mut header = [0; HEADER_SIZE];
+        match self.socket.read_exact(&mut header).await {
+            Ok(_) => {}
+            // Like Client::recv, EOF before a header is the peer closing the connection.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "The peer closed the connection",
+                )
+                .into())
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let mut header = PacketHeader::try_parse(&header)?;
+        input::check_payload_size(&header, self.max_payload_size)?;
+        let mut data = vec![0; header.payload_size as usize];
+        self.socket.read_exact(&mut data).await?;
+        trace!("Received packet: {:?} {:?}", header, data);
+        input::verify_checksum(&header, &data)?;
+        let data = compression::decompress(&mut header, data, self.max_payload_size)?;
+        Ok((header, data))
+    }
+
+    /// Receives data from the socket, and stores it in the stream for its type.
+    ///
+    /// If there is no stream for the type of the packet, it is kept in [`AsyncClient::unhandled_packets`].
+    /// If the peer closed the connection between packets, an error with the kind `ConnectionAborted` is returned.
+    pub async fn recv(&mut self) -> Result<()> {
+        let (header, data) = self.read_packet().await?;
+        if let Some(info) = self.streams.get_mut(&header.type_id()) {
+            info.push(&data, header)?;
+        } else {
+            warn!("No stream for type id {}", header.type_id());
+            self.unhandled.push((header.type_id(), data));
+        }
+        Ok(())
+    }
+
+    /// Receives exactly one packet, and returns it as T. (see [`Client::recv_into`](crate::Client::recv_into))
+    ///
+    /// Returns a `TypeMismatch` error if the packet is not a T.
+    pub async fn recv_into<T>(&mut self) -> Result<T>
+    where
+        T: Sendable + 'static,
+    {
+        let (header, data) = self.read_packet().await?;
+        if header.type_id() != hash_type_id::<T>() {
+            return Err(LazuliError::TypeMismatch {
+                expected: std::any::type_name::<T>(),
+                found: header.type_id(),
+            });
+        }
+        T::recv(&mut io::Cursor::new(data))
+    }
+
+    pub fn stream<T>(&mut self) -> Stream<T>
+    where
+        T: Sendable + Send + 'static,
+    {
+        let stream = Stream::new();
+        self.streams
+            .insert(hash_type_id::<T>(), StreamConnector::new(&stream));
+        stream
+    }
+
+    /// Takes the packets that were received without a stream for their type, as (type_id, payload).
+    pub fn unhandled_packets(&mut self) -> UnhandledPackets {
+        std::mem::take(&mut self.unhandled)
+    }
+
+    /// Shuts down the write half of the socket, so the peer sees the connection close.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.socket.shutdown().await?;
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl std::fmt::Debug for AsyncClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AsyncClient")
+            .field("socket", &self.socket)
+            .field("streams", &self.streams)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::AsyncClient;
+    use crate::LazuliError;
+
+    /// Creates a pair of connected async clients.
+    /// (client, server)
+    async fn make_async_pair() -> (AsyncClient, AsyncClient) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client = AsyncClient::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let server = listener.accept().await.unwrap().0;
+        (client, AsyncClient::from_stream(server))
+    }
+
+    #[tokio::test]
+    async fn test_async_send_recv() {
+        let (mut client, mut server) = make_async_pair().await;
+        let mut stream = client.stream::<String>();
+        server.send(&"Hello, world!".to_owned()).await.unwrap();
+        client.recv().await.unwrap();
+        assert_eq!(stream.get().unwrap(), "Hello, world!");
+
+        client.send(&"Hello, server!".to_owned()).await.unwrap();
+        let received: String = server.recv_into().await.unwrap();
+        assert_eq!(received, "Hello, server!");
+
+        client.send(&5u32).await.unwrap();
+        let err = server.recv_into::<String>().await.unwrap_err();
+        assert!(matches!(err, LazuliError::TypeMismatch { .. }));
+
+        client.shutdown().await.unwrap();
+        let err = server.recv().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+    }
+
+    #[tokio::test]
+    async fn test_async_with_blocking_client() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let blocking = tokio::task::spawn_blocking(move || {
+            let mut client = crate::Client::connect(addr).unwrap();
+            client.send(&vec![1u32, 2, 3]).unwrap();
+            client.recv_into::<String>().unwrap()
+        });
+        let mut server = AsyncClient::from_stream(listener.accept().await.unwrap().0);
+        let received: Vec<u32> = server.recv_into().await.unwrap();
+        assert_eq!(received, vec![1, 2, 3]);
+        server.send(&"Done".to_owned()).await.unwrap();
+        assert_eq!(blocking.await.unwrap(), "Done");
+    }
+}
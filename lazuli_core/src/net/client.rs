@@ -1,40 +1,268 @@
 use std::{
+    any::Any,
     fmt::Debug,
-    io::{self, Write},
-    net::{TcpStream, ToSocketAddrs},
-    sync::{Arc, Mutex},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
 };
 
-use log::trace;
+use log::{trace, warn};
 
-use crate::{hash_type_id, stream::Stream, ArcMutex, Result, Sendable};
+use crate::{
+    batch_type_id, hash_type_id,
+    header::Crc32Writer,
+    keyed_type_id,
+    sendable::{SendableAny, TypeRegistry},
+    stream::{Overflow, Stream},
+    ArcMutex, LazuliError, PacketHeader, Result, Sendable, SendableBytes, UnknownType, HEADER_SIZE,
+    PROTOCOL_VERSION,
+};
 
 use super::{
-    config::SocketConfig, connector::StreamConnector, input, listener::SocketListener,
-    StreamCollection,
+    compression,
+    config::SocketConfig,
+    connector::StreamConnector,
+    input::PacketReader,
+    listener::SocketListener,
+    socket::{BlockingWriter, Socket, Transport},
+    stats::{ClientStats, StatsCounters},
+    HandlerCollection, StreamCollection, UnhandledPackets,
 };
 /// A client for sending and receiving data.
 pub struct Client {
-    socket: ArcMutex<TcpStream>,
+    socket: ArcMutex<Socket>,
+    /// The address of the peer when the client was created. This is where `reconnect` connects to.
+    addr: Option<SocketAddr>,
     streams: ArcMutex<StreamCollection>,
+    handlers: ArcMutex<HandlerCollection>,
+    unhandled: ArcMutex<UnhandledPackets>,
     listener: Option<SocketListener>,
+    /// Holds any packet that was partially read by a non-blocking receive.
+    /// This is shared with the listener, so nothing is lost when switching between the two.
+    reader: ArcMutex<PacketReader>,
+    /// Reused by send_buffered, so it doesn't allocate for every packet.
+    send_buffer: Vec<u8>,
+    /// Packets waiting to be written, if buffering is enabled.
+    write_buffer: Option<Vec<u8>>,
+    /// Whether sent packets include a checksum.
+    checksum_enabled: bool,
+    /// The nonces of pongs that have been received, and a condvar that is notified when one arrives.
+    pongs: Arc<(Mutex<Vec<u64>>, Condvar)>,
+    /// The nonce of the next ping.
+    next_ping: u64,
+    /// The types that `recv_any` can receive.
+    types: TypeRegistry,
+    /// How much has been sent and received. This is shared with the listener.
+    stats: Arc<StatsCounters>,
+}
+
+/// The first packet sent by `Client::handshake`.
+#[derive(Debug)]
+struct Handshake {
+    version: u32,
+    /// A hash of the type ids of every registered stream.
+    registry: u64,
+}
+
+impl Sendable for Handshake {
+    const SIZE_CONST: bool = true;
+
+    fn size(&self) -> u32 {
+        12
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = self.version.send();
+        data.extend(self.registry.send());
+        data
+    }
+
+    fn recv(data: &mut dyn io::Read) -> Result<Self> {
+        Ok(Handshake {
+            version: u32::recv(data)?,
+            registry: u64::recv(data)?,
+        })
+    }
+}
+
+/// Sent by `Client::ping`. The peer answers with a Pong with the same nonce, without the application being involved.
+#[derive(Debug)]
+struct Ping(u64);
+
+/// The answer to a Ping.
+#[derive(Debug)]
+struct Pong(u64);
+
+impl Sendable for Ping {
+    const SIZE_CONST: bool = true;
+
+    fn size(&self) -> u32 {
+        8
+    }
+
+    fn send(&self) -> Vec<u8> {
+        self.0.send()
+    }
+
+    fn recv(data: &mut dyn io::Read) -> Result<Self> {
+        Ok(Ping(u64::recv(data)?))
+    }
+}
+
+impl Sendable for Pong {
+    const SIZE_CONST: bool = true;
+
+    fn size(&self) -> u32 {
+        8
+    }
+
+    fn send(&self) -> Vec<u8> {
+        self.0.send()
+    }
+
+    fn recv(data: &mut dyn io::Read) -> Result<Self> {
+        Ok(Pong(u64::recv(data)?))
+    }
+}
+
+/// A reader that shortens the read timeout of the socket before every read, so no read waits past the deadline.
+///
+/// A read timeout alone restarts with every read, so a peer that sends a byte at a time could make a packet take forever.
+struct DeadlineReader<'a> {
+    socket: &'a mut Socket,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        // A timeout of zero is an error, rather than "don't wait".
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The deadline passed before the packet arrived",
+            ));
+        }
+        self.socket.set_read_timeout(Some(remaining))?;
+        self.socket.read(buf)
+    }
+}
+
+/// A packet that has been serialized once, so it can be sent to many clients without serializing it again.
+/// (see `Server::broadcast`)
+pub(crate) struct PreparedPacket {
+    header: PacketHeader<UnknownType>,
+    checksum: u32,
+    payload: Vec<u8>,
+}
+
+impl PreparedPacket {
+    pub(crate) fn new<T: Sendable + 'static>(data: &T) -> Self {
+        let payload = data.send();
+        PreparedPacket {
+            header: data.header().into_unknown(),
+            checksum: crate::header::crc32(&payload),
+            payload,
+        }
+    }
+}
+
+/// Returns an `InvalidData` error if a streamed payload was not the size that its header says.
+fn check_streamed_size<T: Sendable>(header: &PacketHeader<T>, len: u64) -> Result<()> {
+    if len != header.payload_size as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "size() does not match the streamed payload (Expected: {}, Got: {})",
+                header.payload_size, len
+            ),
+        )
+        .into());
+    }
+    Ok(())
 }
 
+/// How many bytes a buffered client reads at once, and how many bytes it buffers before writing.
+const BUFFER_CAPACITY: usize = 8 * 1024;
+
+/// The name of the listener thread, before the peer address.
+const LISTENER_NAME: &str = "lazuli-listener";
+
 impl Client {
     pub fn from_stream(stream: TcpStream) -> Self {
-        Client {
-            socket: Arc::new(Mutex::new(stream)),
-            streams: Default::default(),
-            listener: None,
-        }
+        Self::from_socket(stream.into())
+    }
+
+    /// Creates a client that sends and receives over any transport, such as a Unix socket or an in-memory pipe.
+    ///
+    /// Things the transport doesn't support (see [`Transport`]) return an `Unsupported` error.
+    /// In particular, listening needs [`Transport::set_nonblocking`].
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> Self {
+        Self::from_socket(Socket::Custom(Box::new(transport)))
     }
 
-    pub fn from_arcmutex_socket(stream: ArcMutex<TcpStream>) -> Self {
-        Client {
+    /// Creates a client that sends and receives over socket, which may be a TLS connection.
+    pub fn from_socket(socket: Socket) -> Self {
+        Self::from_arcmutex_socket(Arc::new(Mutex::new(socket)))
+    }
+
+    pub fn from_arcmutex_socket(stream: ArcMutex<Socket>) -> Self {
+        let addr = stream.lock().unwrap().peer_addr().ok();
+        let client = Client {
+            addr,
             socket: stream,
             streams: Default::default(),
+            handlers: Default::default(),
+            unhandled: Default::default(),
             listener: None,
-        }
+            reader: Default::default(),
+            send_buffer: Vec::new(),
+            write_buffer: None,
+            checksum_enabled: true,
+            pongs: Default::default(),
+            next_ping: 0,
+            types: TypeRegistry::new(),
+            stats: Default::default(),
+        };
+        client.add_ping_handlers();
+        client
+    }
+
+    /// Adds the handlers that answer pings, and record pongs for `ping`.
+    fn add_ping_handlers(&self) {
+        // The handler only has a weak reference, so it doesn't keep the socket from being shut down when the client is dropped.
+        let socket = Arc::downgrade(&self.socket);
+        let stats = self.stats.clone();
+        let answer: super::Handler = Box::new(move |data| {
+            let pong = Pong(Ping::recv(data)?.0);
+            let Some(socket) = socket.upgrade() else {
+                return Ok(());
+            };
+            let bytes = pong.send();
+            let mut header = pong.header();
+            header.calculate_checksum(&bytes);
+            // This runs on the listener thread, where the socket is non-blocking.
+            let mut socket = socket.lock().unwrap();
+            let mut writer = BlockingWriter(&mut socket);
+            writer.write_all(&header.to_bytes())?;
+            writer.write_all(&bytes)?;
+            writer.flush()?;
+            stats.sent((HEADER_SIZE + bytes.len()) as u64);
+            Ok(())
+        });
+        let pongs = self.pongs.clone();
+        let record: super::Handler = Box::new(move |data| {
+            let nonce = Pong::recv(data)?.0;
+            let (received, available) = &*pongs;
+            received.lock().unwrap().push(nonce);
+            available.notify_all();
+            Ok(())
+        });
+        let mut handlers = self.handlers.lock().unwrap();
+        handlers.insert(hash_type_id::<Ping>(), answer);
+        handlers.insert(hash_type_id::<Pong>(), record);
     }
 
     pub(crate) fn with_streams(mut self, streams: ArcMutex<StreamCollection>) -> Self {
@@ -52,89 +280,859 @@ impl Client {
                 Err(_) => continue,
             }
         }
-        Err(io::Error::new(
-            io::ErrorKind::AddrNotAvailable,
-            "No available addresses",
-        ))
+        Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "No available addresses").into())
+    }
+
+    /// Connects to a Unix domain socket at path. (see [`UnixServer`])
+    ///
+    /// [`UnixServer`]: crate::UnixServer
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<std::path::Path>>(path: P) -> Result<Client> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        Ok(Self::from_transport(stream))
+    }
+
+    /// Connects to the address over TLS, checking the server's certificate against server_name.
+    ///
+    /// Packets are framed exactly as they are over plain TCP, so the peer must also be using TLS. (see [`Server::accept_tls`])
+    /// The TLS handshake is completed before this returns, so an untrusted certificate is reported here.
+    ///
+    /// [`Server::accept_tls`]: crate::Server::accept_tls
+    #[cfg(feature = "tls")]
+    pub fn connect_tls<T: ToSocketAddrs>(
+        addr: T,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Client> {
+        let server_name = rustls::pki_types::ServerName::try_from(server_name.to_owned())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self::from_socket(Socket::tls_client(
+            stream,
+            server_name,
+            config,
+        )?))
+    }
+
+    /// Connects to the same address as the client was originally connected to, replacing the socket.
+    ///
+    /// Registered streams and handlers keep working with the new connection. If the client was listening, the listener
+    /// is restarted on the new socket. Anything that was partially received or still buffered for the old socket is discarded.
+    /// Any configuration applied to the old socket is not applied to the new one.
+    /// A TLS client reconnects over TLS with the same configuration. The server side of a TLS connection can't reconnect.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let addr = self.addr.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "The client does not know the address it was connected to",
+            )
+        })?;
+        let stream = self.socket.lock().unwrap().reconnect(addr)?;
+        let was_listening = self.listener_started();
+        // The old connection is dead, so whatever error the listener stopped with doesn't matter.
+        let _ = self.stop_listening();
+        *self.socket.lock().unwrap() = stream;
+        self.reader.lock().unwrap().reset();
+        // The listener closes the streams when the old connection drops, but they receive from the new one.
+        for connector in self.streams.lock().unwrap().values() {
+            connector.set_closed(false);
+        }
+        if let Some(buf) = &mut self.write_buffer {
+            buf.clear();
+        }
+        if was_listening {
+            self.listen()?;
+        }
+        Ok(())
+    }
+
+    /// Connects to the address, and applies the configuration to the socket.
+    pub fn connect_with_config<T: ToSocketAddrs>(addr: T, config: &SocketConfig) -> Result<Client> {
+        Self::connect(addr)?.with_config(config)
     }
 
     /// Applies the configuration to the socket.
     pub fn with_config(self, config: &SocketConfig) -> Result<Self> {
-        config.apply_stream(&mut self.socket.lock().unwrap())?;
+        let socket = self.socket.lock().unwrap();
+        let tcp = socket.tcp().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "A SocketConfig can only be applied to a TCP connection",
+            )
+        })?;
+        config.apply_stream(tcp)?;
+        drop(socket);
         Ok(self)
     }
 
+    /// Enables buffering of reads and writes.
+    ///
+    /// Reads take as many bytes as are available (up to 8 KiB) at once, so several small packets can be received with one syscall.
+    /// Writes are held in a buffer until it holds 8 KiB, or until `flush` is called.
+    /// **Sent data is not guaranteed to reach the peer until `flush` is called.**
+    pub fn with_buffering(mut self) -> Self {
+        let mut reader = self.reader.lock().unwrap();
+        let max_payload_size = reader.max_payload_size();
+        *reader = PacketReader::with_read_buffer(BUFFER_CAPACITY);
+        reader.set_max_payload_size(max_payload_size);
+        drop(reader);
+        self.write_buffer = Some(Vec::with_capacity(BUFFER_CAPACITY));
+        self
+    }
+
+    /// Sets the largest payload a received packet can claim. The default is [`crate::DEFAULT_MAX_PAYLOAD_SIZE`]. (16 MiB)
+    ///
+    /// Packets that claim more are rejected with an `InvalidData` error before anything is allocated for them.
+    /// The payload is never read, so the connection can't be used afterward. This applies to the listener too.
+    pub fn set_max_payload_size(&mut self, max: u32) {
+        self.reader.lock().unwrap().set_max_payload_size(max);
+    }
+
+    /// Sets whether sent packets include a checksum of their payload. Checksums are enabled by default.
+    ///
+    /// Packets without a checksum are accepted by the receiver without verification, so this only affects the sending side.
+    /// Disabling checksums saves CPU on large payloads when the link is already trusted. (e.g. localhost, or a TLS tunnel)
+    pub fn set_checksum_enabled(&mut self, enabled: bool) {
+        self.checksum_enabled = enabled;
+    }
+
+    /// Writes any buffered packets to the socket.
+    ///
+    /// This does nothing if buffering is not enabled.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(buf) = &mut self.write_buffer {
+            if !buf.is_empty() {
+                let mut socket = self.socket.lock().unwrap();
+                let mut writer = BlockingWriter(&mut socket);
+                let result = writer.write_all(buf).and_then(|_| writer.flush());
+                // If the write failed, the stream is in an unknown state anyway, so don't try to send it again.
+                buf.clear();
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a packet made of parts to the socket, or to the write buffer if buffering is enabled.
+    fn write_packet(&mut self, parts: &[&[u8]]) -> Result<()> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        match &mut self.write_buffer {
+            Some(buf) => {
+                for part in parts {
+                    buf.extend_from_slice(part);
+                }
+                if buf.len() >= BUFFER_CAPACITY {
+                    self.flush()?;
+                }
+            }
+            None => {
+                let mut socket = self.socket.lock().unwrap();
+                let mut writer = BlockingWriter(&mut socket);
+                for part in parts {
+                    writer.write_all(part)?;
+                }
+            }
+        }
+        self.stats.sent(len as u64);
+        Ok(())
+    }
+
     /// Sends data to the socket.
     #[inline]
     pub fn send<T>(&mut self, data: &T) -> Result<()>
     where
-        T: Sendable + 'static + Debug,
+        T: Sendable + 'static,
     {
         let bytes = data.send();
         trace!("Sending data: {:?}", bytes);
         let mut p_header = data.header();
-        p_header.calculate_checksum(&bytes);
+        if self.checksum_enabled {
+            p_header.calculate_checksum(&bytes);
+        }
+        self.write_packet(&[&p_header.to_bytes(), &bytes])
+    }
+
+    /// Sends data to the keyed stream for T with the same key on the peer. (see [`Client::stream_keyed`])
+    ///
+    /// If the peer has no stream for the key, the packet is kept in its unhandled packets.
+    pub fn send_keyed<T>(&mut self, key: u32, data: &T) -> Result<()>
+    where
+        T: Sendable + 'static,
+    {
+        let bytes = data.send();
+        trace!("Sending data with key {}: {:?}", key, bytes);
+        let mut p_header = data.header();
+        p_header.set_key(key);
+        if self.checksum_enabled {
+            p_header.calculate_checksum(&bytes);
+        }
+        self.write_packet(&[&p_header.to_bytes(), &bytes])
+    }
+
+    /// Sends every item in items as one packet, so the header is only sent once instead of once per item.
+    ///
+    /// The peer pushes each item to its stream for T separately, in the order of items, exactly as if they were sent one at a time.
+    /// Batches are only received by the stream made by [`Client::stream`] or [`Client::bounded_stream`].
+    /// Handlers registered with [`Client::on`] and keyed streams don't receive them, so a batch without a stream is kept in the unhandled packets.
+    pub fn send_batch<T>(&mut self, items: &[T]) -> Result<()>
+    where
+        T: Sendable + 'static,
+    {
+        // A batch is sent in the same format as a Vec<T>.
+        let mut bytes = Vec::new();
+        (items.len() as u32).send_into(&mut bytes);
+        for item in items {
+            item.send_into(&mut bytes);
+        }
+        trace!("Sending a batch of {} items: {:?}", items.len(), bytes);
+        // SAFETY: The payload size is the size of the bytes that were just serialized.
+        let mut p_header: PacketHeader<T> = unsafe { PacketHeader::new(bytes.len() as u32) };
+        p_header.set_batch();
+        if self.checksum_enabled {
+            p_header.calculate_checksum(&bytes);
+        }
+        self.write_packet(&[&p_header.to_bytes(), &bytes])
+    }
+
+    /// Sends a packet that was already serialized. The checksum is only included if it is enabled for this client.
+    pub(crate) fn send_prepared(&mut self, packet: &PreparedPacket) -> Result<()> {
+        let mut p_header = packet.header;
+        if self.checksum_enabled {
+            p_header.set_checksum(packet.checksum);
+        }
+        self.write_packet(&[&p_header.to_bytes(), &packet.payload])
+    }
+
+    /// Sends a value whose type is only known at runtime. It is received the same as if it was sent with `send`.
+    pub fn send_any(&mut self, data: &dyn SendableAny) -> Result<()> {
+        let bytes = data.send_any();
+        trace!("Sending data: {:?}", bytes);
+        let mut p_header = data.header_any();
+        if self.checksum_enabled {
+            p_header.calculate_checksum(&bytes);
+        }
+        self.write_packet(&[&p_header.to_bytes(), &bytes])
+    }
+
+    /// Sends a byte slice to the socket, without copying it into a Vec first.
+    ///
+    /// This is received as a `Vec<u8>`, exactly as if a Vec with the same bytes was sent.
+    pub fn send_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        // SAFETY: This is sent in the same format as a Vec<u8>, and the size comes from SendableBytes, which uses that format.
+        let mut p_header: PacketHeader<Vec<u8>> =
+            unsafe { PacketHeader::new(SendableBytes(bytes).size()) };
+        let length = (bytes.len() as u32).to_be_bytes();
+        if self.checksum_enabled {
+            p_header.calculate_checksum_parts(&[&length, bytes]);
+        }
+        self.write_packet(&[&p_header.to_bytes(), &length, bytes])
+    }
+
+    /// Sends data to the socket, writing the header and payload with a single write.
+    ///
+    /// The packet is built in a buffer that is reused between calls, using `Sendable::send_into`.
+    /// This avoids an allocation per packet, and avoids the header being sent in its own TCP segment.
+    pub fn send_buffered<T>(&mut self, data: &T) -> Result<()>
+    where
+        T: Sendable + 'static,
+    {
+        let mut buf = std::mem::take(&mut self.send_buffer);
+        buf.clear();
+        // Leave space for the header, which needs the checksum of the payload.
+        buf.resize(HEADER_SIZE, 0);
+        data.send_into(&mut buf);
+        let mut p_header = data.header();
+        p_header.payload_size = (buf.len() - HEADER_SIZE) as u32;
+        if self.checksum_enabled {
+            p_header.calculate_checksum(&buf[HEADER_SIZE..]);
+        }
+        buf[..HEADER_SIZE].copy_from_slice(&p_header.to_bytes());
+        trace!("Sending packet: {:?}", buf);
+        let result = self.write_packet(&[&buf]);
+        self.send_buffer = buf;
+        result
+    }
+
+    /// Sends data to the socket, streaming the payload with `Sendable::send_chunked` instead of serializing it up front.
+    ///
+    /// This is meant for very large payloads (e.g. a huge `Vec<u8>`), which would otherwise be copied in full by `send`.
+    /// The payload size in the header comes from `size()`. If checksums are enabled, the payload is serialized twice:
+    /// once to calculate the checksum, and once to write it.
+    ///
+    /// If buffering is enabled, the buffer is flushed first, and the packet is written straight to the socket.
+    pub fn send_streaming<T>(&mut self, data: &T) -> Result<()>
+    where
+        T: Sendable + 'static,
+    {
+        let mut p_header = data.header();
+        if self.checksum_enabled {
+            let mut crc = Crc32Writer::new();
+            let len = data.send_chunked(&mut crc)?;
+            check_streamed_size(&p_header, len)?;
+            p_header.set_checksum(crc.finish());
+        }
+        self.flush()?;
         let mut socket = self.socket.lock().unwrap();
-        socket.write_all(&p_header.to_bytes())?;
-        socket.write_all(&bytes)?;
+        let mut writer = BlockingWriter(&mut socket);
+        writer.write_all(&p_header.to_bytes())?;
+        let len = data.send_chunked(&mut writer)?;
+        self.stats.sent(HEADER_SIZE as u64 + len);
+        // If this fails, the peer will misread the stream, but there is no way to take back what was written.
+        check_streamed_size(&p_header, len)
+    }
+
+    /// Sends data to the socket, compressing the payload with deflate.
+    ///
+    /// This is worth it for large payloads that compress well, like big blobs of bytes or text.
+    /// For small payloads, the compressed payload can end up larger than the original.
+    pub fn send_compressed<T>(&mut self, data: &T) -> Result<()>
+    where
+        T: Sendable + 'static,
+    {
+        let bytes = compression::compress(&data.send())?;
+        trace!("Sending compressed data: {:?}", bytes);
+        let mut p_header = data.header();
+        p_header.payload_size = bytes.len() as u32;
+        p_header.set_compressed(true);
+        // The checksum covers the compressed bytes, because those are what is sent.
+        if self.checksum_enabled {
+            p_header.calculate_checksum(&bytes);
+        }
+        self.write_packet(&[&p_header.to_bytes(), &bytes])?;
         Ok(())
     }
 
-    /// Receives data from the socket. This does not return anything, but instead stores the data in the stream.
-    pub fn recv(&mut self) -> Result<()> {
-        if self.listener.is_some() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
+    /// Writes bytes straight to the socket, without a header.
+    ///
+    /// This is an escape hatch for talking to peers that don't use lazuli, or for sending a packet that was framed by hand.
+    /// Nothing about the bytes is checked, so a lazuli peer will misread the stream unless it is expecting them
+    /// (e.g. with [`Client::recv_raw`]).
+    ///
+    /// If buffering is enabled, the buffer is flushed first, so the bytes are sent after everything sent before them.
+    pub fn send_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        self.flush()?;
+        trace!("Sending raw data: {:?}", bytes);
+        BlockingWriter(&mut self.socket.lock().unwrap()).write_all(bytes)?;
+        Ok(())
+    }
+
+    /// Reads exactly `len` bytes straight from the socket, without parsing a header.
+    ///
+    /// This is the other half of [`Client::send_raw`]. Any bytes that the client has already read past the last packet are
+    /// returned first, so nothing is lost between packets and raw reads.
+    ///
+    /// Returns an error if the listener is running, or if part of a packet has been read
+    /// (e.g. after a timed out receive), because the raw bytes would be taken from the middle of that packet.
+    pub fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.check_not_listening()?;
+        let mut reader = self.reader.lock().unwrap();
+        if reader.in_progress() {
+            return Err(io::Error::other(
+                "Cannot receive raw data while a packet is partially read. Finish receiving it first.",
+            )
+            .into());
+        }
+        let mut data = vec![0; len];
+        let buffered = reader.take_buffered(&mut data);
+        self.socket
+            .lock()
+            .unwrap()
+            .read_exact(&mut data[buffered..])?;
+        trace!("Received raw data: {:?}", data);
+        Ok(data)
+    }
+
+    /// Reads a single packet from the socket, verifies its checksum, and inflates it if it is compressed.
+    fn read_packet(&mut self) -> Result<(PacketHeader<UnknownType>, Vec<u8>)> {
+        self.read_packet_before(None)
+    }
+
+    /// Like `read_packet`, but if deadline is set, the whole packet has to arrive before it.
+    ///
+    /// The read timeout of the socket is changed while reading, so the caller has to restore it afterward.
+    fn read_packet_before(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<(PacketHeader<UnknownType>, Vec<u8>)> {
+        self.check_not_listening()?;
+        // Reading through the PacketReader finishes any packet that a non-blocking receive left halfway.
+        // It also verifies the checksum as the payload is read.
+        let mut socket = self.socket.lock().unwrap();
+        let mut reader = self.reader.lock().unwrap();
+        let max_payload_size = reader.max_payload_size();
+        let (mut header, data) = match deadline {
+            Some(deadline) => reader.read_packet(&mut DeadlineReader {
+                socket: &mut socket,
+                deadline,
+            })?,
+            None => reader.read_packet(&mut *socket)?,
+        };
+        drop((socket, reader));
+        trace!("Received packet: {:?} {:?}", header, data);
+        // Counted before inflating, so it is the size on the wire.
+        self.stats
+            .received((HEADER_SIZE + header.payload_size as usize) as u64);
+        let data = compression::decompress(&mut header, data, max_payload_size)?;
+        Ok((header, data))
+    }
+
+    /// Reads a single packet from the socket without blocking, and verifies its checksum.
+    ///
+    /// Returns `Ok(None)` if a full packet has not arrived yet. Anything that has arrived is kept for the next read.
+    /// The socket is left in blocking mode afterward.
+    fn read_packet_nonblocking(&mut self) -> Result<Option<(PacketHeader<UnknownType>, Vec<u8>)>> {
+        // The listener needs the socket to stay non-blocking, so check before touching it.
+        self.check_not_listening()?;
+        self.socket.lock().unwrap().set_nonblocking(true)?;
+        let result = self.read_packet();
+        self.socket.lock().unwrap().set_nonblocking(false)?;
+        match result {
+            Ok(packet) => Ok(Some(packet)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns an error if the listener is running, because it would be reading from the same socket.
+    fn check_not_listening(&self) -> Result<()> {
+        if self.listener_started() {
+            return Err(io::Error::other(
                 "Cannot receive data while listening. If you want to stop listening, call stop_listening() first.",
-            ));
+            ).into());
+        }
+        Ok(())
+    }
+
+    /// Passes a packet to its handler, or pushes it to its stream.
+    fn dispatch(&mut self, header: PacketHeader<UnknownType>, data: Vec<u8>) -> Result<()> {
+        // Handlers take priority over streams.
+        if let Some(handler) = self.handlers.lock().unwrap().get_mut(&header.type_id()) {
+            return handler(&mut io::Cursor::new(data));
         }
-        let header = input::input_header(&mut self.socket.lock().unwrap())?;
-        trace!("Received header: {:?}", header);
-        let data = input::input_data(&mut self.socket.lock().unwrap(), &header)?;
-        trace!("Received data: {:?}", data);
-        input::verify_checksum(&header, &data)?;
-        trace!("Checksum verified");
         let mut stream = self.streams.lock().unwrap();
-        if let Some(info) = stream.get_mut(&header.id()) {
-            info.push(data, header)?;
+        if let Some(info) = stream.get_mut(&header.type_id()) {
+            info.push(&data, header)?;
         } else {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "Stream not found for data",
-            ));
+            warn!("No stream or handler for type id {}", header.type_id());
+            self.unhandled
+                .lock()
+                .unwrap()
+                .push((header.type_id(), data));
         }
         Ok(())
     }
 
-    pub fn stream<T>(&mut self) -> Stream<T>
+    /// Receives data from the socket. This does not return anything, but instead stores the data in the stream.
+    ///
+    /// If there is no stream or handler for the type of the packet, it is kept in [`Client::unhandled_packets`].
+    ///
+    /// If the peer closed the connection between packets, an error with the kind `ConnectionAborted` is returned.
+    /// This is what a receive loop should stop on. If it closed in the middle of a packet, the kind is `UnexpectedEof`.
+    pub fn recv(&mut self) -> Result<()> {
+        let (header, data) = self.read_packet()?;
+        self.dispatch(header, data)
+    }
+
+    /// Receives data from the socket, waiting at most `timeout` for it to arrive.
+    ///
+    /// Returns an error with the kind `TimedOut` if no data arrived in time.
+    /// The previous read timeout of the socket is restored afterward, even if an error occurred.
+    ///
+    /// If the timeout happens in the middle of a packet, the part that was read is kept, and the next receive finishes the packet.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        let previous = self.socket.lock().unwrap().read_timeout()?;
+        self.socket
+            .lock()
+            .unwrap()
+            .set_read_timeout(Some(timeout))?;
+        let result = self.recv();
+        let restored = self.socket.lock().unwrap().set_read_timeout(previous);
+        match result {
+            // Depending on the platform, a read timeout is either WouldBlock or TimedOut.
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                restored?;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for data").into())
+            }
+            result => result.and(restored.map_err(Into::into)),
+        }
+    }
+
+    /// Tries to receive a packet without blocking, and stores it in its stream.
+    ///
+    /// Returns `Ok(true)` if a packet was received, and `Ok(false)` if no packet was available.
+    ///
+    /// If only part of a packet has arrived, it is kept, and the next receive continues it.
+    /// The socket is left in blocking mode afterward.
+    pub fn try_recv(&mut self) -> Result<bool> {
+        match self.read_packet_nonblocking()? {
+            Some((header, data)) => {
+                self.dispatch(header, data)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Receives every packet that has fully arrived, without blocking, and stores each in its stream.
+    ///
+    /// Returns the number of packets received. If a packet has only partially arrived, it is kept, and the next receive continues it.
+    /// The socket is left in blocking mode afterward.
+    pub fn recv_all_available(&mut self) -> Result<usize> {
+        let mut count = 0;
+        while let Some((header, data)) = self.read_packet_nonblocking()? {
+            self.dispatch(header, data)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Waits at most `timeout` for packets to arrive, then receives every packet that has fully arrived and stores each in its stream.
+    ///
+    /// This is for single-threaded event loops, which want to receive without blocking for long and without the listener thread.
+    /// Returns the number of packets received, which is 0 if nothing arrived in time.
+    /// Packets that have already arrived are received without waiting. The socket is left in blocking mode afterward.
+    pub fn poll(&mut self, timeout: Duration) -> Result<usize> {
+        let count = self.recv_all_available()?;
+        if count > 0 {
+            return Ok(count);
+        }
+        // A deadline instead of a read timeout, so a peer that keeps sending a byte at a time can't hold this up past timeout.
+        let deadline = Instant::now() + timeout;
+        let previous = self.socket.lock().unwrap().read_timeout()?;
+        let result = self.read_packet_before(Some(deadline));
+        let restored = self.socket.lock().unwrap().set_read_timeout(previous);
+        match result {
+            Ok((header, data)) => {
+                restored?;
+                self.dispatch(header, data)?;
+                Ok(1 + self.recv_all_available()?)
+            }
+            // Depending on the platform, a read timeout is either WouldBlock or TimedOut.
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                restored?;
+                Ok(0)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Receives exactly one packet, and returns it as T.
+    ///
+    /// This does not need a stream to be registered for T, which makes it useful for one-shot exchanges.
+    /// Returns a `TypeMismatch` error if the packet is not a T.
+    pub fn recv_into<T>(&mut self) -> Result<T>
     where
         T: Sendable + 'static,
+    {
+        let (header, data) = self.read_packet()?;
+        if header.type_id() != hash_type_id::<T>() {
+            return Err(LazuliError::TypeMismatch {
+                expected: std::any::type_name::<T>(),
+                found: header.type_id(),
+            });
+        }
+        T::recv(&mut io::Cursor::new(data))
+    }
+
+    /// Registers T, so [`Client::recv_any`] can receive it.
+    pub fn register_type<T>(&mut self)
+    where
+        T: Sendable + Send + 'static,
+    {
+        self.types.register::<T>();
+    }
+
+    /// Receives exactly one packet of any type registered with [`Client::register_type`].
+    ///
+    /// The value can be downcast back to its type with `Box::downcast`.
+    /// Like `recv_into`, this does not use streams or handlers. Returns an `UnknownStream` error if the type is not registered.
+    pub fn recv_any(&mut self) -> Result<Box<dyn Any + Send>> {
+        let (header, data) = self.read_packet()?;
+        self.types
+            .recv(header.type_id(), &mut io::Cursor::new(data))
+    }
+
+    /// Sends req, then waits for the next packet and returns it as Resp. (see [`Client::recv_into`])
+    ///
+    /// This does not need a stream to be registered for Resp. The next packet has to be the response,
+    /// so anything else the peer sends in between causes a `TypeMismatch` error.
+    /// Returns an error without sending anything if the listener is running, because it would take the response.
+    pub fn request<Req, Resp>(&mut self, req: &Req) -> Result<Resp>
+    where
+        Req: Sendable + 'static,
+        Resp: Sendable + 'static,
+    {
+        self.check_not_listening()?;
+        self.send(req)?;
+        // The request might be sitting in the write buffer, and the peer can't respond to it from there.
+        self.flush()?;
+        self.recv_into()
+    }
+
+    /// Sends a ping, and waits at most `timeout` for the peer to answer. Returns the round-trip time.
+    ///
+    /// The peer answers automatically when it receives the ping, either with its listener or with a method that
+    /// stores packets in streams (e.g. `recv`). If it is not receiving, the ping is not answered. Returns a `TimedOut` error if there is no answer in time.
+    ///
+    /// If this client is not listening, anything else that arrives while waiting is received as if by `recv`.
+    pub fn ping(&mut self, timeout: Duration) -> Result<Duration> {
+        let nonce = self.next_ping;
+        self.next_ping += 1;
+        let pongs = self.pongs.clone();
+        let (received, available) = &*pongs;
+        // Pongs for earlier pings that timed out don't count.
+        received.lock().unwrap().clear();
+        let start = Instant::now();
+        self.send(&Ping(nonce))?;
+        self.flush()?;
+        let deadline = start + timeout;
+        let mut nonces = received.lock().unwrap();
+        while !nonces.contains(&nonce) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "The peer did not answer the ping in time",
+                )
+                .into());
+            }
+            if self.listener_started() {
+                nonces = available.wait_timeout(nonces, remaining).unwrap().0;
+            } else {
+                // The pong is recorded by its handler, which needs the lock.
+                drop(nonces);
+                match self.recv_timeout(remaining) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                    Err(e) => return Err(e),
+                }
+                nonces = received.lock().unwrap();
+            }
+        }
+        Ok(start.elapsed())
+    }
+
+    /// Creates a stream that receives every T sent with `send` (or in a batch with `send_batch`).
+    ///
+    /// T must be Send, because values are pushed to the stream by the listener thread. Types like `Rc` can still be sent,
+    /// and received with `recv_into`.
+    pub fn stream<T>(&mut self) -> Stream<T>
+    where
+        T: Sendable + Send + 'static,
+    {
+        let stream = self.register_stream(hash_type_id::<T>(), Stream::new());
+        self.register_batches(&stream);
+        stream
+    }
+
+    /// Creates a stream for T that holds at most `capacity` items.
+    ///
+    /// When a T is received while the stream is full, `overflow` decides whether the oldest item is dropped,
+    /// or the new item is dropped and an error is returned.
+    ///
+    /// # Panics
+    /// Panics if capacity is 0.
+    pub fn bounded_stream<T>(&mut self, capacity: usize, overflow: Overflow) -> Stream<T>
+    where
+        T: Sendable + Send + 'static,
+    {
+        let stream = self.register_stream(
+            hash_type_id::<T>(),
+            Stream::with_capacity(capacity, overflow),
+        );
+        self.register_batches(&stream);
+        stream
+    }
+
+    /// Creates a stream for T that only receives values sent with [`Client::send_keyed`] and the same key.
+    ///
+    /// This allows several independent streams of the same type over one connection (e.g. one per channel or topic).
+    /// Keyed streams are separate from the stream made by [`Client::stream`], which only receives values sent with `send`.
+    pub fn stream_keyed<T>(&mut self, key: u32) -> Stream<T>
+    where
+        T: Sendable + Send + 'static,
+    {
+        self.register_stream(keyed_type_id::<T>(key), Stream::new())
+    }
+
+    /// Connects a stream to the client under id, replacing any existing stream with the same id.
+    fn register_stream<T>(&mut self, id: u64, stream: Stream<T>) -> Stream<T>
+    where
+        T: Sendable + Send + 'static,
     {
         // SAFETY: This is safe because the stream is connected to a StreamConnector, which is guaranteed to be valid.
-        let stream: Stream<T> = Stream::new();
         let info = StreamConnector::new(&stream);
+        self.streams.lock().unwrap().insert(id, info);
+        stream
+    }
+
+    /// Connects stream to the client so it also receives batches of T's. (see [`Client::send_batch`])
+    fn register_batches<T>(&mut self, stream: &Stream<T>)
+    where
+        T: Sendable + Send + 'static,
+    {
         self.streams
             .lock()
             .unwrap()
-            .insert(hash_type_id::<T>(), info);
-        stream
+            .insert(batch_type_id::<T>(), StreamConnector::new_batch(stream));
+    }
+
+    /// Registers a callback that runs whenever a T is received.
+    ///
+    /// If a callback is registered for T, it is used instead of any stream for T.
+    /// The callback must be `Send` because it runs on the listener thread when listening.
+    pub fn on<T>(&mut self, mut handler: impl FnMut(T) + Send + 'static)
+    where
+        T: Sendable + 'static,
+    {
+        let handler: super::Handler = Box::new(move |data| {
+            handler(T::recv(data)?);
+            Ok(())
+        });
+        self.handlers
+            .lock()
+            .unwrap()
+            .insert(hash_type_id::<T>(), handler);
     }
 
+    /// Starts a thread that receives packets as they arrive, and stores them in their streams (or passes them to their handlers).
+    ///
+    /// The thread is named `lazuli-listener` followed by the peer address. See [`Client::listen_named`] to pick the name.
+    ///
+    /// A packet that can't be delivered (e.g. its payload can't be decoded, or its stream is full) is logged, counted in
+    /// [`ClientStats::packets_dropped`](crate::ClientStats::packets_dropped), and skipped. Errors from the socket stop the listener
+    /// (see [`Client::error`]).
     pub fn listen(&mut self) -> Result<()> {
-        let listener = SocketListener::new(self.socket.clone(), self.streams.clone());
-        self.listener = Some(listener);
-        self.listener.as_mut().unwrap().run()?;
-        Ok(())
+        self.listen_named(LISTENER_NAME)
+    }
+
+    /// Like [`Client::listen`], but the listener thread is named `name` followed by the peer address.
+    ///
+    /// This makes it easier to tell the listeners of many clients apart in a debugger or profiler.
+    pub fn listen_named(&mut self, name: &str) -> Result<()> {
+        self.start_listener(name, None)
+    }
+
+    /// Like [`Client::listen`], but the listener stops itself once no packet has been received for `idle`.
+    ///
+    /// When it stops because it was idle, the streams are closed, and [`Client::error`] is an error with the kind `TimedOut`.
+    pub fn listen_until_idle(&mut self, idle: Duration) -> Result<()> {
+        self.start_listener(LISTENER_NAME, Some(idle))
+    }
+
+    /// Starts the listener, named name. If idle is set, it stops itself after that long without a packet.
+    fn start_listener(&mut self, name: &str, idle: Option<Duration>) -> Result<()> {
+        // The listener is kept after it is stopped, so listening again reuses it.
+        let listener = self.listener.get_or_insert_with(|| {
+            SocketListener::new(
+                self.socket.clone(),
+                self.streams.clone(),
+                self.handlers.clone(),
+                self.unhandled.clone(),
+                self.reader.clone(),
+                self.stats.clone(),
+            )
+        });
+        listener.run(name, idle)
+    }
+
+    /// Stops the listener, so data can be received with `recv` again.
+    ///
+    /// Returns the error that stopped the listener, if it stopped because of an error. Does nothing if the client is not listening.
+    pub fn stop_listening(&mut self) -> Result<()> {
+        match &mut self.listener {
+            Some(listener) if listener.is_started() => {
+                trace!("Stopping listener");
+                // This also puts the socket back in blocking mode, the way recv expects it.
+                listener.stop()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true if the listener has been started and not stopped, even if it exited because of an error.
+    ///
+    /// Until it is stopped, the socket may be non-blocking, so nothing else should read from it.
+    fn listener_started(&self) -> bool {
+        self.listener
+            .as_ref()
+            .is_some_and(SocketListener::is_started)
+    }
+
+    /// Returns true if the listener thread is running.
+    ///
+    /// This is false if `listen` was never called, if the listener was stopped, or if it exited because of an error.
+    pub fn is_listening(&self) -> bool {
+        self.listener.as_ref().is_some_and(|l| l.is_running())
+    }
+
+    /// Takes every packet that was received without a stream or handler for its type, in the order they arrived.
+    ///
+    /// Each packet is returned as its type id and its raw payload. This includes packets received by the listener.
+    pub fn unhandled_packets(&mut self) -> UnhandledPackets {
+        std::mem::take(&mut *self.unhandled.lock().unwrap())
     }
 
-    pub fn stop_listening(&mut self) {
-        if let Some(listener) = &mut self.listener {
-            println!("Stopping listener...");
-            listener.stop().unwrap();
+    /// Shuts down the read half, write half, or both halves of the connection.
+    ///
+    /// Shutting down the write half sends EOF to the peer, so a blocking receive on the other end returns instead of waiting forever.
+    /// Any buffered packets are flushed first.
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        if how != Shutdown::Read {
+            self.flush()?;
         }
+        Ok(self.socket.lock().unwrap().shutdown(how)?)
     }
 
-    pub fn error(&self) -> Option<io::Error> {
+    /// Exchanges the protocol version and the set of registered stream types with the peer.
+    ///
+    /// Both ends of the connection must call this, after registering their streams and before sending anything else.
+    /// Returns a `HandshakeFailed` error if the peer's `PROTOCOL_VERSION` or registered stream types don't match ours.
+    /// This catches mismatched builds up front, instead of as misrouted packets later.
+    pub fn handshake(&mut self) -> Result<()> {
+        let ours = Handshake {
+            version: PROTOCOL_VERSION,
+            registry: self.registry_hash(),
+        };
+        self.send(&ours)?;
+        self.flush()?;
+        let theirs: Handshake = self.recv_into()?;
+        if theirs.version != ours.version {
+            return Err(LazuliError::HandshakeFailed(format!(
+                "Protocol version mismatch (Ours: {}, Theirs: {})",
+                ours.version, theirs.version
+            )));
+        }
+        if theirs.registry != ours.registry {
+            return Err(LazuliError::HandshakeFailed(
+                "The peer has a different set of registered streams".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Hashes the type ids of every registered stream. The order they were registered in doesn't matter.
+    fn registry_hash(&self) -> u64 {
+        let mut ids: Vec<u64> = self.streams.lock().unwrap().keys().copied().collect();
+        ids.sort_unstable();
+        let mut hasher = DefaultHasher::new();
+        ids.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Gets how much has been sent and received, including packets received by the listener.
+    pub fn stats(&self) -> ClientStats {
+        self.stats.snapshot()
+    }
+
+    /// Gets the error that stopped the listener, if there is one.
+    ///
+    /// This is cleared by `stop_listening`, which returns the same error.
+    pub fn error(&self) -> Option<LazuliError> {
         self.listener.as_ref().and_then(|l| l.error())
     }
 
@@ -145,8 +1143,35 @@ impl Client {
     pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
         self.socket.lock().unwrap().peer_addr()
     }
-    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
-        self.socket.lock().unwrap().set_nonblocking(nonblocking)
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.socket.lock().unwrap().local_addr()
+    }
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.lock().unwrap().set_nonblocking(nonblocking)
+    }
+
+    /// Gets the socket, so tests in other modules can check how it was configured.
+    #[cfg(test)]
+    pub(crate) fn socket(&self) -> &ArcMutex<Socket> {
+        &self.socket
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Like BufWriter, make a best effort to send anything that is still buffered.
+        let _ = self.flush();
+        // Stop the listener first, because it holds a reference to the socket, and would report the shutdown as an error.
+        let _ = self.stop_listening();
+        self.listener = None;
+        // The socket may be shared (see from_arcmutex_socket), so only shut it down if nothing else is using it.
+        // Errors are ignored, because the socket may already be closed.
+        if Arc::strong_count(&self.socket) == 1 {
+            let _ = self
+                .socket
+                .lock()
+                .map(|mut socket| socket.shutdown(Shutdown::Both));
+        }
     }
 }
 
@@ -172,11 +1197,16 @@ impl Debug for Client {
 
 #[cfg(test)]
 mod tests {
-    use std::vec;
+    use std::{time::Duration, vec};
 
-    use crate::{net::test_utils::make_client_server_pair, stream::Stream, Result, Sendable};
+    use crate::{
+        hash_type_id,
+        net::test_utils::{make_client_server_pair, test_send_recv},
+        stream::Stream,
+        LazuliError, PacketHeader, Result, Sendable, SendableAny, HEADER_SIZE,
+    };
 
-    use super::StreamConnector;
+    use super::{SocketConfig, StreamConnector};
 
     macro_rules! test_send_recv_num {
         ($name: ident, $type: ty, $val: expr) => {
@@ -256,6 +1286,756 @@ mod tests {
         client.recv().unwrap();
         assert_eq!(stream.get().unwrap(), data);
     }
+    #[test]
+    fn test_send_recv_empty() {
+        let (mut client, mut server) = make_client_server_pair();
+        test_send_recv(&mut client, &mut server, ());
+        test_send_recv(&mut client, &mut server, vec![(); 3]);
+        test_send_recv(&mut client, &mut server, Vec::<()>::new());
+        test_send_recv(&mut client, &mut server, Some(()));
+        test_send_recv(&mut client, &mut server, None::<()>);
+        test_send_recv(&mut client, &mut server, Vec::<u8>::new());
+
+        // Empty payloads work without a checksum too.
+        server.set_checksum_enabled(false);
+        test_send_recv(&mut client, &mut server, ());
+        test_send_recv(&mut client, &mut server, Vec::<u8>::new());
+
+        // Empty payloads work with the listener too.
+        let mut stream = client.stream::<()>();
+        client.listen().unwrap();
+        server.send(&()).unwrap();
+        assert_eq!(stream.get_timeout(Duration::from_secs(5)), Some(()));
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let (mut client, mut server) = make_client_server_pair();
+        let _stream = client.stream::<u32>();
+        let start = std::time::Instant::now();
+        let err = client.recv_timeout(Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        // The read timeout should be restored.
+        assert_eq!(client.socket.lock().unwrap().read_timeout().unwrap(), None);
+        // The client should still work after a timeout.
+        server.send(&1u32).unwrap();
+        client.recv_timeout(Duration::from_millis(100)).unwrap();
+    }
+
+    #[test]
+    fn test_try_recv() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<u32>();
+        assert!(!client.try_recv().unwrap());
+        server.send(&42u32).unwrap();
+        while !client.try_recv().unwrap() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(stream.get(), Some(42));
+    }
+
+    #[test]
+    fn test_try_recv_partial_header() {
+        use std::io::Write;
+        use std::net::{Ipv4Addr, TcpListener, TcpStream};
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        sender.set_nodelay(true).unwrap();
+        let mut client = super::Client::from_stream(listener.accept().unwrap().0);
+        let mut stream = client.stream::<u32>();
+
+        let value = 0xDEADBEEFu32;
+        let mut header = value.header();
+        header.calculate_checksum(&value.send());
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend(value.send());
+
+        // Send part of the header, which should be kept until the rest arrives.
+        sender.write_all(&packet[..7]).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!client.try_recv().unwrap());
+        assert!(!client.try_recv().unwrap());
+        // Send the rest of the packet.
+        sender.write_all(&packet[7..]).unwrap();
+        while !client.try_recv().unwrap() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(stream.get(), Some(value));
+    }
+
+    #[test]
+    fn test_send_bytes() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<Vec<u8>>();
+        let data = [5u8; 100];
+        server.send_bytes(&data[..10]).unwrap();
+        server.send_bytes(&[]).unwrap();
+        client.recv().unwrap();
+        client.recv().unwrap();
+        assert_eq!(stream.drain(), vec![vec![5u8; 10], vec![]]);
+    }
+
+    #[test]
+    fn test_bounded_stream() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.bounded_stream::<u32>(2, super::Overflow::Error);
+        for i in 0..3u32 {
+            server.send(&i).unwrap();
+        }
+        client.recv().unwrap();
+        client.recv().unwrap();
+        assert!(client.recv().is_err());
+        assert_eq!(stream.drain(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let (mut client, mut server) = make_client_server_pair();
+        let _stream = client.stream::<u32>();
+        server.shutdown(std::net::Shutdown::Write).unwrap();
+        let start = std::time::Instant::now();
+        let err = client.recv_timeout(Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_recv_disconnect() {
+        use std::io::Write;
+        let (mut client, mut server) = make_client_server_pair();
+        let _stream = client.stream::<u32>();
+        server.send(&1u32).unwrap();
+        drop(server);
+        client.recv().unwrap();
+        let err = client.recv().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+
+        // Closing in the middle of a packet is not a clean disconnect.
+        let (mut client, server) = make_client_server_pair();
+        let _stream = client.stream::<u32>();
+        let mut header = 1u32.header();
+        header.calculate_checksum(&1u32.send());
+        server
+            .socket
+            .lock()
+            .unwrap()
+            .write_all(&header.to_bytes())
+            .unwrap();
+        drop(server);
+        let err = client.recv().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_shutdown_on_drop() {
+        let (mut client, mut server) = make_client_server_pair();
+        let _stream = client.stream::<u32>();
+        server.listen().unwrap();
+        drop(server);
+        let err = client.recv_timeout(Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        use std::net::{Ipv4Addr, TcpListener};
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = super::Client::connect(addr).unwrap();
+        let mut server = super::Client::from_stream(listener.accept().unwrap().0);
+        let mut stream = server.stream::<u32>();
+        client.send(&1u32).unwrap();
+        server.recv().unwrap();
+        assert_eq!(stream.get(), Some(1));
+
+        // Restart the "peer" on the same port.
+        drop(server);
+        drop(listener);
+        let listener = TcpListener::bind(addr).unwrap();
+        let mut client_stream = client.stream::<String>();
+        client.listen().unwrap();
+        client.reconnect().unwrap();
+        let mut server = super::Client::from_stream(listener.accept().unwrap().0);
+        let mut stream = server.stream::<u32>();
+        client.send(&2u32).unwrap();
+        server.recv().unwrap();
+        assert_eq!(stream.get(), Some(2));
+
+        // The listener should have been restarted, and the stream registered before reconnecting should still work.
+        server.send(&"Hello".to_string()).unwrap();
+        assert_eq!(
+            client_stream.get_timeout(Duration::from_secs(5)),
+            Some("Hello".to_string())
+        );
+        client.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_without_address() {
+        let (mut client, server) = make_client_server_pair();
+        client.addr = None;
+        drop(server);
+        let err = client.reconnect().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn test_send_streaming() {
+        use std::io::Read;
+        let (client, mut server) = make_client_server_pair();
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let expected = data.send();
+        // The streamed packet should be byte for byte the same as a normal one.
+        let reader = std::thread::spawn(move || {
+            let len = 2 * (HEADER_SIZE + expected.len());
+            let mut packets = vec![0; len];
+            client
+                .socket
+                .lock()
+                .unwrap()
+                .read_exact(&mut packets)
+                .unwrap();
+            let (normal, streamed) = packets.split_at(len / 2);
+            assert_eq!(normal, streamed);
+            assert_eq!(&normal[HEADER_SIZE..], expected);
+        });
+        server.send(&data).unwrap();
+        server.send_streaming(&data).unwrap();
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_large_while_listening() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<Vec<u8>>();
+        // Listening makes the socket non-blocking, and several MiB won't fit in the socket buffers.
+        server.listen().unwrap();
+        let data: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let reader = std::thread::spawn(move || {
+            // Let the socket buffers fill up before reading anything.
+            std::thread::sleep(Duration::from_millis(100));
+            for _ in 0..3 {
+                client.recv().unwrap();
+                assert_eq!(stream.get().unwrap(), expected);
+            }
+        });
+        server.send(&data).unwrap();
+        server.send_streaming(&data).unwrap();
+        server.send_bytes(&data).unwrap();
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_streaming_recv() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<String>();
+        server.send_streaming(&"Hello, world!".to_string()).unwrap();
+        server.set_checksum_enabled(false);
+        server.send_streaming(&"Again".to_string()).unwrap();
+        client.recv().unwrap();
+        client.recv().unwrap();
+        assert_eq!(stream.drain(), vec!["Hello, world!", "Again"]);
+    }
+
+    #[test]
+    fn test_send_raw() {
+        use std::{
+            io::Read,
+            net::{Ipv4Addr, TcpListener},
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = super::Client::connect(listener.local_addr().unwrap()).unwrap();
+        let mut raw = listener.accept().unwrap().0;
+        client.send(&5u32).unwrap();
+        client.send_raw(b"raw bytes").unwrap();
+        let mut data = vec![0; HEADER_SIZE + 4 + 9];
+        raw.read_exact(&mut data).unwrap();
+        assert_eq!(&data[HEADER_SIZE..HEADER_SIZE + 4], 5u32.send());
+        // The raw bytes come right after the packet, with no header of their own.
+        assert_eq!(&data[HEADER_SIZE + 4..], b"raw bytes");
+    }
+
+    #[test]
+    fn test_recv_raw() {
+        let (client, mut server) = make_client_server_pair();
+        // The read buffer will read the raw bytes along with the packet, so this checks they aren't lost.
+        let mut client = client.with_buffering();
+        let mut stream = client.stream::<u32>();
+        server.send(&5u32).unwrap();
+        server.send_raw(b"raw bytes").unwrap();
+        server.send(&6u32).unwrap();
+        client.recv().unwrap();
+        assert_eq!(client.recv_raw(9).unwrap(), b"raw bytes");
+        client.recv().unwrap();
+        assert_eq!(stream.drain(), vec![5, 6]);
+    }
+
+    #[test]
+    fn test_recv_any() {
+        let (mut client, mut server) = make_client_server_pair();
+        client.register_type::<u32>();
+        client.register_type::<String>();
+        let messages: Vec<Box<dyn SendableAny>> = vec![
+            Box::new(1u32),
+            Box::new("two".to_owned()),
+            Box::new("three".to_owned()),
+            Box::new(4u32),
+        ];
+        for message in &messages {
+            server.send_any(message.as_ref()).unwrap();
+        }
+        assert_eq!(*client.recv_any().unwrap().downcast::<u32>().unwrap(), 1);
+        assert_eq!(
+            *client.recv_any().unwrap().downcast::<String>().unwrap(),
+            "two"
+        );
+        assert_eq!(
+            *client.recv_any().unwrap().downcast::<String>().unwrap(),
+            "three"
+        );
+        assert_eq!(*client.recv_any().unwrap().downcast::<u32>().unwrap(), 4);
+
+        // Types that aren't registered are an error.
+        server.send(&5u64).unwrap();
+        let err = client.recv_any().unwrap_err();
+        assert!(matches!(err, LazuliError::UnknownStream(id) if id == hash_type_id::<u64>()));
+    }
+
+    #[test]
+    fn test_max_payload_size() {
+        let (mut client, mut server) = make_client_server_pair();
+        client.set_max_payload_size(64);
+        server.send(&vec![0u8; 100]).unwrap();
+        let err = client.recv().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // The listener uses the same limit.
+        let (mut client, mut server) = make_client_server_pair();
+        client.set_max_payload_size(64);
+        client.listen().unwrap();
+        server.send(&vec![0u8; 100]).unwrap();
+        let start = std::time::Instant::now();
+        while client.error().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            client.error().unwrap().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        // A compressed payload is small on the wire, but it can't inflate past the limit either.
+        let (mut client, mut server) = make_client_server_pair();
+        client.set_max_payload_size(64);
+        let _stream = client.stream::<Vec<u8>>();
+        server.send_compressed(&vec![0u8; 1000]).unwrap();
+        let err = client.recv().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // The listener only drops the packet, because the whole packet was read.
+        client.listen().unwrap();
+        server.send_compressed(&vec![0u8; 1000]).unwrap();
+        let start = std::time::Instant::now();
+        while client.stats().packets_dropped == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(client.is_listening());
+    }
+
+    #[test]
+    fn test_stats() {
+        let (mut client, mut server) = make_client_server_pair();
+        let text = "Hello, world!".to_owned();
+        client.send(&text).unwrap();
+        client.send(&7u32).unwrap();
+        server.recv().unwrap();
+
+        let sent = 2 * HEADER_SIZE as u64 + text.size() as u64 + 4;
+        let stats = client.stats();
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.bytes_sent, sent);
+        assert_eq!(stats.packets_received, 0);
+        assert_eq!(stats.bytes_received, 0);
+        let stats = server.stats();
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(
+            stats.bytes_received,
+            (HEADER_SIZE + text.size() as usize) as u64
+        );
+
+        // The listener counts the packets it receives too.
+        server.listen().unwrap();
+        let start = std::time::Instant::now();
+        while server.stats().packets_received < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.stats().bytes_received, sent);
+    }
+
+    #[test]
+    fn test_request() {
+        let (client, mut server) = make_client_server_pair();
+        let mut client = client.with_buffering();
+        let responder = std::thread::spawn(move || {
+            let n: u32 = server.recv_into().unwrap();
+            server.send(&format!("Got {}", n)).unwrap();
+            server
+        });
+        let response: String = client.request(&42u32).unwrap();
+        assert_eq!(response, "Got 42");
+        let mut server = responder.join().unwrap();
+
+        // A response of the wrong type is an error.
+        server.send(&7u32).unwrap();
+        let err = client.request::<u32, String>(&1u32).unwrap_err();
+        assert!(matches!(err, LazuliError::TypeMismatch { .. }));
+
+        // The listener would take the response, so nothing is sent while it is running.
+        client.listen().unwrap();
+        assert!(client.request::<u32, String>(&2u32).is_err());
+        client.stop_listening().unwrap();
+        assert_eq!(server.recv_into::<u32>().unwrap(), 1);
+        assert!(!server.try_recv().unwrap());
+    }
+
+    #[test]
+    fn test_ping() {
+        let (mut client, mut server) = make_client_server_pair();
+        server.listen().unwrap();
+        let rtt = client.ping(Duration::from_secs(5)).unwrap();
+        assert!(rtt < Duration::from_secs(5));
+        // The pong counts as a sent packet, even though the listener sent it.
+        // It is counted once it has been written, which can be just after the client gets it.
+        let start = std::time::Instant::now();
+        while server.stats().packets_sent != 1 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "The pong was never counted"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Pings are also answered by recv, and a listening client waits for the pong without reading the socket itself.
+        server.stop_listening().unwrap();
+        client.listen().unwrap();
+        let answer = std::thread::spawn(move || {
+            server.recv().unwrap();
+            server
+        });
+        client.ping(Duration::from_secs(5)).unwrap();
+        answer.join().unwrap();
+        // The ping and pong never show up as unhandled packets.
+        assert!(client.unhandled_packets().is_empty());
+    }
+
+    #[test]
+    fn test_ping_timeout() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<u32>();
+        server.send(&5u32).unwrap();
+        // The server isn't receiving, so the ping is never answered.
+        let err = client.ping(Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        // Packets that arrived while waiting still went to their streams.
+        assert_eq!(stream.get(), Some(5));
+
+        // The late answer to the first ping doesn't count for the second one.
+        server.recv().unwrap();
+        server.listen().unwrap();
+        client.ping(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_keyed_streams() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut chat = client.stream_keyed::<String>(1);
+        let mut log = client.stream_keyed::<String>(2);
+        let mut plain = client.stream::<String>();
+        server.send_keyed(1, &"Hello".to_owned()).unwrap();
+        server.send_keyed(2, &"Connected".to_owned()).unwrap();
+        server.send(&"Unkeyed".to_owned()).unwrap();
+        server.send_keyed(1, &"World".to_owned()).unwrap();
+        for _ in 0..4 {
+            client.recv().unwrap();
+        }
+        assert_eq!(chat.drain(), vec!["Hello", "World"]);
+        assert_eq!(log.drain(), vec!["Connected"]);
+        assert_eq!(plain.drain(), vec!["Unkeyed"]);
+        // A key with no stream is unhandled, instead of going to another stream of the same type.
+        server.send_keyed(3, &"Lost".to_owned()).unwrap();
+        client.recv().unwrap();
+        assert_eq!(client.unhandled_packets().len(), 1);
+        assert!(chat.is_empty() && log.is_empty() && plain.is_empty());
+    }
+
+    #[test]
+    fn test_send_buffered() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut strings = client.stream::<String>();
+        let mut numbers = client.stream::<Vec<u32>>();
+        server.send_buffered(&"Hello, world!".to_owned()).unwrap();
+        server.send_buffered(&vec![1u32, 2, 3]).unwrap();
+        server.send_buffered(&"Again".to_owned()).unwrap();
+        for _ in 0..3 {
+            client.recv().unwrap();
+        }
+        assert_eq!(strings.drain(), vec!["Hello, world!", "Again"]);
+        assert_eq!(numbers.get(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_buffering() {
+        let (client, server) = make_client_server_pair();
+        let mut client = client.with_buffering();
+        let mut server = server.with_buffering();
+        let mut stream = client.stream::<u32>();
+        for i in 0..100u32 {
+            server.send(&i).unwrap();
+        }
+        // Nothing should have been written yet.
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!client.try_recv().unwrap());
+        server.flush().unwrap();
+        for _ in 0..100 {
+            client.recv().unwrap();
+        }
+        assert_eq!(stream.drain(), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_recv_all_available() {
+        use std::io::Write;
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<u32>();
+        assert_eq!(client.recv_all_available().unwrap(), 0);
+        for i in 0..3u32 {
+            server.send(&i).unwrap();
+        }
+        // Start a fourth packet, but leave it unfinished.
+        let mut header = 3u32.header();
+        header.calculate_checksum(&3u32.send());
+        server
+            .socket
+            .lock()
+            .unwrap()
+            .write_all(&header.to_bytes())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(client.recv_all_available().unwrap(), 3);
+        assert_eq!(stream.drain(), vec![0, 1, 2]);
+
+        server
+            .socket
+            .lock()
+            .unwrap()
+            .write_all(&3u32.send())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(client.recv_all_available().unwrap(), 1);
+        assert_eq!(stream.get(), Some(3));
+    }
+
+    #[test]
+    fn test_unhandled_packets() {
+        let (mut client, mut server) = make_client_server_pair();
+        server.send(&42u32).unwrap();
+        client.recv().unwrap();
+        let unhandled = client.unhandled_packets();
+        assert_eq!(
+            unhandled,
+            vec![(crate::hash_type_id::<u32>(), 42u32.send())]
+        );
+        assert!(client.unhandled_packets().is_empty());
+
+        // The listener should keep unhandled packets too.
+        client.listen().unwrap();
+        server.send(&"Hello, world!".to_owned()).unwrap();
+        let start = std::time::Instant::now();
+        let unhandled = loop {
+            let unhandled = client.unhandled_packets();
+            if !unhandled.is_empty() {
+                break unhandled;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        client.stop_listening().unwrap();
+        assert_eq!(unhandled.len(), 1);
+        assert_eq!(unhandled[0].0, crate::hash_type_id::<String>());
+        assert_eq!(
+            String::recv(&mut std::io::Cursor::new(&unhandled[0].1)).unwrap(),
+            "Hello, world!"
+        );
+    }
+
+    #[test]
+    fn test_send_compressed() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<Vec<u8>>();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 16) as u8).collect();
+        server.send_compressed(&data).unwrap();
+        // Peek the header to make sure the payload was actually compressed.
+        let mut header = [0; HEADER_SIZE];
+        client
+            .socket
+            .lock()
+            .unwrap()
+            .tcp()
+            .unwrap()
+            .peek(&mut header)
+            .unwrap();
+        let header = PacketHeader::try_parse(&header).unwrap();
+        assert!(header.is_compressed());
+        assert!((header.payload_size as usize) < data.len() / 10);
+        client.recv().unwrap();
+        assert_eq!(stream.get(), Some(data));
+    }
+
+    #[test]
+    fn test_connect_with_config() {
+        use std::net::{Ipv4Addr, TcpListener};
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let config = SocketConfig::new().nodelay(true);
+        let client =
+            super::Client::connect_with_config(listener.local_addr().unwrap(), &config).unwrap();
+        assert!(client
+            .socket
+            .lock()
+            .unwrap()
+            .tcp()
+            .unwrap()
+            .nodelay()
+            .unwrap());
+    }
+
+    #[test]
+    fn test_on_handler() {
+        let (mut client, mut server) = make_client_server_pair();
+        let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_received = received.clone();
+        client.on::<u32>(move |value| handler_received.lock().unwrap().push(value));
+        client.listen().unwrap();
+        for i in 1..=3u32 {
+            server.send(&i).unwrap();
+        }
+        let start = std::time::Instant::now();
+        while received.lock().unwrap().len() < 3 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Handler never ran"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        client.stop_listening().unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_recv_into() {
+        let (mut client, mut server) = make_client_server_pair();
+        server.send(&"Hello, world!".to_string()).unwrap();
+        let data: String = client.recv_into().unwrap();
+        assert_eq!(data, "Hello, world!");
+    }
+
+    #[test]
+    fn test_recv_into_wrong_type() {
+        let (mut client, mut server) = make_client_server_pair();
+        server.send(&42u32).unwrap();
+        let err = client.recv_into::<String>().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            err,
+            LazuliError::TypeMismatch { found, .. } if found == crate::hash_type_id::<u32>()
+        ));
+    }
+
+    #[test]
+    fn test_checksum_disabled() {
+        use std::io::Read;
+        let (mut client, mut server) = make_client_server_pair();
+        server.set_checksum_enabled(false);
+        let data = "Hello, world!".to_string();
+        server.send(&data).unwrap();
+        // Read the packet by hand, to check the header.
+        let mut header = [0; HEADER_SIZE];
+        client
+            .socket
+            .lock()
+            .unwrap()
+            .read_exact(&mut header)
+            .unwrap();
+        let header = PacketHeader::try_parse(&header).unwrap();
+        let mut payload = vec![0; header.payload_size as usize];
+        client
+            .socket
+            .lock()
+            .unwrap()
+            .read_exact(&mut payload)
+            .unwrap();
+        assert_eq!(header.checksum(), None);
+        // Without a checksum, any payload passes verification.
+        assert!(header.verify_checksum(&[1, 2, 3]));
+        assert_eq!(payload, data.send());
+
+        let mut stream = client.stream::<String>();
+        server.send_buffered(&data).unwrap();
+        server.send_bytes(&[1, 2, 3]).unwrap();
+        client.recv().unwrap();
+        client.recv().unwrap();
+        assert_eq!(stream.get(), Some(data));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (mut client, mut server) = make_client_server_pair();
+        let _ = client.stream::<u32>();
+        let _ = client.stream::<String>();
+        // Registration order doesn't matter.
+        let _ = server.stream::<String>();
+        let _ = server.stream::<u32>();
+        let handle = std::thread::spawn(move || server.handshake());
+        client.handshake().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_handshake_mismatch() {
+        let (mut client, mut server) = make_client_server_pair();
+        let _ = client.stream::<u32>();
+        let _ = server.stream::<u64>();
+        let handle = std::thread::spawn(move || server.handshake());
+        let err = client.handshake().unwrap_err();
+        assert!(matches!(err, LazuliError::HandshakeFailed(_)), "{:?}", err);
+        let err = handle.join().unwrap().unwrap_err();
+        assert!(matches!(err, LazuliError::HandshakeFailed(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        use std::io::Write;
+        let (mut client, server) = make_client_server_pair();
+        let _stream = client.stream::<u32>();
+        let mut header = 1u32.header();
+        header.calculate_checksum(&1u32.send());
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend(2u32.send());
+        server.socket.lock().unwrap().write_all(&packet).unwrap();
+        let err = client.recv().unwrap_err();
+        match err {
+            LazuliError::ChecksumMismatch { expected, actual } => {
+                assert_eq!(expected, crate::header::crc32(&1u32.send()));
+                assert_eq!(actual, crate::header::crc32(&2u32.send()));
+            }
+            e => panic!("Expected a checksum mismatch, got {:?}", e),
+        }
+    }
+
     #[derive(Debug)]
     struct TestStruct {
         a: u32,
@@ -280,12 +2060,105 @@ mod tests {
     fn test_stream_data_struct() {
         let mut stream: Stream<TestStruct> = Stream::new();
         let mut data = StreamConnector::new(&stream);
-        unsafe {
-            data.push_raw(TestStruct { a: 30, b: 40 }.send().into())
-                .unwrap()
-        };
+        let test_struct = TestStruct { a: 30, b: 40 };
+        data.push(&test_struct.send(), unsafe {
+            PacketHeader::new(test_struct.size())
+        })
+        .unwrap();
         let x = stream.get().unwrap();
         assert_eq!(x.a, 30);
         assert_eq!(x.b, 40);
     }
+
+    #[test]
+    fn test_local_addr() {
+        let (client, server) = make_client_server_pair();
+        let local = client.local_addr().unwrap();
+        assert_ne!(local.port(), 0);
+        // Each side's local address is the other side's peer address.
+        assert_eq!(local, server.peer_addr().unwrap());
+        assert_eq!(server.local_addr().unwrap(), client.peer_addr().unwrap());
+    }
+
+    #[test]
+    fn test_send_batch() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<u32>();
+        let items: Vec<u32> = (0..1000).collect();
+        server.send_batch(&items).unwrap();
+        client.recv().unwrap();
+        assert_eq!(client.stats().packets_received, 1);
+        assert_eq!(stream.drain(), items);
+
+        // A batch isn't a Vec<u32>, so it doesn't go to a stream for one.
+        let mut vec_stream = client.stream::<Vec<u32>>();
+        server.send_batch::<u32>(&[]).unwrap();
+        server.send_batch(&[5u32, 6]).unwrap();
+        client.recv().unwrap();
+        client.recv().unwrap();
+        assert_eq!(vec_stream.get(), None);
+        assert_eq!(stream.drain(), vec![5, 6]);
+    }
+
+    #[test]
+    fn test_poll() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<u32>();
+        let start = std::time::Instant::now();
+        assert_eq!(client.poll(Duration::from_millis(50)).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        for i in 0..3u32 {
+            server.send(&i).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(client.poll(Duration::from_secs(1)).unwrap(), 3);
+        assert_eq!(stream.drain(), vec![0, 1, 2]);
+
+        // Polling waits for a packet that arrives during the timeout.
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            server.send(&3u32).unwrap();
+            server
+        });
+        assert_eq!(client.poll(Duration::from_secs(5)).unwrap(), 1);
+        assert_eq!(stream.get(), Some(3));
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_poll_deadline() {
+        use std::io::Write;
+        let (mut client, server) = make_client_server_pair();
+        let mut stream = client.stream::<u32>();
+        let mut packet = 4u32.header();
+        packet.calculate_checksum(&4u32.send());
+        let mut packet = packet.to_bytes().to_vec();
+        packet.extend(4u32.send());
+        let rest = packet.split_off(packet.len() / 2);
+        // Send half of the packet a byte at a time, each well within the timeout.
+        let socket = server.socket.clone();
+        let first = packet;
+        let trickle = std::thread::spawn(move || {
+            for byte in first {
+                socket.lock().unwrap().write_all(&[byte]).unwrap();
+                std::thread::sleep(Duration::from_millis(30));
+            }
+        });
+        let start = std::time::Instant::now();
+        assert_eq!(client.poll(Duration::from_millis(200)).unwrap(), 0);
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed < Duration::from_millis(400),
+            "poll took {:?}",
+            elapsed
+        );
+        trickle.join().unwrap();
+        // The read timeout of the socket is restored.
+        assert_eq!(client.socket.lock().unwrap().read_timeout().unwrap(), None);
+
+        // The part that was read is kept, so the packet can still be finished.
+        server.socket.lock().unwrap().write_all(&rest).unwrap();
+        assert_eq!(client.poll(Duration::from_secs(5)).unwrap(), 1);
+        assert_eq!(stream.get(), Some(4));
+    }
 }
@@ -0,0 +1,77 @@
+//! Deflate compression for packet payloads.
+//!
+//! Compressed packets have the compressed flag set in their header, and their payload_size is the size of the compressed payload.
+//! The checksum covers the compressed bytes, so it can be checked before inflating.
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+use crate::{PacketHeader, Result, UnknownType};
+
+/// Compresses a serialized payload.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Inflates the payload if the header says it is compressed.
+///
+/// The header is updated to describe the inflated payload, so everything after this can treat the packet as uncompressed.
+/// Returns an `InvalidData` error if the payload is not valid deflate data,
+/// or if it inflates to more than max_payload_size bytes. (the same limit as an uncompressed payload)
+pub fn decompress(
+    header: &mut PacketHeader<UnknownType>,
+    data: Vec<u8>,
+    max_payload_size: u32,
+) -> Result<Vec<u8>> {
+    if !header.is_compressed() {
+        return Ok(data);
+    }
+    let limit = max_payload_size as u64;
+    let mut inflated = Vec::new();
+    // Read one byte past the limit, so we can tell if the payload is too large without inflating all of it.
+    DeflateDecoder::new(data.as_slice())
+        .take(limit + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if inflated.len() as u64 > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Compressed payload inflates to more than {} bytes", limit),
+        )
+        .into());
+    }
+    header.payload_size = inflated.len() as u32;
+    header.set_compressed(false);
+    Ok(inflated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_MAX_PAYLOAD_SIZE;
+
+    #[test]
+    fn test_round_trip() {
+        let data = vec![7u8; 4096];
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        let mut header: PacketHeader<UnknownType> =
+            unsafe { PacketHeader::new(compressed.len() as u32) };
+        header.set_compressed(true);
+        let inflated = decompress(&mut header, compressed, DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+        assert_eq!(inflated, data);
+        assert_eq!(header.payload_size, 4096);
+        assert!(!header.is_compressed());
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        let mut header: PacketHeader<UnknownType> = unsafe { PacketHeader::new(4) };
+        header.set_compressed(true);
+        let err = decompress(&mut header, vec![0xFF; 4], DEFAULT_MAX_PAYLOAD_SIZE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
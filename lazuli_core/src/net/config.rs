@@ -1,4 +1,7 @@
+use socket2::{SockRef, TcpKeepalive};
+
 /// Config flags for the underlying socket of a client.
+#[derive(Default)]
 pub struct SocketConfig {
     /// Whether the socket should be blocking.
     pub blocking: Option<bool>,
@@ -10,18 +13,13 @@ pub struct SocketConfig {
     pub ttl: Option<u32>,
     /// Whether the socket should have the Nagle algorithm disabled
     pub nodelay: Option<bool>,
-}
-
-impl Default for SocketConfig {
-    fn default() -> Self {
-        Self {
-            blocking: None,
-            read_timeout: None,
-            write_timeout: None,
-            ttl: None,
-            nodelay: None,
-        }
-    }
+    /// How long the connection has to be idle before TCP keep-alive probes are sent.
+    /// This detects half-open connections, where the peer went away without closing the connection.
+    pub keepalive: Option<std::time::Duration>,
+    /// The size of the socket's receive buffer (`SO_RCVBUF`), in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// The size of the socket's send buffer (`SO_SNDBUF`), in bytes.
+    pub send_buffer_size: Option<usize>,
 }
 
 impl SocketConfig {
@@ -48,6 +46,17 @@ impl SocketConfig {
         if let Some(nodelay) = self.nodelay {
             socket.set_nodelay(nodelay)?;
         }
+        // std doesn't expose the options below, so they are set through socket2.
+        let sock_ref = SockRef::from(socket);
+        if let Some(keepalive) = self.keepalive {
+            sock_ref.set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            sock_ref.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            sock_ref.set_send_buffer_size(size)?;
+        }
         Ok(())
     }
 
@@ -91,4 +100,66 @@ impl SocketConfig {
         self.nodelay = Some(nodelay);
         self
     }
+
+    /// Enables TCP keep-alive, sending probes after the connection has been idle for `keepalive`.
+    pub fn keepalive(mut self, keepalive: std::time::Duration) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets the size of the socket's receive buffer.
+    ///
+    /// The OS may round or clamp this. (e.g. Linux doubles it)
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the socket's send buffer.
+    ///
+    /// The OS may round or clamp this. (e.g. Linux doubles it)
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use socket2::SockRef;
+
+    use super::SocketConfig;
+
+    fn make_stream() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (stream, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let (stream, _peer) = make_stream();
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+        let config = SocketConfig::new().keepalive(Duration::from_secs(30));
+        config.apply_stream(&stream).unwrap();
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let (stream, _peer) = make_stream();
+        let config = SocketConfig::new()
+            .recv_buffer_size(128 * 1024)
+            .send_buffer_size(128 * 1024);
+        config.apply_stream(&stream).unwrap();
+        // The OS is allowed to adjust the size, but it shouldn't shrink it below what was asked for.
+        let sock_ref = SockRef::from(&stream);
+        assert!(sock_ref.recv_buffer_size().unwrap() >= 128 * 1024);
+        assert!(sock_ref.send_buffer_size().unwrap() >= 128 * 1024);
+    }
 }
@@ -1,96 +1,191 @@
 //! Contains the StreamConnector struct, which allows for the pushing of data into a Stream.
 
 use std::{
+    any::Any,
+    collections::VecDeque,
     fmt::Debug,
     io::Read,
-    mem::{self, ManuallyDrop},
+    mem, ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
 };
 
 use log::trace;
 
-use crate::{sendable, stream::Stream, ArcMutex, PacketHeader, Result, Sendable, UnknownType};
+use crate::{
+    hash_type_id,
+    stream::{Overflow, Stream},
+    LazuliError, PacketHeader, Result, Sendable, UnknownType,
+};
+
+/// Receives a T from data, and pushes it to the stream of connector.
+fn recv_and_push<T: Sendable + 'static>(
+    connector: &mut StreamConnector,
+    data: &mut dyn Read,
+) -> Result<()> {
+    let value = T::recv(data)?;
+    connector.push_typed(value)
+}
 
-/// A single byte type that is used to store the raw data.
-#[repr(transparent)]
-struct Unknown(u8);
+/// Receives a batch of T's from data, and pushes each of them to the stream of connector, in the order they were sent.
+fn recv_batch_and_push<T: Sendable + 'static>(
+    connector: &mut StreamConnector,
+    data: &mut dyn Read,
+) -> Result<()> {
+    for value in Vec::<T>::recv(data)? {
+        connector.push_typed(value)?;
+    }
+    Ok(())
+}
+
+/// Reads a T out of data, and pushes it to the stream of connector.
+///
+/// # Safety
+/// data must hold a valid T, and must be exactly the size of T. Ownership of the T is moved out of data.
+unsafe fn push_bytes<T: 'static>(connector: &mut StreamConnector, data: &[u8]) -> Result<()> {
+    // SAFETY: The caller guarantees that data holds a valid T. The bytes are not aligned for T, so read_unaligned is used.
+    let value = unsafe { ptr::read_unaligned(data.as_ptr() as *const T) };
+    connector.push_typed(value)
+}
+
+/// Sets the closed flag of the stream of connector, holding the lock on the stream while doing so.
+fn set_closed<T: 'static>(connector: &StreamConnector, closed: bool) {
+    // Hold the lock, so a waiting iterator can't check the flag and then miss the notification.
+    let _data = connector.vec::<T>().map(|vec| vec.lock().unwrap());
+    connector.closed.store(closed, Ordering::Release);
+    connector.available.notify_all();
+}
 
 /// The various data required to store a stream.
 /// More specifically, this un-types streams, while keeping needed data.
-// HACK: like this *whole* setup is a hack. I don't know if there is a better way to do this, but there probably is.
+///
+/// Values are stored in the stream as they are, so nothing depends on the layout of the type.
+/// The only thing that still knows the type is a function pointer for each operation that needs it.
+///
+/// Connectors are only made for streams of Send types, because the listener pushes (and drops) values on its own thread.
+/// The stream's `Mutex<VecDeque<T>>` is then Send and Sync, so the connector is too, without any unsafe impls.
 pub struct StreamConnector {
-    raw_data: ArcMutex<Vec<Unknown>>,
-    vec_ptr: ArcMutex<*mut Unknown>,
+    /// The `Mutex<VecDeque<T>>` of the stream. It is shared with the stream, so whichever is dropped last frees the values.
+    data: Arc<dyn Any + Send + Sync>,
+    available: Arc<Condvar>,
+    closed: Arc<AtomicBool>,
     size: usize,
-    grew: ArcMutex<usize>,
-    conversion_fn: fn(&mut dyn Read) -> Result<Box<[u8]>>,
+    capacity: Option<(usize, Overflow)>,
+    recv_fn: fn(&mut StreamConnector, &mut dyn Read) -> Result<()>,
+    push_bytes_fn: unsafe fn(&mut StreamConnector, &[u8]) -> Result<()>,
+    set_closed_fn: fn(&StreamConnector, bool),
     type_name: &'static str,
 }
 
 impl StreamConnector {
     /// Creates a new StreamConnector from a Stream.
-    pub fn new<T: 'static + Sendable>(stream: &Stream<T>) -> Self {
+    pub fn new<T: 'static + Sendable + Send>(stream: &Stream<T>) -> Self {
         StreamConnector {
-            raw_data: unsafe { mem::transmute(stream.get_vec()) },
-            vec_ptr: unsafe { mem::transmute(stream.get_ptr()) },
+            data: stream.get_vec(),
+            available: stream.get_available(),
+            closed: stream.get_closed(),
             size: mem::size_of::<T>(),
-            grew: stream.get_grow_by(),
-            conversion_fn: sendable::as_conversion_fn::<T>(),
+            capacity: stream.get_capacity(),
+            recv_fn: recv_and_push::<T>,
+            push_bytes_fn: push_bytes::<T>,
+            set_closed_fn: set_closed::<T>,
             type_name: std::any::type_name::<T>(),
         }
     }
-    /// Pushes data to the stream.
-    /// Data is the raw data received from the socket.
-    /// # Safety
-    /// The caller must ensure that the data is the correct size for the type, and valid.
-    pub unsafe fn push_raw(&mut self, data: Box<[u8]>) -> Result<()> {
-        let mut v = self.raw_data.lock().unwrap();
-        // We don't need to do any pointer magic if the type is a ZST
-        if data.len() == 0 && self.size == 0 {
-            let len = v.len();
-            unsafe { v.set_len(len + 1) };
-            return Ok(());
+
+    /// Creates a new StreamConnector that receives batches of T's, and pushes each item to the stream separately.
+    /// (see `Client::send_batch`)
+    pub fn new_batch<T: 'static + Sendable + Send>(stream: &Stream<T>) -> Self {
+        let mut connector = Self::new(stream);
+        connector.recv_fn = recv_batch_and_push::<T>;
+        connector
+    }
+
+    /// Gets the vec of the stream, if the stream is a `Stream<T>`.
+    fn vec<T: 'static>(&self) -> Option<&Mutex<VecDeque<T>>> {
+        self.data.downcast_ref()
+    }
+
+    /// Pushes a value to the stream, and wakes up anything waiting on the stream.
+    ///
+    /// Returns a `TypeMismatch` error if the stream is not a `Stream<T>`.
+    /// If the stream is full and its overflow policy is `Overflow::Error`, a `StreamFull` error is returned and the value is dropped.
+    pub fn push_typed<T: 'static>(&mut self, value: T) -> Result<()> {
+        let vec = self.vec::<T>().ok_or(LazuliError::TypeMismatch {
+            expected: self.type_name,
+            found: hash_type_id::<T>(),
+        })?;
+        let mut vec = vec.lock().unwrap();
+        if let Some((capacity, overflow)) = self.capacity {
+            while vec.len() >= capacity {
+                match overflow {
+                    Overflow::Error => {
+                        return Err(LazuliError::StreamFull {
+                            type_name: self.type_name,
+                            capacity,
+                        })
+                    }
+                    // Streams pop from the back, so the oldest item is at the front.
+                    Overflow::DropOldest => {
+                        vec.pop_front();
+                    }
+                }
+            }
         }
-        // ptr, len in bytes, cap in bytes
-        // The len and capacity are converted to bytes because we only know the size of T.
-        let (ptr, len, cap) = (
-            v.as_mut_ptr() as *mut u8,
-            v.len() * self.size,
-            v.capacity() * self.size,
-        );
-        // Create the vec of bytes.
-        // We don't transmute v because it would have an invalid length and capacity.
-        let mut vec = unsafe { Vec::from_raw_parts(ptr, len, cap) };
-        // Check size.
-        assert!(
-            data.len() % self.size == 0,
-            "Data is not the correct size for the type. Expected {}, got {}",
-            self.size,
-            data.len()
-        );
-        // Add the bytes to the array
-        vec.append(data.to_vec().as_mut());
-        // Update the vector pointer in case it gets changed.
-        *self.vec_ptr.lock().unwrap() = vec.as_mut_ptr() as *mut Unknown;
-        // Forget the array we created to prevent any double-frees.
-        let _ = ManuallyDrop::new(vec);
-        // Increment how much the vec grew.
-        *self.grew.lock().unwrap() += 1;
+        vec.push_back(value);
+        drop(vec);
+        self.available.notify_all();
         Ok(())
     }
 
-    pub fn push(&mut self, data: Vec<u8>, header: PacketHeader<UnknownType>) -> Result<()> {
+    /// Pushes data to the stream, and wakes up anything waiting on the stream.
+    /// Data is the bytes of a value of the type in memory.
+    /// # Safety
+    /// The caller must ensure that the data is a valid value of the type.
+    /// Ownership of the value in data is moved into the stream, so the caller must not drop it.
+    ///
+    /// Returns an `InvalidData` error if the data is not the size of the type.
+    /// If the stream is full and its overflow policy is `Overflow::Error`, a `StreamFull` error is returned and the value in data is dropped.
+    #[allow(clippy::boxed_local)] // Kept as a Box<[u8]> so the bytes can't be reused after their value is moved out.
+    pub unsafe fn push_raw(&mut self, data: Box<[u8]>) -> Result<()> {
+        self.check_size(data.len())?;
+        // SAFETY: The size was checked, and the caller guarantees data is a valid value of the type.
+        unsafe { (self.push_bytes_fn)(self, &data) }
+    }
+
+    /// Converts the payload of a packet to the type of the stream, and pushes it to the stream.
+    ///
+    /// The payload is only read from, so the buffer it is in can be reused afterward.
+    pub fn push(&mut self, data: &[u8], header: PacketHeader<UnknownType>) -> Result<()> {
         debug_assert_eq!(header.payload_size as usize, data.len());
-        // Create a cursor from the data.
-        let mut cursor = std::io::Cursor::new(data);
-        let converted = (self.conversion_fn)(&mut cursor)?;
-        trace!("Converted data: {:?}", converted);
-        assert!(
-            converted.len() == self.size,
-            "Data is not the correct size for the type."
-        );
-        unsafe { self.push_raw(converted)? };
+        trace!("Pushing a {} to its stream", self.type_name);
+        (self.recv_fn)(self, &mut std::io::Cursor::new(data))
+    }
+
+    /// Returns an `InvalidData` error if len is not the size of the type of the stream.
+    fn check_size(&self, len: usize) -> Result<()> {
+        if len != self.size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Data is not the correct size for {} (Expected: {}, Got: {})",
+                    self.type_name, self.size, len
+                ),
+            )
+            .into());
+        }
         Ok(())
     }
+
+    /// Marks the stream as closed or open again, and wakes up anything waiting on the stream.
+    ///
+    /// A closed stream still accepts data, so this is only a signal for `Stream::into_blocking_iter`.
+    pub fn set_closed(&self, closed: bool) {
+        (self.set_closed_fn)(self, closed);
+    }
+
     /// Returns the type name of the stream. This is mainly used for the debug implementation.
     pub fn type_name(&self) -> &'static str {
         self.type_name
@@ -106,9 +201,12 @@ impl Debug for StreamConnector {
     }
 }
 
-/// Everything in StreamConnector is behind a mutex, besides the size. The size should never change.
-unsafe impl Send for StreamConnector {}
-unsafe impl Sync for StreamConnector {}
+impl Drop for StreamConnector {
+    fn drop(&mut self) {
+        // Nothing can push to the stream anymore.
+        self.set_closed(true);
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -125,6 +223,22 @@ mod tests {
         assert_eq!(stream.get().unwrap(), 0);
     }
 
+    #[test]
+    fn test_wrong_size() {
+        let mut stream = Stream::<u32>::new();
+        let mut connector = StreamConnector::new(&stream);
+        let err = unsafe { connector.push_raw(vec![0; 8].into()) }.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = unsafe { connector.push_raw(vec![0; 2].into()) }.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        // A payload that is too short to convert should also be an error, not a panic.
+        assert!(connector
+            .push(&[0; 2], unsafe { PacketHeader::new(2) })
+            .is_err());
+        assert_eq!(stream.len(), 0);
+        assert_eq!(stream.get(), None);
+    }
+
     #[test]
     fn test_string() {
         let mut stream = Stream::<String>::new();
@@ -153,4 +267,117 @@ mod tests {
         unsafe { connector.push_raw(data.into()).unwrap() };
         assert_eq!(stream.get().unwrap(), ());
     }
+
+    #[test]
+    fn test_push_zst() {
+        // () has an empty payload, and converts to an empty slice of bytes.
+        let mut stream = Stream::<()>::new();
+        let mut connector = StreamConnector::new(&stream);
+        connector
+            .push(&[], unsafe { PacketHeader::new(0) })
+            .unwrap();
+        connector
+            .push(&[], unsafe { PacketHeader::new(0) })
+            .unwrap();
+        assert_eq!(stream.len(), 2);
+        assert_eq!(stream.drain(), vec![(), ()]);
+
+        // A Vec of a ZST is not a ZST, but its items take no space in the payload.
+        let mut stream = Stream::<Vec<()>>::new();
+        let mut connector = StreamConnector::new(&stream);
+        let data = vec![(); 5];
+        connector
+            .push(&data.send(), unsafe { PacketHeader::new(data.size()) })
+            .unwrap();
+        assert_eq!(stream.get(), Some(data));
+    }
+
+    #[test]
+    fn test_push_typed() {
+        let mut stream = Stream::<String>::new();
+        let mut connector = StreamConnector::new(&stream);
+        connector.push_typed("Hello, world!".to_owned()).unwrap();
+        assert_eq!(stream.get().unwrap(), "Hello, world!");
+
+        let err = connector.push_typed(5u32).unwrap_err();
+        assert!(matches!(err, LazuliError::TypeMismatch { .. }));
+        assert_eq!(stream.len(), 0);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Named {
+        id: u32,
+        name: String,
+    }
+
+    impl Sendable for Named {
+        fn send(&self) -> Vec<u8> {
+            let mut data = self.id.send();
+            data.extend(self.name.send());
+            data
+        }
+
+        fn recv(data: &mut dyn Read) -> Result<Self> {
+            Ok(Named {
+                id: u32::recv(data)?,
+                name: String::recv(data)?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_push_struct() {
+        let mut stream = Stream::<Named>::new();
+        let mut connector = StreamConnector::new(&stream);
+        let value = Named {
+            id: 7,
+            name: "seven".to_owned(),
+        };
+        let data = value.send();
+        connector
+            .push(&data, unsafe { PacketHeader::new(data.len() as u32) })
+            .unwrap();
+        assert_eq!(stream.get(), Some(value));
+    }
+
+    static DROPPED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    /// Counts how many times it is dropped in DROPPED.
+    struct DropCounter(String);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Sendable for DropCounter {
+        fn send(&self) -> Vec<u8> {
+            self.0.send()
+        }
+
+        fn recv(data: &mut dyn Read) -> Result<Self> {
+            Ok(DropCounter(String::recv(data)?))
+        }
+    }
+
+    #[test]
+    fn test_drop_stream_before_connector() {
+        let stream = Stream::<DropCounter>::new();
+        let mut connector = StreamConnector::new(&stream);
+        connector
+            .push_typed(DropCounter("first".to_owned()))
+            .unwrap();
+        drop(stream);
+        // The connector still shares the vec, so pushing after the stream is gone is fine.
+        let data = DropCounter("second".to_owned()).send();
+        connector
+            .push(&data, unsafe { PacketHeader::new(data.len() as u32) })
+            .unwrap();
+        // Only the value used to make data has been dropped so far.
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+        // The connector was the last owner, so both values in the vec are dropped with it, exactly once.
+        drop(connector);
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 3);
+    }
 }
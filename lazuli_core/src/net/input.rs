@@ -1,31 +1,64 @@
 //! Module for handling input from a socket. Contains several helper functions for reading data from a socket.
 //! This module also provides functions that return IOResults, which in turn can be used with the ? operator.
 
-use std::{io::Read, mem, net::TcpStream};
+use std::{
+    io::{self, Read},
+    net::TcpStream,
+};
 
 use log::trace;
 
-use crate::{PacketHeader, Result, UnknownType};
+use crate::{header::Crc32Writer, LazuliError, PacketHeader, Result, UnknownType, HEADER_SIZE};
 
 /// Reads the header of a packet from a TcpStream.
 #[inline]
 pub fn input_header(stream: &mut TcpStream) -> Result<PacketHeader<UnknownType>> {
-    let mut header = [0; mem::size_of::<PacketHeader<UnknownType>>()];
+    let mut header = [0; HEADER_SIZE];
 
     stream.read_exact(&mut header)?;
 
     trace!("Read header: {:?}", header);
 
-    let header = unsafe { PacketHeader::from_bytes_unchecked(header.as_slice()) };
+    // The header comes straight from the peer, so it has to be checked.
+    let header = PacketHeader::try_parse(header.as_slice())?;
 
     Ok(header)
 }
 
+/// The default maximum payload size that a packet header can claim. (16 MiB)
+pub const DEFAULT_MAX_PAYLOAD_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Returns an `InvalidData` error if the header claims a payload larger than max.
+///
+/// The payload size comes straight from the peer, and the payload buffer is allocated up front,
+/// so without a limit a peer could make the receiver allocate up to 4 GiB with a single header.
+#[inline]
+pub fn check_payload_size(header: &PacketHeader<UnknownType>, max: u32) -> Result<()> {
+    if header.payload_size > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Payload size {} exceeds the maximum of {}",
+                header.payload_size, max
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
 /// Reads the data of a packet from a TcpStream.
 /// The header type is UnknownType because this method is intended to be used in tandem with input_header,
 /// or any other method that reads from a socket, where the type will be unknown.
+///
+/// Returns an `InvalidData` error without reading anything if the payload is larger than max_payload_size.
 #[inline]
-pub fn input_data(stream: &mut TcpStream, header: &PacketHeader<UnknownType>) -> Result<Vec<u8>> {
+pub fn input_data(
+    stream: &mut TcpStream,
+    header: &PacketHeader<UnknownType>,
+    max_payload_size: u32,
+) -> Result<Vec<u8>> {
+    check_payload_size(header, max_payload_size)?;
     let mut data = vec![0; header.payload_size as usize];
 
     trace!("Reading {} bytes of data", header.payload_size);
@@ -39,18 +72,504 @@ pub fn input_data(stream: &mut TcpStream, header: &PacketHeader<UnknownType>) ->
     Ok(data)
 }
 
+/// Reads the data of a packet, calculating its checksum as it is read, and verifies it.
+///
+/// Unlike `input_data` followed by `verify_checksum`, the payload is only gone over once,
+/// so the checksum is ready as soon as the last chunk arrives.
+/// Like `input_data`, the payload size is checked against max_payload_size first.
+pub fn input_data_verified<R: Read + ?Sized>(
+    stream: &mut R,
+    header: &PacketHeader<UnknownType>,
+    max_payload_size: u32,
+) -> Result<Vec<u8>> {
+    check_payload_size(header, max_payload_size)?;
+    let mut buf = vec![0; header.payload_size as usize];
+    let mut crc = Crc32Writer::new();
+
+    trace!("Reading {} bytes of data", header.payload_size);
+
+    for chunk in buf.chunks_mut(CHECKSUM_CHUNK_SIZE) {
+        stream.read_exact(chunk)?;
+        crc.update(chunk);
+    }
+
+    trace!("Read data: {:?}", buf);
+
+    verify_checksum_value(header, crc.finish())?;
+    Ok(buf)
+}
+
+/// How much of a payload `input_data_verified` reads before adding it to the checksum.
+const CHECKSUM_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Verifies the checksum of a packet.
 ///
 /// This function is mainly a convenience function for verifying the checksum of a packet.
-/// It runs PacketHeader::verify_checksum, but converts a bool to an IOResult.
+/// It runs PacketHeader::verify_checksum, but converts a bool to a `ChecksumMismatch` error.
 #[inline]
 pub fn verify_checksum(header: &PacketHeader<UnknownType>, data: &[u8]) -> Result<()> {
     if header.verify_checksum(data) {
         Ok(())
     } else {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Checksums do not match",
-        ))
+        Err(LazuliError::ChecksumMismatch {
+            // Verification only fails if the header has a checksum.
+            expected: header.checksum().unwrap_or_default(),
+            actual: crate::header::crc32(data),
+        })
+    }
+}
+
+/// Verifies a checksum that was calculated while the payload was read.
+///
+/// Returns a `ChecksumMismatch` error if it does not match the header.
+#[inline]
+pub fn verify_checksum_value(header: &PacketHeader<UnknownType>, checksum: u32) -> Result<()> {
+    if header.verify_checksum_value(checksum) {
+        Ok(())
+    } else {
+        Err(LazuliError::ChecksumMismatch {
+            // Verification only fails if the header has a checksum.
+            expected: header.checksum().unwrap_or_default(),
+            actual: checksum,
+        })
+    }
+}
+
+/// Reads packets from a socket that may return `WouldBlock` at any point.
+///
+/// `input_header` and `input_data` use `read_exact`, which throws away whatever it has read if the socket would block.
+/// On a slow or fragmented non-blocking socket, that leaves the stream in the middle of a packet, and the next read will
+/// parse payload bytes as a header. The PacketReader keeps the partially read packet around, so the next call to
+/// `read_packet` picks up where the last one left off.
+#[derive(Debug)]
+pub struct PacketReader {
+    /// The header of the current packet, once it has been fully read.
+    header: Option<PacketHeader<UnknownType>>,
+    /// The header bytes, or the payload if the header has been read.
+    buf: Vec<u8>,
+    /// How many bytes of `buf` have been read.
+    filled: usize,
+    /// Bytes that have been read from the socket, but not used yet. Only used if read_capacity is not 0.
+    read_buf: Vec<u8>,
+    /// How many bytes of `read_buf` have been used.
+    read_pos: usize,
+    /// How many bytes to try to read from the socket at once. If this is 0, reads go straight into `buf`.
+    read_capacity: usize,
+    /// The checksum of the part of the payload that has been read so far.
+    crc: Crc32Writer,
+    /// The largest payload a header can claim before it is rejected.
+    max_payload_size: u32,
+}
+
+impl PacketReader {
+    /// Creates a new PacketReader that is waiting for the start of a packet.
+    pub fn new() -> Self {
+        PacketReader {
+            header: None,
+            buf: vec![0; HEADER_SIZE],
+            filled: 0,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_capacity: 0,
+            crc: Crc32Writer::new(),
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+        }
+    }
+
+    /// Creates a new PacketReader that reads up to `capacity` bytes from the socket at once.
+    ///
+    /// This means several small packets can be read with one read call, instead of two calls per packet.
+    /// Anything read past the end of the current packet is kept for the next one.
+    pub fn with_read_buffer(capacity: usize) -> Self {
+        PacketReader {
+            read_capacity: capacity,
+            ..Self::new()
+        }
+    }
+
+    /// Reads from `reader` until a full packet has been read, and returns its header and data.
+    ///
+    /// If the header claims a payload larger than the maximum payload size, an `InvalidData` error is returned
+    /// without reading or allocating the payload.
+    ///
+    /// If the reader returns an error (e.g. `WouldBlock`), the error is returned and everything read so far is kept.
+    /// Calling this again continues the same packet.
+    ///
+    /// If the reader reaches EOF between packets, a `ConnectionAborted` error is returned, because the peer closed the connection.
+    /// If it reaches EOF in the middle of a packet, an `UnexpectedEof` error is returned instead.
+    ///
+    /// The checksum is calculated as the payload is read, and a `ChecksumMismatch` error is returned if it does not match.
+    /// The packet is thrown away in that case, and the next call reads the next packet.
+    pub fn read_packet<R: Read + ?Sized>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<(PacketHeader<UnknownType>, Vec<u8>)> {
+        let mut data = Vec::new();
+        let header = self.read_packet_into(reader, &mut data)?;
+        Ok((header, data))
+    }
+
+    /// Like `read_packet`, but puts the payload in `out` instead of a new Vec.
+    ///
+    /// The allocation that was in `out` is kept by the reader and used for the next packet, so calling this in a loop
+    /// with the same `out` doesn't allocate once the buffers are large enough.
+    /// `out` is only changed once a full packet has been read.
+    pub fn read_packet_into<R: Read + ?Sized>(
+        &mut self,
+        reader: &mut R,
+        out: &mut Vec<u8>,
+    ) -> Result<PacketHeader<UnknownType>> {
+        if self.header.is_none() {
+            self.fill(reader)?;
+            trace!("Read header: {:?}", self.buf);
+            let header = PacketHeader::try_parse(&self.buf)?;
+            if let Err(e) = check_payload_size(&header, self.max_payload_size) {
+                // The payload is never read, so the connection can't be used after this.
+                self.filled = 0;
+                return Err(e);
+            }
+            trace!("Reading {} bytes of data", header.payload_size);
+            self.header = Some(header);
+            self.buf.clear();
+            self.buf.resize(header.payload_size as usize, 0);
+            self.filled = 0;
+            self.crc = Crc32Writer::new();
+        }
+        self.fill(reader)?;
+        let header = self.header.take().unwrap();
+        std::mem::swap(&mut self.buf, out);
+        self.buf.clear();
+        self.buf.resize(HEADER_SIZE, 0);
+        self.filled = 0;
+        trace!("Read data: {:?}", out);
+        verify_checksum_value(&header, self.crc.finish())?;
+        Ok(header)
+    }
+
+    /// Throws away any partially read packet and any buffered bytes, keeping the read buffer capacity.
+    ///
+    /// This is used when the socket is replaced, because what was read from the old socket is meaningless for the new one.
+    pub fn reset(&mut self) {
+        *self = PacketReader {
+            max_payload_size: self.max_payload_size,
+            ..Self::with_read_buffer(self.read_capacity)
+        };
+    }
+
+    /// Sets the largest payload a header can claim. Larger packets are rejected with an `InvalidData` error
+    /// before anything is allocated for them.
+    pub fn set_max_payload_size(&mut self, max: u32) {
+        self.max_payload_size = max;
+    }
+
+    /// Gets the largest payload a header can claim.
+    pub fn max_payload_size(&self) -> u32 {
+        self.max_payload_size
+    }
+
+    /// Copies bytes that were read from the socket but not used yet into `out`, and returns how many were copied.
+    ///
+    /// This is for reading raw bytes after a packet, because the read buffer may have already read past the packet.
+    pub fn take_buffered(&mut self, out: &mut [u8]) -> usize {
+        let available = &self.read_buf[self.read_pos..];
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.read_pos += n;
+        n
+    }
+
+    /// Returns true if part of a packet has been read, but not the whole thing.
+    pub fn in_progress(&self) -> bool {
+        self.header.is_some() || self.filled > 0
+    }
+
+    /// Reads into the rest of `buf`.
+    fn fill<R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<()> {
+        while self.filled < self.buf.len() {
+            let read = if self.read_capacity == 0 {
+                reader.read(&mut self.buf[self.filled..])
+            } else {
+                self.read_buffered(reader)
+            };
+            match read {
+                Ok(0) if !self.in_progress() => {
+                    // Nothing of the next packet has been read, so the peer closed the connection cleanly.
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "The peer closed the connection",
+                    )
+                    .into());
+                }
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The socket was closed in the middle of a packet",
+                    )
+                    .into())
+                }
+                Ok(n) => {
+                    if self.header.is_some() {
+                        // Checksum the payload as it arrives, so it doesn't need a second pass once it is all here.
+                        self.crc.update(&self.buf[self.filled..self.filled + n]);
+                    }
+                    self.filled += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PacketReader {
+    /// Reads into the rest of `buf` from `read_buf`, refilling `read_buf` from the reader if it is empty.
+    fn read_buffered<R: Read + ?Sized>(&mut self, reader: &mut R) -> io::Result<usize> {
+        if self.read_pos == self.read_buf.len() {
+            self.read_pos = 0;
+            self.read_buf.resize(self.read_capacity, 0);
+            match reader.read(&mut self.read_buf) {
+                Ok(n) => self.read_buf.truncate(n),
+                Err(e) => {
+                    self.read_buf.clear();
+                    return Err(e);
+                }
+            }
+        }
+        let available = &self.read_buf[self.read_pos..];
+        let n = available.len().min(self.buf.len() - self.filled);
+        self.buf[self.filled..self.filled + n].copy_from_slice(&available[..n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl Default for PacketReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use super::{PacketReader, DEFAULT_MAX_PAYLOAD_SIZE};
+    use crate::{
+        header::{crc32, Crc32Writer},
+        LazuliError, PacketHeader, Sendable, HEADER_SIZE,
+    };
+
+    /// A reader that yields one byte at a time, returning WouldBlock between every byte.
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+        block: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.block = !self.block;
+            if self.block {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if self.pos == self.data.len() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    fn packet<T: Sendable + 'static>(value: &T) -> Vec<u8> {
+        let data = value.send();
+        let mut header: PacketHeader<T> = unsafe { PacketHeader::new(data.len() as u32) };
+        header.calculate_checksum(&data);
+        let mut bytes = header.to_bytes().to_vec();
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let mut data = packet(&"Hello, world!".to_owned());
+        data.extend(packet(&42u32));
+        let mut trickle = Trickle {
+            data,
+            pos: 0,
+            block: false,
+        };
+        let mut reader = PacketReader::new();
+        let mut packets = vec![];
+        let mut would_blocks = 0;
+        while packets.len() < 2 {
+            match reader.read_packet(&mut trickle) {
+                Ok(packet) => packets.push(packet),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => would_blocks += 1,
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+        }
+        assert!(would_blocks > 0);
+        assert!(!reader.in_progress());
+
+        let (header, data) = &packets[0];
+        assert!(header.verify_checksum(data));
+        assert_eq!(
+            String::recv(&mut io::Cursor::new(data)).unwrap(),
+            "Hello, world!"
+        );
+        let (header, data) = &packets[1];
+        assert!(header.verify_checksum(data));
+        assert_eq!(u32::recv(&mut io::Cursor::new(data)).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_read_buffer() {
+        let mut data = vec![];
+        for i in 0..100u32 {
+            data.extend(packet(&i));
+        }
+        // Make sure packets that straddle the end of the buffer work, as well as ones that don't.
+        let mut cursor = io::Cursor::new(data);
+        let mut reader = PacketReader::with_read_buffer(50);
+        for i in 0..100u32 {
+            let (header, data) = reader.read_packet(&mut cursor).unwrap();
+            assert!(header.verify_checksum(&data));
+            assert_eq!(u32::recv(&mut io::Cursor::new(data)).unwrap(), i);
+        }
+        let err = reader.read_packet(&mut cursor).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn test_read_packet_into() {
+        let mut data = vec![];
+        for i in 0..1000u32 {
+            data.extend(packet(&i.to_string()));
+        }
+        let mut cursor = io::Cursor::new(data);
+        let mut reader = PacketReader::new();
+        let mut buf = Vec::new();
+        let mut buffers = std::collections::HashSet::new();
+        for i in 0..1000u32 {
+            let header = reader.read_packet_into(&mut cursor, &mut buf).unwrap();
+            assert_eq!(header.payload_size as usize, buf.len());
+            assert!(header.verify_checksum(&buf));
+            assert_eq!(
+                String::recv(&mut io::Cursor::new(&buf)).unwrap(),
+                i.to_string()
+            );
+            buffers.insert(buf.as_ptr());
+        }
+        // The reader and the caller trade the same two allocations back and forth, instead of allocating per packet.
+        assert!(buffers.len() <= 4, "Allocated {} buffers", buffers.len());
+    }
+
+    #[test]
+    fn test_eof_between_packets() {
+        let mut reader = PacketReader::new();
+        let mut cursor = io::Cursor::new(packet(&42u32));
+        reader.read_packet(&mut cursor).unwrap();
+        let err = reader.read_packet(&mut cursor).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(!reader.in_progress());
+    }
+
+    #[test]
+    fn test_eof_mid_packet() {
+        let mut data = packet(&42u32);
+        data.pop();
+        let mut reader = PacketReader::new();
+        let err = reader.read_packet(&mut io::Cursor::new(data)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(reader.in_progress());
+    }
+
+    #[test]
+    fn test_streaming_checksum() {
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut data = packet(&payload);
+        let header = PacketHeader::try_parse(&data[..HEADER_SIZE]).unwrap();
+        let one_shot = crc32(&data[HEADER_SIZE..]);
+        assert!(header.verify_checksum_value(one_shot));
+
+        // Checksumming the payload in uneven pieces gives the same checksum as doing it in one pass.
+        let mut crc = Crc32Writer::new();
+        for chunk in data[HEADER_SIZE..].chunks(1000 + 7) {
+            crc.update(chunk);
+        }
+        assert_eq!(crc.finish(), one_shot);
+
+        let mut cursor = io::Cursor::new(&data[HEADER_SIZE..]);
+        let read =
+            super::input_data_verified(&mut cursor, &header, DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+        assert_eq!(read, &data[HEADER_SIZE..]);
+
+        // The reader checks the checksum of the payload as it reads it, even when it arrives a byte at a time.
+        let mut trickle = Trickle {
+            data: data.clone(),
+            pos: 0,
+            block: false,
+        };
+        let mut reader = PacketReader::new();
+        loop {
+            match reader.read_packet(&mut trickle) {
+                Ok((_, read)) => {
+                    assert_eq!(read, &data[HEADER_SIZE..]);
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+        }
+
+        // Corrupting a byte in the middle of the payload is caught.
+        data[HEADER_SIZE + 100_000] ^= 0xFF;
+        let err = PacketReader::new()
+            .read_packet(&mut io::Cursor::new(&data))
+            .unwrap_err();
+        assert!(
+            matches!(err, LazuliError::ChecksumMismatch { expected, .. } if expected == one_shot)
+        );
+        let err = super::input_data_verified(
+            &mut io::Cursor::new(&data[HEADER_SIZE..]),
+            &header,
+            DEFAULT_MAX_PAYLOAD_SIZE,
+        )
+        .unwrap_err();
+        assert!(matches!(err, LazuliError::ChecksumMismatch { .. }));
+    }
+
+    #[test]
+    fn test_max_payload_size() {
+        // A header that claims a 4 GiB payload, with nothing after it.
+        let mut header: PacketHeader<Vec<u8>> = unsafe { PacketHeader::new(u32::MAX) };
+        header.calculate_checksum(&[]);
+        let bytes = header.to_bytes();
+        let mut reader = PacketReader::new();
+        assert_eq!(reader.max_payload_size(), DEFAULT_MAX_PAYLOAD_SIZE);
+        let err = reader
+            .read_packet(&mut io::Cursor::new(&bytes))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Nothing was allocated for the payload.
+        assert!(reader.buf.capacity() < 1024);
+
+        // The limit can be lowered.
+        let data = vec![1u8; 100];
+        let mut packet = data.header().to_bytes().to_vec();
+        packet.extend(data.send());
+        let mut reader = PacketReader::new();
+        reader.set_max_payload_size(64);
+        let err = reader
+            .read_packet(&mut io::Cursor::new(&packet))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        reader.reset();
+        assert_eq!(reader.max_payload_size(), 64);
+        reader.set_max_payload_size(1024);
+        let (_, read) = reader.read_packet(&mut io::Cursor::new(&packet)).unwrap();
+        assert_eq!(read, data.send());
     }
 }
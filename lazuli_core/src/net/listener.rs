@@ -1,116 +1,268 @@
 use std::{
-    io,
-    net::TcpStream,
+    io, mem,
     sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
 };
 
-use log::error;
+use log::{error, warn};
 
-use crate::{ArcMutex, Result};
+use crate::{ArcMutex, LazuliError, PacketHeader, Result, UnknownType, HEADER_SIZE};
 
-use super::{input, StreamCollection};
+use super::{
+    compression, input::PacketReader, socket::Socket, stats::StatsCounters, HandlerCollection,
+    StreamCollection, UnhandledPackets,
+};
 /// A listener for a TcpClient. This listener listens for incoming data on the socket and pushes it to the appropriate stream.
 /// This listener is intended to be used in tandem with a TcpClient, and is not intended to be used on its own.
 pub struct SocketListener {
-    socket: ArcMutex<TcpStream>,
+    socket: ArcMutex<Socket>,
     streams: ArcMutex<StreamCollection>,
+    handlers: ArcMutex<HandlerCollection>,
+    unhandled: ArcMutex<UnhandledPackets>,
+    reader: ArcMutex<PacketReader>,
+    stats: Arc<StatsCounters>,
     thread: Option<std::thread::JoinHandle<Result<()>>>,
     should_close: Arc<AtomicBool>,
-    error: Option<io::Error>,
+    error: ArcMutex<Option<LazuliError>>,
 }
 
 impl SocketListener {
     /// Creates a new SocketListener.
-    pub fn new(socket: ArcMutex<TcpStream>, streams: ArcMutex<StreamCollection>) -> Self {
+    pub fn new(
+        socket: ArcMutex<Socket>,
+        streams: ArcMutex<StreamCollection>,
+        handlers: ArcMutex<HandlerCollection>,
+        unhandled: ArcMutex<UnhandledPackets>,
+        reader: ArcMutex<PacketReader>,
+        stats: Arc<StatsCounters>,
+    ) -> Self {
         Self {
             socket,
             streams,
+            handlers,
+            unhandled,
+            reader,
+            stats,
             thread: None,
             should_close: Arc::new(AtomicBool::new(false)),
-            error: None,
+            error: Default::default(),
         }
     }
     /// Runs the listener. This starts a new thread that listens for incoming data on the socket.
-    pub fn run(&mut self) -> Result<()> {
+    ///
+    /// A listener can be run again after it is stopped. Does nothing if it was already started and has not been stopped.
+    ///
+    /// The thread is named after the prefix and the peer address (e.g. `lazuli-listener 127.0.0.1:8080`), so it can be told apart
+    /// from the listeners of other clients.
+    ///
+    /// If idle is set, the listener stops itself with a `TimedOut` error once no packet has been received for that long.
+    pub fn run(&mut self, prefix: &str, idle: Option<Duration>) -> Result<()> {
+        if self.thread.is_some() {
+            return Ok(());
+        }
+        // The error belongs to the last run.
+        *self.error.lock().unwrap() = None;
         let run = self.should_close.clone();
         let socket = self.socket.clone();
         // Set the socket to non-blocking mode. This is EXTREMELY important for the listener to work.
         // If it is blocking, the thread will never exit, and the program will hang.
         socket.lock().unwrap().set_nonblocking(true)?;
         let streams = self.streams.clone();
-        let thread = std::thread::Builder::new()
-            .name("RSOCK listener".to_string())
-            .spawn(move || Self::run_thread(run, socket, streams))?;
+        let handlers = self.handlers.clone();
+        let unhandled = self.unhandled.clone();
+        let reader = self.reader.clone();
+        let stats = self.stats.clone();
+        let error = self.error.clone();
+        let name = match socket.lock().unwrap().peer_addr() {
+            Ok(addr) => format!("{} {}", prefix, addr),
+            Err(_) => prefix.to_string(),
+        };
+        let thread = std::thread::Builder::new().name(name).spawn(move || {
+            Self::run_thread(
+                run, socket, streams, handlers, unhandled, reader, stats, error, idle,
+            )
+        })?;
         self.thread = Some(thread);
         Ok(())
     }
+    #[allow(clippy::too_many_arguments)] // Each of these is a separate piece of state shared with the client.
     fn run_thread(
         should_close: Arc<AtomicBool>,
-        socket: ArcMutex<TcpStream>,
+        socket: ArcMutex<Socket>,
         streams: ArcMutex<StreamCollection>,
+        handlers: ArcMutex<HandlerCollection>,
+        unhandled: ArcMutex<UnhandledPackets>,
+        reader: ArcMutex<PacketReader>,
+        stats: Arc<StatsCounters>,
+        error: ArcMutex<Option<LazuliError>>,
+        idle: Option<Duration>,
     ) -> Result<()> {
+        // The loop spins on WouldBlock, so idleness has to be measured with the clock rather than by counting iterations.
+        let mut last_packet = Instant::now();
+        // The reader keeps any partially read packet between iterations, so a WouldBlock in the middle of a packet
+        // doesn't cause the rest of the packet to be read as a new header.
+        // The payload buffer is reused for every packet, so the listener doesn't allocate per packet.
+        let mut buf = Vec::new();
         while !should_close.load(std::sync::atomic::Ordering::Acquire) {
-            match Self::thread_inner(should_close.clone(), socket.clone(), streams.clone()) {
-                Ok(_) => {}
-                Err(e) => {
-                    if e.kind() != io::ErrorKind::WouldBlock {
-                        error!("Error in listener thread: {}", e);
+            match Self::thread_inner(
+                &mut reader.lock().unwrap(),
+                &socket,
+                &streams,
+                &handlers,
+                &unhandled,
+                &stats,
+                &mut buf,
+            ) {
+                Ok(Ok(())) => last_packet = Instant::now(),
+                // Only this packet is lost, so it shouldn't take down every other stream on the connection.
+                // (e.g. a payload that can't be decoded, or a full bounded stream)
+                Ok(Err(e)) => {
+                    warn!("Dropped a packet: {}", e);
+                    stats.dropped();
+                    last_packet = Instant::now();
+                }
+                // Errors from reading the socket are fatal (e.g. the peer disconnected), besides WouldBlock.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if idle.is_some_and(|idle| last_packet.elapsed() >= idle) {
+                        let e = io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "No packet was received before the idle timeout",
+                        )
+                        .into();
+                        return Self::fail(e, &streams, &error);
                     }
                 }
+                Err(e) => return Self::fail(e, &streams, &error),
             }
         }
 
         Ok(())
     }
 
-    fn thread_inner(
-        should_close: Arc<AtomicBool>,
-        socket: ArcMutex<TcpStream>,
-        streams: ArcMutex<StreamCollection>,
+    /// Stops the thread because of e. The error is stored so the client can see why the listener stopped.
+    fn fail(
+        e: LazuliError,
+        streams: &ArcMutex<StreamCollection>,
+        error: &ArcMutex<Option<LazuliError>>,
     ) -> Result<()> {
-        let mut stream = socket.lock().unwrap();
-        let header = input::input_header(&mut *stream)?;
-        let mut would_block = true;
-        while would_block {
-            match input::input_data(&mut *stream, &header) {
-                Err(e) => {
-                    // if the thread is closing, return.
-                    if should_close.load(std::sync::atomic::Ordering::Acquire) {
-                        return Ok(());
-                    }
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        continue;
-                    }
-                    return Err(e);
-                }
-                Ok(data) => {
-                    input::verify_checksum(&header, data.as_slice())?;
-                    let mut streams = streams.lock().unwrap();
-                    if let Some(info) = streams.get_mut(&header.id()) {
-                        info.push(data, header)?;
-                    } else {
-                        error!("Stream not found: {}", header.id());
-                    }
-                    would_block = false;
-                }
-            }
+        error!("Error in listener thread: {}", e);
+        *error.lock().unwrap() = Some(e.duplicate());
+        // Nothing more will be received, so let anything waiting on a stream finish.
+        for connector in streams.lock().unwrap().values() {
+            connector.set_closed(true);
         }
-        Ok(())
+        Err(e)
+    }
+
+    /// Reads one packet, and passes it to its handler or stream.
+    ///
+    /// The outer error means the connection can't be used anymore (e.g. the peer disconnected, or a header couldn't be parsed).
+    /// The inner error only affects the packet that was read, so the listener can keep going.
+    fn thread_inner(
+        reader: &mut PacketReader,
+        socket: &ArcMutex<Socket>,
+        streams: &ArcMutex<StreamCollection>,
+        handlers: &ArcMutex<HandlerCollection>,
+        unhandled: &ArcMutex<UnhandledPackets>,
+        stats: &StatsCounters,
+        buf: &mut Vec<u8>,
+    ) -> Result<Result<()>> {
+        // The reader verifies the checksum as it reads the payload.
+        let header = match reader.read_packet_into(&mut *socket.lock().unwrap(), buf) {
+            Ok(header) => header,
+            // The checksum is checked after the whole payload is read, so the next packet still starts in the right place.
+            Err(e @ LazuliError::ChecksumMismatch { .. }) => {
+                stats.received((HEADER_SIZE + buf.len()) as u64);
+                return Ok(Err(e));
+            }
+            Err(e) => return Err(e),
+        };
+        stats.received((HEADER_SIZE + buf.len()) as u64);
+        let max_payload_size = reader.max_payload_size();
+        Ok(Self::dispatch(
+            header,
+            streams,
+            handlers,
+            unhandled,
+            buf,
+            max_payload_size,
+        ))
     }
-    /// Gets the error, if there is one. This will return None if there is no error.
-    pub fn error(&self) -> Option<io::Error> {
-        if let Some(err) = &self.error {
-            // Make a clone of the error. (I don't know why io::Error doesn't implement Clone, but it's probably for a good reason.)
-            Some(io::Error::new(err.kind(), err.to_string()))
+
+    /// Passes a packet to its handler, or pushes it to its stream.
+    fn dispatch(
+        mut header: PacketHeader<UnknownType>,
+        streams: &ArcMutex<StreamCollection>,
+        handlers: &ArcMutex<HandlerCollection>,
+        unhandled: &ArcMutex<UnhandledPackets>,
+        buf: &mut Vec<u8>,
+        max_payload_size: u32,
+    ) -> Result<()> {
+        if header.is_compressed() {
+            *buf = compression::decompress(&mut header, mem::take(buf), max_payload_size)?;
+        }
+        // Handlers take priority over streams.
+        if let Some(handler) = handlers.lock().unwrap().get_mut(&header.type_id()) {
+            return handler(&mut io::Cursor::new(buf.as_slice()));
+        }
+        let mut streams = streams.lock().unwrap();
+        if let Some(info) = streams.get_mut(&header.type_id()) {
+            info.push(buf, header)?;
         } else {
-            None
+            warn!("No stream or handler for type id {}", header.type_id());
+            // Unhandled packets are kept, so they need their own copy.
+            unhandled
+                .lock()
+                .unwrap()
+                .push((header.type_id(), buf.to_vec()));
         }
+        Ok(())
+    }
+
+    /// Gets the error that stopped the listener, if there is one. This will return None if there is no error.
+    pub fn error(&self) -> Option<LazuliError> {
+        // Make a copy of the error. (I don't know why io::Error doesn't implement Clone, but it's probably for a good reason.)
+        self.error
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(LazuliError::duplicate)
+    }
+    /// Returns true if the listener has been started, and has not been stopped. The thread may have exited because of an error.
+    pub fn is_started(&self) -> bool {
+        self.thread.is_some()
     }
+
+    /// Returns true if the listener thread has been started, and has not exited.
+    ///
+    /// The thread exits when it is stopped, or when it hits an error (see [`SocketListener::error`]).
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
     /// Stops the listener. This will stop the listener thread.
+    ///
+    /// Returns the error that stopped the thread if it exited because of an error, or Ok if it was stopped cleanly.
+    /// Does nothing if the listener is not running.
+    ///
+    /// The socket is put back in blocking mode, which is how the client keeps it when it is not listening.
     pub fn stop(&mut self) -> Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
         self.should_close
             .store(true, std::sync::atomic::Ordering::Release);
-        self.thread.take().unwrap().join().unwrap()
+        let result = thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("The listener thread panicked").into()));
+        // Reset everything the thread used, so the listener can be run again.
+        self.should_close
+            .store(false, std::sync::atomic::Ordering::Release);
+        *self.error.lock().unwrap() = None;
+        self.socket.lock().unwrap().set_nonblocking(false)?;
+        result
     }
 }
 
@@ -124,6 +276,207 @@ impl Drop for SocketListener {
 
 #[cfg(test)]
 mod tests {
+    use std::time::{Duration, Instant};
 
-    // TODO: add tests or a macro to generate tests.
+    use crate::net::test_utils::make_client_server_pair;
+
+    #[test]
+    fn test_error_on_disconnect() {
+        let (mut client, server) = make_client_server_pair();
+        client.listen().unwrap();
+        assert!(client.error().is_none());
+        drop(server);
+        let start = Instant::now();
+        while client.is_listening() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Listener never stopped after the disconnect"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let reported = client
+            .error()
+            .expect("Listener never reported the disconnect");
+        // The thread's result should be the same error.
+        let err = client.stop_listening().unwrap_err();
+        assert_eq!(err.kind(), reported.kind());
+    }
+
+    #[test]
+    fn test_stop_result() {
+        let (mut client, _server) = make_client_server_pair();
+        assert!(!client.is_listening());
+        client.listen().unwrap();
+        assert!(client.is_listening());
+        client.stop_listening().unwrap();
+        assert!(!client.is_listening());
+        // Stopping again does nothing.
+        client.stop_listening().unwrap();
+        // The client can receive normally after the listener has stopped.
+        assert!(!client.try_recv().unwrap());
+    }
+
+    #[test]
+    fn test_restart() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<u32>();
+        client.listen().unwrap();
+        // Listening again while running does nothing.
+        client.listen().unwrap();
+        server.send(&1u32).unwrap();
+        assert_eq!(stream.get_timeout(Duration::from_secs(5)).unwrap(), 1);
+        client.stop_listening().unwrap();
+
+        // The socket is blocking again, so recv waits for the packet.
+        server.send(&2u32).unwrap();
+        client.recv().unwrap();
+        assert_eq!(stream.get().unwrap(), 2);
+
+        // The same listener is run again.
+        client.listen().unwrap();
+        assert!(client.is_listening());
+        for i in 3..6u32 {
+            server.send(&i).unwrap();
+            assert_eq!(stream.get_timeout(Duration::from_secs(5)).unwrap(), i);
+        }
+        client.stop_listening().unwrap();
+        assert!(!client.is_listening());
+        assert!(!client.try_recv().unwrap());
+    }
+
+    #[test]
+    fn test_blocking_iter_ends_on_disconnect() {
+        let (mut client, mut server) = make_client_server_pair();
+        let stream = client.stream::<u32>();
+        client.listen().unwrap();
+        let sender = std::thread::spawn(move || {
+            for i in 0..3u32 {
+                server.send(&i).unwrap();
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            drop(server);
+        });
+        let items: Vec<u32> = stream.into_blocking_iter().collect();
+        sender.join().unwrap();
+        assert_eq!(items, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_thread_name() {
+        let (mut client, mut server) = make_client_server_pair();
+        let names = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let record = names.clone();
+        // Handlers run on the listener thread.
+        client.on(move |_: u32| {
+            let name = std::thread::current().name().map(str::to_owned);
+            record.lock().unwrap().push(name);
+        });
+        let peer = client.peer_addr().unwrap();
+
+        client.listen().unwrap();
+        server.send(&1u32).unwrap();
+        wait_for_len(&names, 1);
+        client.stop_listening().unwrap();
+
+        client.listen_named("worker").unwrap();
+        server.send(&2u32).unwrap();
+        wait_for_len(&names, 2);
+
+        let names = names.lock().unwrap();
+        assert_eq!(names[0], Some(format!("lazuli-listener {}", peer)));
+        assert_eq!(names[1], Some(format!("worker {}", peer)));
+    }
+
+    /// Waits until vec has len items.
+    fn wait_for_len<T>(vec: &std::sync::Mutex<Vec<T>>, len: usize) {
+        let start = Instant::now();
+        while vec.lock().unwrap().len() < len {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_listen_until_idle() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<u32>();
+        let idle = Duration::from_millis(300);
+        let start = Instant::now();
+        client.listen_until_idle(idle).unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        server.send(&5u32).unwrap();
+        assert_eq!(stream.get_timeout(Duration::from_secs(5)), Some(5));
+        while client.is_listening() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Listener never stopped after going idle"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // The packet restarted the idle window, so the listener can't have stopped before it ended.
+        assert!(start.elapsed() >= Duration::from_millis(150) + idle);
+        assert!(stream.is_closed());
+        let err = client.stop_listening().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_overflow_while_listening() {
+        let (mut client, mut server) = make_client_server_pair();
+        let mut full = client.bounded_stream::<u32>(1, crate::Overflow::Error);
+        let mut other = client.stream::<String>();
+        client.listen().unwrap();
+        for i in 0..3u32 {
+            server.send(&i).unwrap();
+        }
+        server.send(&"after".to_owned()).unwrap();
+        assert_eq!(
+            other.get_timeout(Duration::from_secs(5)).as_deref(),
+            Some("after")
+        );
+        assert!(client.is_listening());
+        assert!(client.error().is_none());
+        // Only the first packet fit. The others were dropped.
+        assert_eq!(full.drain(), vec![0]);
+        assert_eq!(client.stats().packets_dropped, 2);
+
+        // There is room again once the stream has been emptied.
+        server.send(&3u32).unwrap();
+        assert_eq!(full.get_timeout(Duration::from_secs(5)), Some(3));
+        client.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_packet_while_listening() {
+        use crate::{PacketHeader, Sendable};
+
+        let (mut client, mut server) = make_client_server_pair();
+        let mut stream = client.stream::<String>();
+        client.listen().unwrap();
+        // A String that isn't valid UTF-8, with a correct checksum.
+        let payload = [0, 0, 0, 1, 0xFF];
+        let mut header: PacketHeader<String> = unsafe { PacketHeader::new(5) };
+        header.calculate_checksum(&payload);
+        server.send_raw(&header.to_bytes()).unwrap();
+        server.send_raw(&payload).unwrap();
+        // A valid String, with the wrong checksum.
+        let payload = "bad".to_owned().send();
+        let mut header = "bad".to_owned().header();
+        header.set_checksum(!crate::header::crc32(&payload));
+        server.send_raw(&header.to_bytes()).unwrap();
+        server.send_raw(&payload).unwrap();
+
+        server.send(&"valid".to_owned()).unwrap();
+        assert_eq!(
+            stream.get_timeout(Duration::from_secs(5)).as_deref(),
+            Some("valid")
+        );
+        assert!(client.is_listening());
+        assert!(client.error().is_none());
+        assert_eq!(stream.len(), 0);
+        let stats = client.stats();
+        assert_eq!(stats.packets_received, 3);
+        assert_eq!(stats.packets_dropped, 2);
+        client.stop_listening().unwrap();
+    }
 }
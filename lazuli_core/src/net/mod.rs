@@ -1,24 +1,43 @@
+#[cfg(feature = "tokio")]
+mod async_client;
 mod client;
+mod compression;
 mod config;
 mod connector;
 mod input;
 mod listener;
 mod server;
+mod socket;
+mod stats;
+mod udp;
+#[cfg(unix)]
+mod unix;
 
-pub(self) type StreamCollection = std::collections::HashMap<u32, connector::StreamConnector>;
+type StreamCollection = std::collections::HashMap<u64, connector::StreamConnector>;
+/// A type-erased packet handler. This deserializes the payload and passes it to the user's callback.
+type Handler = Box<dyn FnMut(&mut dyn std::io::Read) -> crate::Result<()> + Send>;
+type HandlerCollection = std::collections::HashMap<u64, Handler>;
+/// Packets that arrived without a stream or handler for their type, as (type_id, payload).
+type UnhandledPackets = Vec<(u64, Vec<u8>)>;
 
+#[cfg(feature = "tokio")]
+pub use async_client::AsyncClient;
 pub use client::Client;
-pub use server::Server;
+pub use config::SocketConfig;
+#[cfg(test)]
+pub(crate) use connector::StreamConnector;
+pub use input::DEFAULT_MAX_PAYLOAD_SIZE;
+pub use server::{ClientId, Server, ShutdownHandle};
+pub use socket::{Socket, Transport};
+pub use stats::ClientStats;
+pub use udp::{UdpClient, MAX_UDP_PAYLOAD};
+#[cfg(unix)]
+pub use unix::UnixServer;
 
 #[cfg(test)]
 /// Test utilities for the client module.
 mod test_utils {
-    use std::{
-        net::{IpAddr, Ipv4Addr, SocketAddr},
-        sync::Mutex,
-    };
-
-    use log::debug;
+    use std::net::Ipv4Addr;
 
     use crate::Sendable;
 
@@ -45,7 +64,7 @@ mod test_utils {
     /// Tests sending and receiving data. Convenience function for testing.
     pub(super) fn test_send_recv<T>(client: &mut Client, server: &mut Client, data: T)
     where
-        T: Sendable + 'static + PartialEq,
+        T: Sendable + Send + 'static + PartialEq + std::fmt::Debug,
     {
         let mut stream = client.stream::<T>();
         server.send(&data).unwrap();
@@ -1,42 +1,208 @@
 use std::{
-    net::{TcpListener, ToSocketAddrs},
-    sync::{Arc, Mutex},
+    io::ErrorKind,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use crate::{ArcMutex, Client, Result, Sendable};
+use crate::{ArcMutex, Client, LazuliError, Result, Sendable};
 
-use super::config::{self, SocketConfig};
+use super::{client::PreparedPacket, config::SocketConfig};
+
+/// Identifies a client accepted by a [`Server`].
+///
+/// Ids are assigned in the order clients are accepted, and are never reused by the same server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClientId(pub u64);
 
 pub struct Server {
-    listener: TcpListener,
-    streams: Vec<ArcMutex<Client>>,
+    /// Always has at least one listener. If there are several, they are all non-blocking, and accepting polls them in turn.
+    listeners: Vec<TcpListener>,
+    streams: Vec<(ClientId, ArcMutex<Client>)>,
+    next_id: u64,
+    /// Whether the listener has been put into non-blocking mode by a config.
+    nonblocking: bool,
+    /// Set when the server should stop serving.
+    shutdown: Arc<AtomicBool>,
+    /// The threads spawned by `serve`.
+    handler_threads: Vec<JoinHandle<()>>,
+}
+
+/// A handle that can stop a [`Server`] that is running `serve`, from any thread.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    shutdown: Arc<AtomicBool>,
 }
-/// TODO: down the road, add a tokio feature flag and use tokio for various async operations.
+
+impl ShutdownHandle {
+    /// Tells the server to stop accepting clients. `serve` returns once every handler thread has finished.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Release);
+    }
+}
+/// TODO: The tokio feature only has a client (see `AsyncClient`). Add an async server as well.
 impl Server {
     /// Creates a new server.
+    ///
+    /// If addrs resolves to several addresses, this listens on the first one that can be bound.
+    /// Use `new_multi` to listen on all of them.
     pub fn new<T: ToSocketAddrs>(addrs: T) -> Result<Self> {
         let listener = TcpListener::bind(addrs)?;
-        Ok(Server {
-            listener,
+        Ok(Self::from_listeners(vec![listener]))
+    }
+
+    /// Creates a server that listens on every address addrs resolves to. (e.g. both an IPv4 and an IPv6 address)
+    ///
+    /// Accepting takes a connection from whichever address has one. Returns an error if any address can't be bound,
+    /// or if addrs doesn't resolve to any address.
+    pub fn new_multi<T: ToSocketAddrs>(addrs: T) -> Result<Self> {
+        let listeners = addrs
+            .to_socket_addrs()?
+            .map(TcpListener::bind)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        if listeners.is_empty() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "The addresses did not resolve to anything to listen on",
+            )
+            .into());
+        }
+        let server = Self::from_listeners(listeners);
+        server.restore_blocking()?;
+        Ok(server)
+    }
+
+    fn from_listeners(listeners: Vec<TcpListener>) -> Self {
+        Server {
+            listeners,
             streams: vec![],
-        })
+            next_id: 0,
+            nonblocking: false,
+            shutdown: Default::default(),
+            handler_threads: vec![],
+        }
     }
     /// Adds a configuration to the server.
-    pub fn with_config(self, config: SocketConfig) -> Result<Self> {
-        config.apply_listener(&self.listener)?;
+    pub fn with_config(mut self, config: SocketConfig) -> Result<Self> {
+        for listener in &self.listeners {
+            config.apply_listener(listener)?;
+        }
+        if let Some(blocking) = config.blocking {
+            self.nonblocking = !blocking;
+        }
+        // Several listeners have to stay non-blocking, whatever the config says.
+        self.restore_blocking()?;
         Ok(self)
     }
-    /// Accepts a connection.
-    pub fn accept(&mut self) -> Result<ArcMutex<Client>> {
-        let stream = self.listener.accept()?.0;
-        let stream = Client::from_stream(stream);
-        let stream = Arc::new(Mutex::new(stream));
-        self.streams.push(stream.clone());
-        Ok(stream)
+
+    /// Puts the listeners back in the blocking mode they are kept in when nothing is accepting.
+    fn restore_blocking(&self) -> std::io::Result<()> {
+        let nonblocking = self.nonblocking || self.listeners.len() > 1;
+        for listener in &self.listeners {
+            listener.set_nonblocking(nonblocking)?;
+        }
+        Ok(())
+    }
+
+    /// Accepts a connection from whichever listener has one, without waiting. The listeners must be non-blocking.
+    ///
+    /// Returns a `WouldBlock` error if none of them has a connection.
+    fn try_accept_any(&self) -> std::io::Result<TcpStream> {
+        for listener in &self.listeners {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // Some platforms give accepted sockets the non-blocking mode of the listener.
+                    stream.set_nonblocking(false)?;
+                    return Ok(stream);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ErrorKind::WouldBlock.into())
+    }
+
+    /// Accepts a connection from any of the listeners. This blocks unless the server is non-blocking.
+    fn accept_stream(&self) -> std::io::Result<TcpStream> {
+        if let [listener] = self.listeners.as_slice() {
+            return listener.accept().map(|(stream, _)| stream);
+        }
+        loop {
+            match self.try_accept_any() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock && !self.nonblocking => {
+                    std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Accepts a connection. Returns the id assigned to the client along with the client.
+    pub fn accept(&mut self) -> Result<(ClientId, ArcMutex<Client>)> {
+        let stream = self.accept_stream()?;
+        Ok(self.add_client(stream))
+    }
+
+    /// Accepts a connection, and wraps it in TLS using config. Returns the id assigned to the client along with the client.
+    ///
+    /// The TLS handshake is completed before this returns. If it fails, the error is returned and no client is added.
+    #[cfg(feature = "tls")]
+    pub fn accept_tls(
+        &mut self,
+        config: Arc<rustls::ServerConfig>,
+    ) -> Result<(ClientId, ArcMutex<Client>)> {
+        let stream = self.accept_stream()?;
+        let socket = super::socket::Socket::tls_server(stream, config)?;
+        Ok(self.add_socket(socket))
+    }
+
+    /// Accepts a connection, waiting at most `timeout` for one to arrive.
+    ///
+    /// Returns `Ok(None)` if no connection arrived in time.
+    /// This works whether or not the server is non-blocking, and leaves the blocking mode as it was.
+    pub fn accept_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<(ClientId, ArcMutex<Client>)>> {
+        let deadline = Instant::now() + timeout;
+        for listener in &self.listeners {
+            listener.set_nonblocking(true)?;
+        }
+        let result = loop {
+            match self.try_accept_any() {
+                Ok(stream) => break Ok(Some(stream)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Ok(None);
+                    }
+                    std::thread::sleep(ACCEPT_POLL_INTERVAL.min(deadline - now));
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        let restored = self.restore_blocking();
+        let stream = result?;
+        restored?;
+        Ok(stream.map(|stream| self.add_client(stream)))
+    }
+
+    /// Accepts a connection, and applies the configuration to its socket before wrapping it in a client.
+    pub fn accept_with_config(
+        &mut self,
+        config: &SocketConfig,
+    ) -> Result<(ClientId, ArcMutex<Client>)> {
+        let stream = self.accept_stream()?;
+        config.apply_stream(&stream)?;
+        Ok(self.add_client(stream))
     }
 
     /// Accepts n connections.
-    pub fn accept_n(&mut self, n: usize) -> Result<Vec<ArcMutex<Client>>> {
+    pub fn accept_n(&mut self, n: usize) -> Result<Vec<(ClientId, ArcMutex<Client>)>> {
         let mut streams = vec![];
         for _ in 0..n {
             streams.push(self.accept()?);
@@ -44,32 +210,228 @@ impl Server {
         Ok(streams)
     }
 
-    pub fn incoming(&mut self) -> impl Iterator<Item = Result<ArcMutex<Client>>> + '_ {
-        self.listener.incoming().map(|stream| {
-            let stream = stream?;
-            let stream = Client::from_stream(stream);
-            let stream = Arc::new(Mutex::new(stream));
-            self.streams.push(stream.clone());
-            Ok(stream)
-        })
+    /// Accepts n connections, and applies the configuration to each of them.
+    pub fn accept_n_with_config(
+        &mut self,
+        n: usize,
+        config: &SocketConfig,
+    ) -> Result<Vec<(ClientId, ArcMutex<Client>)>> {
+        let mut streams = vec![];
+        for _ in 0..n {
+            streams.push(self.accept_with_config(config)?);
+        }
+        Ok(streams)
+    }
+
+    /// Returns an iterator that accepts connections. It never ends, like `TcpListener::incoming`.
+    pub fn incoming(&mut self) -> impl Iterator<Item = Result<(ClientId, ArcMutex<Client>)>> + '_ {
+        std::iter::from_fn(move || Some(self.accept()))
+    }
+
+    /// Like `incoming`, but applies the configuration to each accepted socket before wrapping it in a client.
+    ///
+    /// If the configuration can't be applied to a socket, the error is returned in its place, and the socket is dropped.
+    pub fn incoming_with_config(
+        &mut self,
+        config: SocketConfig,
+    ) -> impl Iterator<Item = Result<(ClientId, ArcMutex<Client>)>> + '_ {
+        std::iter::from_fn(move || Some(self.accept_with_config(&config)))
+    }
+
+    /// Wraps a newly accepted socket in a client and assigns it the next id.
+    fn add_client(&mut self, stream: TcpStream) -> (ClientId, ArcMutex<Client>) {
+        self.add_socket(stream.into())
+    }
+
+    /// Wraps a newly accepted connection in a client and assigns it the next id.
+    fn add_socket(&mut self, socket: super::socket::Socket) -> (ClientId, ArcMutex<Client>) {
+        let id = ClientId(self.next_id);
+        self.next_id += 1;
+        let stream = Arc::new(Mutex::new(Client::from_socket(socket)));
+        self.streams.push((id, stream.clone()));
+        (id, stream)
+    }
+
+    /// Accepts clients until the server is shut down, and runs `handler` on a new thread for each one.
+    /// The threads are named `lazuli-handler` followed by the peer address.
+    ///
+    /// Use a [`ShutdownHandle`] from `shutdown_handle` to stop the server from another thread.
+    /// Once it is stopped, this waits for every handler thread to finish before returning.
+    /// If accepting fails, the error is returned after the handler threads have finished.
+    pub fn serve<F>(&mut self, handler: F) -> Result<()>
+    where
+        F: Fn(ArcMutex<Client>) + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let result = loop {
+            if self.shutdown.load(Ordering::Acquire) {
+                break Ok(());
+            }
+            // Poll for clients instead of blocking, so a shutdown is noticed even if nobody connects.
+            match self.accept_timeout(SERVE_POLL_INTERVAL) {
+                Ok(Some((_, client))) => {
+                    let handler = handler.clone();
+                    // Named like the listener threads, so the handler of each client can be told apart.
+                    let name = match client.lock().unwrap().peer_addr() {
+                        Ok(addr) => format!("{} {}", HANDLER_NAME, addr),
+                        Err(_) => HANDLER_NAME.to_string(),
+                    };
+                    match std::thread::Builder::new()
+                        .name(name)
+                        .spawn(move || handler(client))
+                    {
+                        Ok(thread) => self.handler_threads.push(thread),
+                        Err(e) => break Err(e.into()),
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.shutdown().and(result)
+    }
+
+    /// Gets a handle that can be used to stop `serve` from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Stops accepting clients in `serve`, and waits for every handler thread it spawned to finish.
+    /// Once a server is shut down, any later call to `serve` returns immediately.
+    ///
+    /// Returns an error if any of the handler threads panicked.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.shutdown.store(true, Ordering::Release);
+        let mut panicked = 0;
+        for thread in self.handler_threads.drain(..) {
+            if thread.join().is_err() {
+                panicked += 1;
+            }
+        }
+        if panicked > 0 {
+            return Err(
+                std::io::Error::other(format!("{} handler thread(s) panicked", panicked)).into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Gets the number of clients the server is holding.
+    ///
+    /// Disconnected clients are counted until they are removed by `broadcast` or `prune_disconnected`.
+    pub fn client_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Gets the client with the given id, if the server still holds it.
+    pub fn get_client(&self, id: ClientId) -> Option<ArcMutex<Client>> {
+        self.streams
+            .iter()
+            .find(|(client_id, _)| *client_id == id)
+            .map(|(_, client)| client.clone())
     }
 }
 
 impl Server {
     /// Sends a message to all clients.
-    pub fn broadcast<T: Sendable + 'static>(&self, data: &T) -> Result<()> {
-        for stream in &self.streams {
-            let mut stream = stream.lock().unwrap();
-            stream.send(data)?;
+    ///
+    /// Clients that have disconnected are removed from the server.
+    /// If sending fails for any other reason, the first error is returned after every client has been sent to.
+    ///
+    /// The data is serialized and checksummed once, and the same bytes are sent to every client.
+    pub fn broadcast<T: Sendable + 'static>(&mut self, data: &T) -> Result<()> {
+        let packet = PreparedPacket::new(data);
+        let mut result = Ok(());
+        self.streams.retain(
+            |(_, stream)| match stream.lock().unwrap().send_prepared(&packet) {
+                Ok(()) => true,
+                Err(e) if is_disconnect(&e) => false,
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                    true
+                }
+            },
+        );
+        result
+    }
+
+    /// Sends a message to every client that `predicate` returns true for.
+    ///
+    /// Like `broadcast`, the data is only serialized once.
+    /// Unlike `broadcast`, this does not stop at or remove failing clients.
+    /// Every send error is collected and returned along with the id of the client it came from.
+    pub fn broadcast_filtered<T, F>(&self, data: &T, predicate: F) -> Vec<(ClientId, LazuliError)>
+    where
+        T: Sendable + 'static,
+        F: Fn(&Client) -> bool,
+    {
+        let packet = PreparedPacket::new(data);
+        let mut errors = vec![];
+        for (id, stream) in &self.streams {
+            let mut client = stream.lock().unwrap();
+            if !predicate(&client) {
+                continue;
+            }
+            if let Err(e) = client.send_prepared(&packet) {
+                errors.push((*id, e));
+            }
         }
-        Ok(())
+        errors
+    }
+
+    /// Sends a message to a single client.
+    ///
+    /// Returns a `NotFound` error if the server does not hold a client with the given id.
+    pub fn send_to<T: Sendable + 'static>(&self, id: ClientId, data: &T) -> Result<()> {
+        let client = self.get_client(id).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, format!("No client with id {}", id.0))
+        })?;
+        let result = client.lock().unwrap().send(data);
+        result
+    }
+
+    /// Removes any clients that are no longer connected.
+    pub fn prune_disconnected(&mut self) {
+        self.streams
+            .retain(|(_, stream)| stream.lock().unwrap().is_connected());
     }
     /// Gets the local address of the server.
-    pub fn local_addr(&self) -> Result<std::net::SocketAddr> {
-        self.listener.local_addr()
+    ///
+    /// If the server listens on several addresses (see `new_multi`), this is the first one. Use `local_addrs` to get all of them.
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// Gets every local address the server listens on.
+    pub fn local_addrs(&self) -> std::io::Result<Vec<std::net::SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
     }
 }
 
+/// How long `accept_timeout` sleeps between checks for a new connection.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The name of each `serve` handler thread, before the peer address.
+const HANDLER_NAME: &str = "lazuli-handler";
+
+/// How long `serve` waits for a client before checking if it should shut down.
+const SERVE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Checks if an error means that the peer has disconnected.
+fn is_disconnect(e: &LazuliError) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+    )
+}
+
 #[cfg(test)]
 mod test {
     use std::net::Ipv4Addr;
@@ -81,7 +443,7 @@ mod test {
     fn make_server_client_pair(server: &mut Server) -> (Client, ArcMutex<Client>) {
         let addr = server.local_addr().unwrap();
         let client = Client::connect(addr).unwrap();
-        let server_client = server.accept().unwrap();
+        let (_, server_client) = server.accept().unwrap();
         (client, server_client)
     }
 
@@ -91,7 +453,7 @@ mod test {
         let (mut client, server_client) = make_server_client_pair(&mut server);
         test_send_recv(
             &mut client,
-            &mut *server_client.lock().unwrap(),
+            &mut server_client.lock().unwrap(),
             "Hello, world!".to_owned(),
         );
         Ok(())
@@ -112,6 +474,215 @@ mod test {
         assert_eq!(str_stream_2.get().unwrap(), "Hello, world!".to_owned());
         Ok(())
     }
+    #[test]
+    fn test_broadcast_serializes_once() -> Result<()> {
+        use std::sync::atomic::AtomicUsize;
+
+        static SENDS: AtomicUsize = AtomicUsize::new(0);
+
+        /// Counts how many times it is serialized.
+        struct Counted(u64);
+
+        impl Sendable for Counted {
+            fn send(&self) -> Vec<u8> {
+                SENDS.fetch_add(1, Ordering::Relaxed);
+                self.0.send()
+            }
+
+            fn recv(data: &mut dyn std::io::Read) -> Result<Self> {
+                Ok(Counted(u64::recv(data)?))
+            }
+        }
+
+        let mut server = make_server();
+        let (mut client1, _) = make_server_client_pair(&mut server);
+        let (mut client2, _) = make_server_client_pair(&mut server);
+        server.broadcast(&Counted(42))?;
+        assert_eq!(SENDS.load(Ordering::Relaxed), 1);
+
+        let len = crate::HEADER_SIZE + 8;
+        let bytes = client1.recv_raw(len)?;
+        assert_eq!(bytes, client2.recv_raw(len)?);
+        assert_eq!(bytes[crate::HEADER_SIZE..], 42u64.to_be_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast_removes_disconnected() -> Result<()> {
+        let mut server = make_server();
+        let (mut alive, _) = make_server_client_pair(&mut server);
+        let (dead, _) = make_server_client_pair(&mut server);
+        let mut stream = alive.stream::<String>();
+        drop(dead);
+        // The first write to a closed socket usually succeeds, so it can take a couple of tries to notice.
+        let mut tries = 0;
+        while server.client_count() == 2 {
+            assert!(tries < 100, "Disconnected client was never removed");
+            server.broadcast(&"Hello, world!".to_owned())?;
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            tries += 1;
+        }
+        assert_eq!(server.client_count(), 1);
+        alive.recv()?;
+        assert_eq!(stream.get().unwrap(), "Hello, world!".to_owned());
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast_filtered() -> Result<()> {
+        let mut server = make_server();
+        let (mut client1, _) = make_server_client_pair(&mut server);
+        let (mut client2, _) = make_server_client_pair(&mut server);
+        let mut stream1 = client1.stream::<String>();
+        let mut stream2 = client2.stream::<String>();
+        let target = client1.local_addr()?;
+        let errors = server.broadcast_filtered(&"Hello, world!".to_owned(), |client| {
+            client.peer_addr().is_ok_and(|addr| addr == target)
+        });
+        assert!(errors.is_empty());
+        client1.recv()?;
+        assert_eq!(stream1.get().unwrap(), "Hello, world!".to_owned());
+        assert!(!client2.try_recv()?);
+        assert_eq!(stream2.get(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_ids() -> Result<()> {
+        let mut server = make_server();
+        let addr = server.local_addr()?;
+        let mut client1 = Client::connect(addr)?;
+        let (id1, _) = server.accept()?;
+        let mut client2 = Client::connect(addr)?;
+        let (id2, _) = server.accept()?;
+        assert_ne!(id1, id2);
+        assert_eq!(server.client_count(), 2);
+
+        let mut stream1 = client1.stream::<u32>();
+        let mut stream2 = client2.stream::<u32>();
+        server.send_to(id2, &42u32)?;
+        assert!(!client1.try_recv()?);
+        client2.recv()?;
+        assert_eq!(stream2.get(), Some(42));
+        assert_eq!(stream1.get(), None);
+
+        let err = server.send_to(ClientId(1000), &0u32).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_timeout() -> Result<()> {
+        let mut server = make_server();
+        let start = Instant::now();
+        assert!(server.accept_timeout(Duration::from_millis(100))?.is_none());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(2));
+
+        let mut client = Client::connect(server.local_addr()?)?;
+        let (id, server_client) = server
+            .accept_timeout(Duration::from_secs(5))?
+            .expect("Connection was not accepted");
+        assert_eq!(server.client_count(), 1);
+        client.send(&"Hello, world!".to_owned())?;
+        let mut stream = server_client.lock().unwrap().stream::<String>();
+        server_client.lock().unwrap().recv()?;
+        assert_eq!(stream.get(), Some("Hello, world!".to_owned()));
+        server.send_to(id, &"Hello, client!".to_owned())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_multi() -> Result<()> {
+        let addrs: [std::net::SocketAddr; 2] = [
+            (Ipv4Addr::LOCALHOST, 0).into(),
+            (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        ];
+        let mut server = Server::new_multi(&addrs[..])?;
+        let bound = server.local_addrs()?;
+        assert_eq!(bound.len(), 2);
+        assert!(bound[0].is_ipv4());
+        assert!(bound[1].is_ipv6());
+        assert_eq!(server.local_addr()?, bound[0]);
+
+        // Connect to the IPv6 address first, so accept has to find it on the second listener.
+        for addr in bound.iter().rev() {
+            let mut client = Client::connect(addr)?;
+            let (_, server_client) = server.accept()?;
+            let mut server_client = server_client.lock().unwrap();
+            assert_eq!(server_client.local_addr()?, *addr);
+            test_send_recv(&mut client, &mut server_client, addr.to_string());
+        }
+        assert_eq!(server.client_count(), 2);
+
+        // Polling with a timeout works across every listener too.
+        assert!(server.accept_timeout(Duration::from_millis(20))?.is_none());
+        let _client = Client::connect(bound[1])?;
+        assert!(server.accept_timeout(Duration::from_secs(5))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_with_config() -> Result<()> {
+        let config = SocketConfig::new()
+            .nodelay(true)
+            .read_timeout(Duration::from_secs(7));
+        let nodelay = |client: &ArcMutex<Client>| {
+            let client = client.lock().unwrap();
+            let socket = client.socket().lock().unwrap();
+            let tcp = socket.tcp().unwrap();
+            tcp.nodelay().unwrap() && tcp.read_timeout().unwrap() == Some(Duration::from_secs(7))
+        };
+
+        let mut server = make_server();
+        let addr = server.local_addr()?;
+        let _client = Client::connect(addr)?;
+        let (_, plain) = server.accept()?;
+        assert!(!nodelay(&plain));
+        let _client = Client::connect(addr)?;
+        let (_, configured) = server.accept_with_config(&config)?;
+        assert!(nodelay(&configured));
+
+        let _clients = [Client::connect(addr)?, Client::connect(addr)?];
+        for (_, client) in server.accept_n_with_config(2, &config)? {
+            assert!(nodelay(&client));
+        }
+        let _client = Client::connect(addr)?;
+        let (_, client) = server.incoming_with_config(config).next().unwrap()?;
+        assert!(nodelay(&client));
+        assert_eq!(server.client_count(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve() -> Result<()> {
+        let mut server = make_server();
+        let addr = server.local_addr()?;
+        let handle = server.shutdown_handle();
+        let server_thread = std::thread::spawn(move || {
+            server.serve(|client| {
+                let mut client = client.lock().unwrap();
+                let message = client.recv_into::<String>().unwrap();
+                client.send(&message).unwrap();
+                let name = std::thread::current().name().map(str::to_owned);
+                client.send(&name).unwrap();
+            })
+        });
+
+        let mut client = Client::connect(addr)?;
+        client.send(&"Hello, world!".to_owned())?;
+        assert_eq!(client.recv_into::<String>()?, "Hello, world!");
+        assert_eq!(
+            client.recv_into::<Option<String>>()?,
+            Some(format!("lazuli-handler {}", client.local_addr()?))
+        );
+
+        handle.shutdown();
+        server_thread.join().unwrap()?;
+        Ok(())
+    }
+
     #[test]
     fn test_nonblocking_server() -> Result<()> {
         let mut server = Server::new((Ipv4Addr::LOCALHOST, 0))?
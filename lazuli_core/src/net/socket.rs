@@ -0,0 +1,540 @@
+//! Contains the Socket enum, which lets a Client send over a plain TcpStream, a TLS stream, or any other transport.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+#[cfg(feature = "tls")]
+use rustls::{
+    pki_types::ServerName, ClientConfig, ClientConnection, ServerConfig, ServerConnection,
+    StreamOwned,
+};
+
+/// A byte stream that a Client can send and receive packets over. (e.g. a Unix socket, or an in-memory pipe for testing)
+///
+/// Only Read and Write are required. The other methods are used for things like timeouts and listening,
+/// and return an `Unsupported` error by default. Listening needs `set_nonblocking`, because the listener thread
+/// can't hold the transport while it waits for data.
+pub trait Transport: Read + Write + Send {
+    /// Sets whether reads return a `WouldBlock` error instead of waiting for data.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let _ = nonblocking;
+        Err(unsupported("set_nonblocking"))
+    }
+
+    /// Gets the read timeout. Returns None by default, meaning reads wait forever.
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Sets how long reads wait for data before returning a `TimedOut` or `WouldBlock` error.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Err(unsupported("set_read_timeout"))
+    }
+
+    /// Gets the address of the peer.
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(unsupported("peer_addr"))
+    }
+
+    /// Gets the local address.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(unsupported("local_addr"))
+    }
+
+    /// Shuts down the read half, write half, or both halves of the transport. Does nothing by default.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let _ = how;
+        Ok(())
+    }
+}
+
+fn unsupported(method: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("The transport does not support {}", method),
+    )
+}
+
+impl Transport for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+/// Unix sockets don't have a `std::net::SocketAddr`, so `peer_addr` and `local_addr` are unsupported.
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        std::os::unix::net::UnixStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, how)
+    }
+}
+
+impl fmt::Debug for dyn Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Transport")
+    }
+}
+
+/// The connection a Client sends and receives packets over.
+///
+/// Packets are framed the same way no matter what the connection is. Everything that is about the connection itself
+/// (addresses, timeouts, blocking mode) goes to the underlying [`Transport`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Socket {
+    /// A plain TCP connection.
+    Tcp(TcpStream),
+    /// The client side of a TLS connection.
+    #[cfg(feature = "tls")]
+    TlsClient {
+        stream: Box<StreamOwned<ClientConnection, TcpStream>>,
+        /// Kept so the connection can be remade by `reconnect`.
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    },
+    /// The server side of a TLS connection.
+    #[cfg(feature = "tls")]
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+    /// Any other transport.
+    Custom(Box<dyn Transport>),
+}
+
+impl Socket {
+    /// Wraps a connected TcpStream in TLS as a client, and completes the TLS handshake.
+    ///
+    /// server_name is the name the server's certificate is checked against.
+    #[cfg(feature = "tls")]
+    pub fn tls_client(
+        mut stream: TcpStream,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<Socket> {
+        let mut conn = ClientConnection::new(config.clone(), server_name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // Finish the handshake now, so a bad certificate is reported here instead of by the first send or receive.
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        Ok(Socket::TlsClient {
+            stream: Box::new(StreamOwned::new(conn, stream)),
+            server_name,
+            config,
+        })
+    }
+
+    /// Wraps an accepted TcpStream in TLS as a server, and completes the TLS handshake.
+    #[cfg(feature = "tls")]
+    pub fn tls_server(mut stream: TcpStream, config: Arc<ServerConfig>) -> io::Result<Socket> {
+        let mut conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        Ok(Socket::TlsServer(Box::new(StreamOwned::new(conn, stream))))
+    }
+
+    /// Gets the underlying TcpStream. Returns None for a custom transport.
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Socket::Tcp(stream) => Some(stream),
+            #[cfg(feature = "tls")]
+            Socket::TlsClient { stream, .. } => Some(stream.get_ref()),
+            #[cfg(feature = "tls")]
+            Socket::TlsServer(stream) => Some(stream.get_ref()),
+            Socket::Custom(_) => None,
+        }
+    }
+
+    /// Gets the transport that the connection is made over. For TLS, this is the TcpStream under it.
+    fn transport(&self) -> &dyn Transport {
+        match self {
+            Socket::Custom(transport) => &**transport,
+            // Every other variant is over TCP.
+            _ => self.tcp().unwrap(),
+        }
+    }
+
+    /// Connects to addr with the same kind of connection as this one.
+    ///
+    /// The server side of a TLS connection can't be remade, so this returns an `Unsupported` error for it.
+    pub(crate) fn reconnect(&self, addr: SocketAddr) -> io::Result<Socket> {
+        match self {
+            Socket::Tcp(_) => Ok(Socket::Tcp(TcpStream::connect(addr)?)),
+            #[cfg(feature = "tls")]
+            Socket::TlsClient {
+                server_name,
+                config,
+                ..
+            } => Socket::tls_client(
+                TcpStream::connect(addr)?,
+                server_name.clone(),
+                config.clone(),
+            ),
+            #[cfg(feature = "tls")]
+            Socket::TlsServer(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The server side of a TLS connection can't reconnect",
+            )),
+            Socket::Custom(_) => Err(unsupported("reconnecting")),
+        }
+    }
+
+    /// Shuts down the read half, write half, or both halves of the connection.
+    ///
+    /// For TLS, shutting down the write half sends a close_notify first, so the peer sees a clean EOF.
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        if how != Shutdown::Read {
+            match self {
+                Socket::Tcp(_) | Socket::Custom(_) => {}
+                Socket::TlsClient { stream, .. } => {
+                    stream.conn.send_close_notify();
+                    stream.flush()?;
+                }
+                Socket::TlsServer(stream) => {
+                    stream.conn.send_close_notify();
+                    stream.flush()?;
+                }
+            }
+        }
+        self.transport().shutdown(how)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.transport().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport().local_addr()
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.transport().set_nonblocking(nonblocking)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.transport().read_timeout()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.transport().set_read_timeout(timeout)
+    }
+}
+
+impl From<TcpStream> for Socket {
+    fn from(stream: TcpStream) -> Self {
+        Socket::Tcp(stream)
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Socket::TlsClient { stream, .. } => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Socket::TlsServer(stream) => stream.read(buf),
+            Socket::Custom(transport) => transport.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Socket::TlsClient { stream, .. } => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Socket::TlsServer(stream) => stream.write(buf),
+            Socket::Custom(transport) => transport.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Socket::TlsClient { stream, .. } => stream.flush(),
+            #[cfg(feature = "tls")]
+            Socket::TlsServer(stream) => stream.flush(),
+            Socket::Custom(transport) => transport.flush(),
+        }
+    }
+}
+
+/// Writes to a socket that may be non-blocking, waiting out `WouldBlock` instead of returning it.
+///
+/// The listener makes the socket non-blocking, so a plain `write_all` can fail with `WouldBlock` after writing part of a packet,
+/// and the peer would read whatever is sent next as the rest of it. Hold the socket lock for the whole packet,
+/// so nothing else is written in the middle of it.
+pub(crate) struct BlockingWriter<'a>(pub(crate) &'a mut Socket);
+
+impl Write for BlockingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.0.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::yield_now(),
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            match self.0.flush() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::yield_now(),
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        io::{self, Read, Write},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Condvar, Mutex,
+        },
+        time::Duration,
+    };
+
+    use super::Transport;
+    use crate::{Client, SocketConfig};
+
+    /// One direction of an in-memory pipe.
+    #[derive(Default)]
+    struct Pipe {
+        data: Mutex<VecDeque<u8>>,
+        available: Condvar,
+    }
+
+    /// One end of an in-memory duplex stream. No real sockets are involved.
+    struct MemoryStream {
+        read: Arc<Pipe>,
+        write: Arc<Pipe>,
+        nonblocking: AtomicBool,
+    }
+
+    fn duplex() -> (MemoryStream, MemoryStream) {
+        let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let end = |read: &Arc<Pipe>, write: &Arc<Pipe>| MemoryStream {
+            read: read.clone(),
+            write: write.clone(),
+            nonblocking: AtomicBool::new(false),
+        };
+        (end(&a, &b), end(&b, &a))
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut data = self.read.data.lock().unwrap();
+            while data.is_empty() {
+                if self.nonblocking.load(Ordering::Acquire) {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                data = self.read.available.wait(data).unwrap();
+            }
+            let n = buf.len().min(data.len());
+            for (byte, read) in buf.iter_mut().zip(data.drain(..n)) {
+                *byte = read;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write.data.lock().unwrap().extend(buf);
+            self.write.available.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MemoryStream {
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.nonblocking.store(nonblocking, Ordering::Release);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_memory_transport() {
+        let (a, b) = duplex();
+        let mut client = Client::from_transport(a);
+        let mut server = Client::from_transport(b);
+
+        let mut strings = server.stream::<String>();
+        client.send(&"Hello, world!".to_owned()).unwrap();
+        client.send_compressed(&"Compressed".to_owned()).unwrap();
+        server.recv().unwrap();
+        assert!(server.try_recv().unwrap());
+        assert!(!server.try_recv().unwrap());
+        assert_eq!(strings.drain(), vec!["Hello, world!", "Compressed"]);
+
+        let mut numbers = client.stream::<Vec<u32>>();
+        client.listen().unwrap();
+        server.send(&vec![1u32, 2, 3]).unwrap();
+        assert_eq!(
+            numbers.get_timeout(Duration::from_secs(5)).unwrap(),
+            vec![1, 2, 3]
+        );
+        client.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn test_memory_transport_unsupported() {
+        let (a, _b) = duplex();
+        let mut client = Client::from_transport(a);
+        assert_eq!(
+            client.peer_addr().unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        assert!(!client.is_connected());
+        let err = client.recv_timeout(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            client.reconnect().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        let err = client
+            .with_config(&SocketConfig::new().nodelay(true))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut client = Client::from_transport(a);
+        let mut server = Client::from_transport(b);
+        let mut stream = server.stream::<String>();
+        client.send(&"Hello, world!".to_owned()).unwrap();
+        server.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(stream.get().unwrap(), "Hello, world!");
+    }
+
+    #[cfg(feature = "tls")]
+    mod tls {
+        use std::{
+            net::{Ipv4Addr, TcpListener},
+            sync::Arc,
+            time::Duration,
+        };
+
+        use rustls::{
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+            ClientConfig, RootCertStore, ServerConfig,
+        };
+
+        use crate::{Client, Server};
+
+        /// A self-signed certificate for localhost, and its key.
+        const CERT: &[u8] = include_bytes!("../../test_data/localhost.cert.der");
+        const KEY: &[u8] = include_bytes!("../../test_data/localhost.key.der");
+
+        fn configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+            let cert = CertificateDer::from(CERT.to_vec());
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(KEY.to_vec()));
+            let mut roots = RootCertStore::empty();
+            roots.add(cert.clone()).unwrap();
+            let client = ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let server = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert], key)
+                .unwrap();
+            (Arc::new(client), Arc::new(server))
+        }
+
+        #[test]
+        fn test_tls_send_recv() {
+            let (client_config, server_config) = configs();
+            let mut server = Server::new((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let addr = server.local_addr().unwrap();
+            let accept = std::thread::spawn(move || server.accept_tls(server_config).unwrap().1);
+            let mut client = Client::connect_tls(addr, "localhost", client_config).unwrap();
+            let server_client = accept.join().unwrap();
+            let mut server_client = server_client.lock().unwrap();
+
+            let mut stream = server_client.stream::<String>();
+            client.send(&"Hello over TLS".to_owned()).unwrap();
+            server_client.recv().unwrap();
+            assert_eq!(stream.get().unwrap(), "Hello over TLS");
+
+            let mut stream = client.stream::<Vec<u32>>();
+            server_client.send(&vec![1u32, 2, 3]).unwrap();
+            client.listen().unwrap();
+            assert_eq!(
+                stream.get_timeout(Duration::from_secs(5)).unwrap(),
+                vec![1, 2, 3]
+            );
+        }
+
+        #[test]
+        fn test_tls_untrusted_certificate() {
+            let (_, server_config) = configs();
+            // A client that trusts nothing, so the server's self-signed certificate is rejected.
+            let client_config = ClientConfig::builder()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth();
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let accept = std::thread::spawn(move || {
+                let stream = listener.accept().unwrap().0;
+                super::super::Socket::tls_server(stream, server_config).is_err()
+            });
+            assert!(Client::connect_tls(addr, "localhost", Arc::new(client_config)).is_err());
+            assert!(accept.join().unwrap());
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Contains the counters behind `Client::stats`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How much a client has sent and received. Returned by [`Client::stats`](crate::Client::stats).
+///
+/// Byte counts include the header of every packet, and are the sizes on the wire. (e.g. compressed payloads are counted compressed)
+/// Bytes sent with `send_raw` or received with `recv_raw` are not packets, so they are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    /// Bytes of every packet sent, including their headers. Packets still in the write buffer are counted.
+    pub bytes_sent: u64,
+    /// Bytes of every packet received, including their headers.
+    pub bytes_received: u64,
+    /// Packets sent. Packets still in the write buffer are counted.
+    pub packets_sent: u64,
+    /// Packets received, whether they were delivered to a stream or handler, kept as unhandled, or dropped.
+    pub packets_received: u64,
+    /// Packets the listener received, but dropped because they couldn't be delivered.
+    /// (e.g. a payload that couldn't be decoded, or a full bounded stream) These are also counted in packets_received.
+    pub packets_dropped: u64,
+}
+
+/// The counters for a client. These are shared with the listener, which receives on another thread.
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    packets_dropped: AtomicU64,
+}
+
+impl StatsCounters {
+    /// Counts a packet of len bytes that was sent, including its header.
+    pub(crate) fn sent(&self, len: u64) {
+        self.bytes_sent.fetch_add(len, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a packet of len bytes that was received, including its header.
+    pub(crate) fn received(&self, len: u64) {
+        self.bytes_received.fetch_add(len, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a packet that the listener received, but couldn't deliver. (e.g. its payload couldn't be decoded)
+    pub(crate) fn dropped(&self) {
+        self.packets_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes a snapshot of the counters.
+    pub(crate) fn snapshot(&self) -> ClientStats {
+        ClientStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            packets_dropped: self.packets_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! A client for sending and receiving packets over UDP.
+//!
+//! Every packet is sent as a single datagram, containing the header followed by the payload.
+//! Because datagrams are never split, the partial read issues that the TCP client has to deal with don't apply here.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use log::trace;
+
+use crate::{
+    hash_type_id, stream::Stream, LazuliError, PacketHeader, Result, Sendable,
+    DEFAULT_MAX_PAYLOAD_SIZE, HEADER_SIZE,
+};
+
+use super::{compression, connector::StreamConnector, input, StreamCollection};
+
+/// The largest payload that fits in a single UDP datagram after the header.
+pub const MAX_UDP_PAYLOAD: usize = 65507 - HEADER_SIZE;
+
+/// A client for sending and receiving data over UDP.
+///
+/// Unlike [`Client`](crate::Client), a UdpClient is not connected to a single peer.
+/// Data is sent to an address, and received from any address.
+pub struct UdpClient {
+    socket: UdpSocket,
+    streams: StreamCollection,
+}
+
+impl UdpClient {
+    /// Creates a UdpClient bound to the address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Self::from_socket(UdpSocket::bind(addr)?))
+    }
+
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        UdpClient {
+            socket,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Sends data to the address as a single datagram.
+    ///
+    /// Returns an `InvalidInput` error if the payload is larger than [`MAX_UDP_PAYLOAD`].
+    pub fn send<T, A>(&self, data: &T, addr: A) -> Result<()>
+    where
+        T: Sendable + 'static,
+        A: ToSocketAddrs,
+    {
+        let bytes = data.send();
+        if bytes.len() > MAX_UDP_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Payload is too large for a datagram ({} > {})",
+                    bytes.len(),
+                    MAX_UDP_PAYLOAD
+                ),
+            )
+            .into());
+        }
+        let mut header = data.header();
+        header.calculate_checksum(&bytes);
+        let mut datagram = header.to_bytes().to_vec();
+        datagram.extend(bytes);
+        trace!("Sending datagram: {:?}", datagram);
+        self.socket.send_to(&datagram, addr)?;
+        Ok(())
+    }
+
+    /// Receives a single datagram, and stores its data in the stream for its type.
+    ///
+    /// Returns the address the datagram came from.
+    /// Returns a `BadHeader` error if the datagram does not contain exactly one packet,
+    /// and an `UnknownStream` error if there is no stream for its type.
+    pub fn recv(&mut self) -> Result<SocketAddr> {
+        let mut buf = vec![0; HEADER_SIZE + MAX_UDP_PAYLOAD];
+        let (len, addr) = self.socket.recv_from(&mut buf)?;
+        let datagram = &buf[..len];
+        trace!("Received datagram from {}: {:?}", addr, datagram);
+        if len < HEADER_SIZE {
+            return Err(LazuliError::BadHeader(format!(
+                "Datagram is too short to contain a header ({} bytes)",
+                len
+            )));
+        }
+        let mut header = PacketHeader::try_parse(&datagram[..HEADER_SIZE])?;
+        let data = &datagram[HEADER_SIZE..];
+        if header.payload_size as usize != data.len() {
+            return Err(LazuliError::BadHeader(format!(
+                "Datagram payload size does not match the header (Expected: {}, Got: {})",
+                header.payload_size,
+                data.len()
+            )));
+        }
+        input::verify_checksum(&header, data)?;
+        // A datagram can't claim more than 64 KiB, but it can inflate to much more.
+        let data = compression::decompress(&mut header, data.to_vec(), DEFAULT_MAX_PAYLOAD_SIZE)?;
+        let Some(info) = self.streams.get_mut(&header.type_id()) else {
+            return Err(LazuliError::UnknownStream(header.type_id()));
+        };
+        info.push(&data, header)?;
+        Ok(addr)
+    }
+
+    /// Creates a stream for the type T.
+    pub fn stream<T>(&mut self) -> Stream<T>
+    where
+        T: Sendable + Send + 'static,
+    {
+        let stream: Stream<T> = Stream::new();
+        self.streams
+            .insert(hash_type_id::<T>(), StreamConnector::new(&stream));
+        stream
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<std::time::Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+}
+
+impl Debug for UdpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpClient")
+            .field("socket", &self.socket)
+            .field("streams", &self.streams.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    fn make_pair() -> (UdpClient, UdpClient) {
+        let a = UdpClient::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let b = UdpClient::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        b.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn test_udp_round_trip() -> Result<()> {
+        let (sender, mut receiver) = make_pair();
+        let mut u32_stream = receiver.stream::<u32>();
+        let mut string_stream = receiver.stream::<String>();
+        let addr = receiver.local_addr()?;
+
+        sender.send(&42u32, addr)?;
+        assert_eq!(receiver.recv()?, sender.local_addr()?);
+        assert_eq!(u32_stream.get(), Some(42));
+
+        sender.send(&"Hello, world!".to_owned(), addr)?;
+        receiver.recv()?;
+        assert_eq!(string_stream.get(), Some("Hello, world!".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_udp_rejects_bad_length() -> Result<()> {
+        let (_, mut receiver) = make_pair();
+        let _stream = receiver.stream::<u32>();
+        let mut header = 42u32.header();
+        header.calculate_checksum(&42u32.send());
+        let mut datagram = header.to_bytes().to_vec();
+        datagram.extend(42u32.send());
+        datagram.push(0);
+        let raw = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        raw.send_to(&datagram, receiver.local_addr()?)?;
+        let err = receiver.recv().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        raw.send_to(&datagram[..10], receiver.local_addr()?)?;
+        let err = receiver.recv().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
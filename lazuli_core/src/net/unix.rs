@@ -0,0 +1,148 @@
+//! Contains the UnixServer struct, which accepts clients over a Unix domain socket.
+
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{ArcMutex, Client, Result};
+
+use super::server::ClientId;
+
+/// A server that accepts clients over a Unix domain socket, instead of TCP.
+///
+/// The clients are regular [`Client`]s, so everything that works over TCP works here. (except things that need a
+/// `SocketAddr`, like `peer_addr` and `reconnect`)
+/// The socket file is removed when the server is dropped.
+pub struct UnixServer {
+    listener: UnixListener,
+    path: PathBuf,
+    streams: Vec<(ClientId, ArcMutex<Client>)>,
+    next_id: u64,
+}
+
+impl UnixServer {
+    /// Creates a server listening on a new socket file at path.
+    ///
+    /// Returns an `AddrInUse` error if a file already exists at path.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        Ok(UnixServer {
+            listener,
+            path,
+            streams: vec![],
+            next_id: 0,
+        })
+    }
+
+    /// Accepts a connection. Returns the id assigned to the client along with the client.
+    pub fn accept(&mut self) -> Result<(ClientId, ArcMutex<Client>)> {
+        let stream = self.listener.accept()?.0;
+        Ok(self.add_client(stream))
+    }
+
+    /// Accepts n connections.
+    pub fn accept_n(&mut self, n: usize) -> Result<Vec<(ClientId, ArcMutex<Client>)>> {
+        let mut streams = vec![];
+        for _ in 0..n {
+            streams.push(self.accept()?);
+        }
+        Ok(streams)
+    }
+
+    /// Wraps a newly accepted socket in a client and assigns it the next id.
+    fn add_client(&mut self, stream: UnixStream) -> (ClientId, ArcMutex<Client>) {
+        let id = ClientId(self.next_id);
+        self.next_id += 1;
+        let stream = Arc::new(Mutex::new(Client::from_transport(stream)));
+        self.streams.push((id, stream.clone()));
+        (id, stream)
+    }
+
+    /// Gets the number of clients the server is holding.
+    pub fn client_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Gets the client with the given id, if the server still holds it.
+    pub fn get_client(&self, id: ClientId) -> Option<ArcMutex<Client>> {
+        self.streams
+            .iter()
+            .find(|(client_id, _)| *client_id == id)
+            .map(|(_, client)| client.clone())
+    }
+
+    /// Gets the path of the socket file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        // Unix sockets leave their file behind, which would make binding to the same path fail next time.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use crate::Client;
+
+    use super::UnixServer;
+
+    /// Gets a socket path in the temp directory that no other test is using.
+    fn socket_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "lazuli-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    #[test]
+    fn test_unix_send_recv() {
+        let path = socket_path();
+        let mut server = UnixServer::new(&path).unwrap();
+        let mut client = Client::connect_unix(&path).unwrap();
+        let (id, server_client) = server.accept().unwrap();
+        assert_eq!(server.client_count(), 1);
+        assert!(server.get_client(id).is_some());
+        let mut server_client = server_client.lock().unwrap();
+
+        let mut stream = server_client.stream::<String>();
+        client.send(&"Hello, world!".to_owned()).unwrap();
+        server_client.recv().unwrap();
+        assert_eq!(stream.get().unwrap(), "Hello, world!");
+
+        // The listener relies on non-blocking reads, the same as it does over TCP.
+        let mut stream = client.stream::<String>();
+        client.listen().unwrap();
+        server_client.send(&"Hello, client!".to_owned()).unwrap();
+        assert_eq!(
+            stream.get_timeout(Duration::from_secs(5)).unwrap(),
+            "Hello, client!"
+        );
+    }
+
+    #[test]
+    fn test_socket_file_removed() {
+        let path = socket_path();
+        let server = UnixServer::new(&path).unwrap();
+        assert!(path.exists());
+        assert!(UnixServer::new(&path).is_err());
+        drop(server);
+        assert!(!path.exists());
+        // The path can be reused once the server is gone.
+        UnixServer::new(&path).unwrap();
+    }
+}
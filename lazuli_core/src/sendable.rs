@@ -11,21 +11,40 @@
 //!
 //!
 
-use core::slice;
 use std::{
-    io::{self, Read},
+    any::Any,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
+    hash::Hash,
+    io::{self, Read, Write},
+    marker::PhantomData,
     mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::{
+        NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroU128, NonZeroU16,
+        NonZeroU32, NonZeroU64, NonZeroU8,
+    },
+    ops::{Range, RangeInclusive},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use log::trace;
 
-use crate::header::PacketHeader;
-use crate::Result;
+use crate::header::{PacketHeader, UnknownType};
+use crate::{hash_type_id, LazuliError, Result};
 
 /// A trait for types that can be sent over the network.
-///
-/// Sendable has the Debug bound because it is internally useful, and can be helpful for debugging.
-pub trait Sendable: Sized + std::fmt::Debug {
+pub trait Sendable: Sized {
+    /// Whether `size` returns the same value for every value of the type. (e.g. true for u32, false for String)
+    ///
+    /// This is false by default, which is always correct. Collections use it to avoid calling `size` on every item.
+    const SIZE_CONST: bool = false;
+
     /// Returns the header of the packet.
     fn header(&self) -> PacketHeader<Self> {
         unsafe { PacketHeader::new(self.size()) }
@@ -41,41 +60,187 @@ pub trait Sendable: Sized + std::fmt::Debug {
     /// Converts the type to a Vec<u8> that can be sent over the network.
     fn send(&self) -> Vec<u8>;
 
+    /// Appends the bytes that `send` would return to buf.
+    ///
+    /// This lets a buffer be reused between sends, and lets collections write their items without an allocation per item.
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.send());
+    }
+
+    /// Writes the bytes that `send` would return to writer, and returns how many bytes were written.
+    ///
+    /// Large types override this to write their data in pieces, so the whole payload never has to be in memory at once.
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        let data = self.send();
+        writer.write_all(&data)?;
+        Ok(data.len() as u64)
+    }
+
     /// Converts an incoming stream of bytes to the type.
     fn recv(data: &mut dyn Read) -> Result<Self>;
 }
 
-/// Converts the type to a function that can be used to convert incoming data to the type.
-/// This function hides the type of the data, allowing for the conversion function to be used in a generic context.
+/// How many bytes `send_chunked` collects before writing them, for types that are made of many items.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes a length prefix followed by bytes, for types that are sent like a `Vec<u8>` or `String`.
+fn send_bytes_chunked(bytes: &[u8], writer: &mut dyn Write) -> Result<u64> {
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(bytes.len() as u64 + 4)
+}
+
+/// Writes a length prefix followed by every item, collecting about `CHUNK_SIZE` bytes per write.
+fn send_items_chunked<'a, T: Sendable + 'a>(
+    len: usize,
+    items: impl Iterator<Item = &'a T>,
+    writer: &mut dyn Write,
+) -> Result<u64> {
+    let mut buf = Vec::with_capacity(CHUNK_SIZE);
+    (len as u32).send_into(&mut buf);
+    let mut written = 0;
+    for item in items {
+        item.send_into(&mut buf);
+        if buf.len() >= CHUNK_SIZE {
+            writer.write_all(&buf)?;
+            written += buf.len() as u64;
+            buf.clear();
+        }
+    }
+    writer.write_all(&buf)?;
+    Ok(written + buf.len() as u64)
+}
+
+/// The default maximum length prefix that `recv` will accept. (16 Mi)
 ///
-/// This function is used internally by `StreamConnector`.
-pub(crate) fn as_conversion_fn<T: Sendable>() -> fn(&mut dyn Read) -> Result<Box<[u8]>> {
-    |data| {
-        let conversion = Box::new(T::recv(data)?);
-        trace!("Converted to bytes: {:?}", conversion);
-        let as_slice_bytes = unsafe {
-            // We use a slice to get the bytes of the type. This is safe because we are using the size of the type to get the slice.
-            slice::from_raw_parts(
-                Box::leak(conversion) as *mut T as *mut u8,
-                mem::size_of::<T>(),
-            )
-        };
-        Ok(as_slice_bytes.into())
+/// For collections, this is the maximum number of elements. For strings, this is the maximum number of bytes.
+pub const DEFAULT_MAX_LENGTH: u32 = 16 * 1024 * 1024;
+
+static MAX_LENGTH: AtomicU32 = AtomicU32::new(DEFAULT_MAX_LENGTH);
+
+/// Sets the maximum length prefix that `recv` will accept.
+///
+/// Length prefixes come straight from the peer, so without a limit a peer could claim a huge length
+/// and make the receiver loop (and allocate) until it runs out of memory.
+///
+/// The limit is process-wide: it applies to every `recv` on every thread and every client, not just the caller's.
+/// To limit a single connection, use [`Client::set_max_payload_size`](crate::Client::set_max_payload_size) instead.
+pub fn set_max_length(max: u32) {
+    MAX_LENGTH.store(max, Ordering::Relaxed);
+}
+
+/// Gets the maximum length prefix that `recv` will accept.
+pub fn max_length() -> u32 {
+    MAX_LENGTH.load(Ordering::Relaxed)
+}
+
+/// Reads a length prefix, returning an `InvalidData` error if it is larger than `max_length()`.
+fn recv_length(data: &mut dyn Read) -> Result<u32> {
+    let length = u32::recv(data)?;
+    let max = max_length();
+    if length > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Length prefix {} exceeds the maximum of {}", length, max),
+        )
+        .into());
+    }
+    Ok(length)
+}
+
+/// A Sendable value whose type is only known at runtime, so values of different types can be sent from one collection.
+/// (e.g. a `Vec<Box<dyn SendableAny>>`)
+///
+/// This is implemented for every Sendable type, and is sent with `Client::send_any`.
+/// The receiving side turns the packets back into values with a [`TypeRegistry`].
+pub trait SendableAny: Any + Send {
+    /// Returns the header of the packet, like [`Sendable::header`].
+    fn header_any(&self) -> PacketHeader<UnknownType>;
+
+    /// Converts the value to bytes, like [`Sendable::send`].
+    fn send_any(&self) -> Vec<u8>;
+}
+
+impl<T: Sendable + Send + 'static> SendableAny for T {
+    fn header_any(&self) -> PacketHeader<UnknownType> {
+        self.header().into_unknown()
+    }
+
+    fn send_any(&self) -> Vec<u8> {
+        self.send()
+    }
+}
+
+/// A function that receives a registered type and returns it as a `Box<dyn Any>`.
+type RecvAnyFn = fn(&mut dyn Read) -> Result<Box<dyn Any + Send>>;
+
+/// Maps the type_id of packets to the function that receives them, for types that are only known at runtime.
+///
+/// This is what `Client::recv_any` uses. The returned values can be downcast back to their concrete types.
+#[derive(Default, Clone)]
+pub struct TypeRegistry {
+    types: HashMap<u64, (&'static str, RecvAnyFn)>,
+}
+
+impl TypeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers T, so packets of T can be received with [`TypeRegistry::recv`].
+    pub fn register<T: Sendable + Send + 'static>(&mut self) {
+        self.types.insert(
+            hash_type_id::<T>(),
+            (std::any::type_name::<T>(), |data| {
+                Ok(Box::new(T::recv(data)?) as Box<dyn Any + Send>)
+            }),
+        );
+    }
+
+    /// Returns true if a type with the given type_id is registered.
+    pub fn is_registered(&self, type_id: u64) -> bool {
+        self.types.contains_key(&type_id)
+    }
+
+    /// Receives a value of the type registered for type_id from data.
+    ///
+    /// Returns an `UnknownStream` error if no type is registered for type_id.
+    pub fn recv(&self, type_id: u64, data: &mut dyn Read) -> Result<Box<dyn Any + Send>> {
+        let (name, recv) = self
+            .types
+            .get(&type_id)
+            .ok_or(LazuliError::UnknownStream(type_id))?;
+        trace!("Receiving a {} from the type registry", name);
+        recv(data)
+    }
+}
+
+impl std::fmt::Debug for TypeRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.types.values().map(|(name, _)| name))
+            .finish()
     }
 }
 
 macro_rules! impl_sendable_number {
     ($t:ty) => {
         impl Sendable for $t {
+            const SIZE_CONST: bool = true;
+
             fn send(&self) -> Vec<u8> {
-                // Follow the standard of big-endian
-                <$t>::to_ne_bytes(*self).to_vec()
+                // Follow the standard of big-endian (network byte order)
+                <$t>::to_be_bytes(*self).to_vec()
+            }
+
+            fn send_into(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&<$t>::to_be_bytes(*self));
             }
 
             fn recv(data: &mut dyn Read,) -> Result<Self> {
                 let mut buffer = [0; std::mem::size_of::<$t>()];
                 data.read_exact(&mut buffer)?;
-                Ok(<$t>::from_ne_bytes(buffer))
+                Ok(<$t>::from_be_bytes(buffer))
             }
         }
     };
@@ -91,7 +256,42 @@ impl_sendable_number!(i8, i16, i32, i64, i128);
 
 impl_sendable_number!(f32, f64);
 
+macro_rules! impl_sendable_nonzero {
+    ($t:ty, $inner:ty) => {
+        impl Sendable for $t {
+            const SIZE_CONST: bool = true;
+
+            fn send(&self) -> Vec<u8> {
+                self.get().send()
+            }
+
+            fn send_into(&self, buf: &mut Vec<u8>) {
+                self.get().send_into(buf);
+            }
+
+            fn recv(data: &mut dyn Read) -> Result<Self> {
+                <$t>::new(<$inner>::recv(data)?).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        concat!("Received 0 for ", stringify!($t)),
+                    ).into()
+                })
+            }
+        }
+    };
+
+    ($($t:ty: $inner:ty),*) => {
+        $(impl_sendable_nonzero!($t, $inner);)*
+    };
+}
+
+impl_sendable_nonzero!(NonZeroU8: u8, NonZeroU16: u16, NonZeroU32: u32, NonZeroU64: u64, NonZeroU128: u128);
+
+impl_sendable_nonzero!(NonZeroI8: i8, NonZeroI16: i16, NonZeroI32: i32, NonZeroI64: i64, NonZeroI128: i128);
+
 impl Sendable for bool {
+    const SIZE_CONST: bool = true;
+
     fn send(&self) -> Vec<u8> {
         if *self {
             vec![1]
@@ -100,6 +300,10 @@ impl Sendable for bool {
         }
     }
 
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
     fn recv(data: &mut dyn Read) -> Result<Self> {
         let mut buffer = [0; 1];
         data.read_exact(&mut buffer)?;
@@ -107,6 +311,29 @@ impl Sendable for bool {
     }
 }
 
+impl Sendable for char {
+    const SIZE_CONST: bool = true;
+
+    fn send(&self) -> Vec<u8> {
+        u32::from(*self).send()
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        u32::from(*self).send_into(buf);
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let value = u32::recv(data)?;
+        char::from_u32(value).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid char (0x{:X} is not a unicode scalar value)", value),
+            )
+            .into()
+        })
+    }
+}
+
 impl<T> Sendable for Vec<T>
 where
     T: Sendable,
@@ -116,6 +343,10 @@ where
     }
 
     fn size(&self) -> u32 {
+        if T::SIZE_CONST {
+            // Every item is the same size, so only the first one needs to be checked.
+            return self.first().map_or(0, T::size) * self.len() as u32 + 4;
+        }
         let mut size = 0;
         for item in self {
             size += item.size();
@@ -124,6 +355,49 @@ where
     }
 
     fn send(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(self.size() as usize);
+        self.send_into(&mut data);
+        data
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).send_into(buf);
+        for item in self {
+            item.send_into(buf);
+        }
+    }
+
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        send_items_chunked(self.len(), self.iter(), writer)
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let mut vec = Vec::new();
+        let length = recv_length(data)?;
+        for _ in 0..length {
+            vec.push(T::recv(data)?);
+        }
+        Ok(vec)
+    }
+}
+
+impl<T> Sendable for VecDeque<T>
+where
+    T: Sendable,
+{
+    fn size(&self) -> u32 {
+        if T::SIZE_CONST {
+            return self.front().map_or(0, T::size) * self.len() as u32 + 4;
+        }
+        let mut size = 0;
+        for item in self {
+            size += item.size();
+        }
+        size + 4
+    }
+
+    fn send(&self) -> Vec<u8> {
+        // Sent front to back, exactly like a Vec.
         let mut data: Vec<u8> = Vec::new();
         data.extend((self.len() as u32).send());
         for item in self {
@@ -132,13 +406,126 @@ where
         data
     }
 
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        send_items_chunked(self.len(), self.iter(), writer)
+    }
+
     fn recv(data: &mut dyn Read) -> Result<Self> {
-        let mut vec = Vec::new();
-        let length = u32::recv(data)?;
+        let mut deque = VecDeque::new();
+        let length = recv_length(data)?;
         for _ in 0..length {
+            deque.push_back(T::recv(data)?);
+        }
+        Ok(deque)
+    }
+}
+
+impl<T, const N: usize> Sendable for [T; N]
+where
+    T: Sendable,
+{
+    const SIZE_CONST: bool = T::SIZE_CONST;
+
+    fn size(&self) -> u32 {
+        // The length is known at compile time, so there is no length prefix.
+        if T::SIZE_CONST {
+            return self.first().map_or(0, T::size) * N as u32;
+        }
+        let mut size = 0;
+        for item in self {
+            size += item.size();
+        }
+        size
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::new();
+        for item in self {
+            data.extend(item.send());
+        }
+        data
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let mut vec = Vec::with_capacity(N);
+        for _ in 0..N {
             vec.push(T::recv(data)?);
         }
-        Ok(vec)
+        // The vec always has exactly N items, so this can't fail.
+        Ok(vec
+            .try_into()
+            .unwrap_or_else(|_| unreachable!("Array length mismatch")))
+    }
+}
+
+impl<K, V> Sendable for HashMap<K, V>
+where
+    K: Sendable + Eq + Hash,
+    V: Sendable,
+{
+    fn size(&self) -> u32 {
+        let mut size = 0;
+        for (key, value) in self {
+            size += key.size() + value.size();
+        }
+        size + 4
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend((self.len() as u32).send());
+        for (key, value) in self {
+            data.extend(key.send());
+            data.extend(value.send());
+        }
+        data
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let mut map = HashMap::new();
+        let length = recv_length(data)?;
+        for _ in 0..length {
+            let key = K::recv(data)?;
+            let value = V::recv(data)?;
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+}
+
+impl<K, V> Sendable for BTreeMap<K, V>
+where
+    K: Sendable + Ord,
+    V: Sendable,
+{
+    fn size(&self) -> u32 {
+        let mut size = 0;
+        for (key, value) in self {
+            size += key.size() + value.size();
+        }
+        size + 4
+    }
+
+    fn send(&self) -> Vec<u8> {
+        // BTreeMap iterates in key order, so the output is deterministic for the same contents.
+        let mut data: Vec<u8> = Vec::new();
+        data.extend((self.len() as u32).send());
+        for (key, value) in self {
+            data.extend(key.send());
+            data.extend(value.send());
+        }
+        data
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let mut map = BTreeMap::new();
+        let length = recv_length(data)?;
+        for _ in 0..length {
+            let key = K::recv(data)?;
+            let value = V::recv(data)?;
+            map.insert(key, value);
+        }
+        Ok(map)
     }
 }
 
@@ -150,6 +537,239 @@ impl Sendable for String {
         self.len() as u32 + 4 // Add 4 bytes for the length of the string.
     }
 
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.size() as usize);
+        self.send_into(&mut data);
+        data
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).send_into(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        send_bytes_chunked(self.as_bytes(), writer)
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let length = recv_length(data)?;
+        // Read incrementally instead of allocating the full length up front,
+        // so a peer that claims a long string but never sends it can't make us allocate it.
+        let mut buffer = Vec::new();
+        data.take(length as u64).read_to_end(&mut buffer)?;
+        if buffer.len() != length as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "String was shorter than its length prefix",
+            )
+            .into());
+        }
+        let string = String::from_utf8(buffer)?;
+        trace!("Received string: {}", string);
+        Ok(string)
+    }
+}
+
+/// Options are sent as a bool tag, followed by the value if it is present.
+impl<T> Sendable for Option<T>
+where
+    T: Sendable,
+{
+    /// One byte for the tag, plus the full size of the value (including any length prefix it has).
+    fn size(&self) -> u32 {
+        match self {
+            Some(value) => value.size() + 1,
+            None => 1,
+        }
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.size() as usize);
+        self.send_into(&mut data);
+        data
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                true.send_into(buf);
+                value.send_into(buf);
+            }
+            None => false.send_into(buf),
+        }
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let is_present = bool::recv(data)?;
+        if !is_present {
+            Ok(None)
+        } else {
+            Ok(Some(T::recv(data)?))
+        }
+    }
+}
+
+impl<T, E> Sendable for std::result::Result<T, E>
+where
+    T: Sendable,
+    E: Sendable,
+{
+    fn size(&self) -> u32 {
+        match self {
+            Ok(value) => value.size() + 1,
+            Err(err) => err.size() + 1,
+        }
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self {
+            Ok(value) => {
+                data.extend(0u8.send());
+                data.extend(value.send());
+            }
+            Err(err) => {
+                data.extend(1u8.send());
+                data.extend(err.send());
+            }
+        }
+        data
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        match u8::recv(data)? {
+            0 => Ok(Ok(T::recv(data)?)),
+            1 => Ok(Err(E::recv(data)?)),
+            tag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid Result discriminant: {}", tag),
+            )
+            .into()),
+        }
+    }
+}
+
+impl<T> Sendable for Box<T>
+where
+    T: Sendable,
+{
+    const SIZE_CONST: bool = T::SIZE_CONST;
+
+    fn header(&self) -> PacketHeader<Self> {
+        unsafe { PacketHeader::new(self.size()) }
+    }
+
+    fn size(&self) -> u32 {
+        T::size(&**self)
+    }
+
+    fn send(&self) -> Vec<u8> {
+        T::send(&**self)
+    }
+
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        T::send_chunked(&**self, writer)
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        Ok(Box::new(T::recv(data)?))
+    }
+}
+
+/// References are send-only. They are sent exactly like the value they point to, including its type id,
+/// so the peer receives a T. (e.g. with `stream::<T>()`)
+///
+/// Like every other type, a reference needs to be `'static` to get a header, so only `&'static T` can be passed to
+/// `Client::send`. Other references are still useful in generic code that calls `send` or `size`.
+///
+/// recv always returns an `Unsupported` error, because there is nothing for the received value to borrow from.
+impl<T> Sendable for &T
+where
+    T: Sendable,
+{
+    const SIZE_CONST: bool = T::SIZE_CONST;
+
+    fn header(&self) -> PacketHeader<Self> {
+        T::header(self).retype()
+    }
+
+    fn size(&self) -> u32 {
+        T::size(self)
+    }
+
+    fn send(&self) -> Vec<u8> {
+        T::send(self)
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        T::send_into(self, buf)
+    }
+
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        T::send_chunked(self, writer)
+    }
+
+    fn recv(_data: &mut dyn Read) -> Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "A reference can't be received. Receive the type it points to instead.",
+        )
+        .into())
+    }
+}
+
+/// Implements Sendable for a shared pointer by sending the value it points to.
+///
+/// recv makes a new pointer around the received value, so sharing does not survive the wire.
+macro_rules! impl_sendable_shared {
+    ($($ptr:ident),*) => {
+        $(
+            impl<T> Sendable for $ptr<T>
+            where
+                T: Sendable,
+            {
+                const SIZE_CONST: bool = T::SIZE_CONST;
+
+                fn header(&self) -> PacketHeader<Self> {
+                    unsafe { PacketHeader::new(self.size()) }
+                }
+
+                fn size(&self) -> u32 {
+                    T::size(&**self)
+                }
+
+                fn send(&self) -> Vec<u8> {
+                    T::send(&**self)
+                }
+
+                fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+                    T::send_chunked(&**self, writer)
+                }
+
+                fn recv(data: &mut dyn Read) -> Result<Self> {
+                    Ok($ptr::new(T::recv(data)?))
+                }
+            }
+        )*
+    };
+}
+
+// Rc is not Send, so there can be no stream for it (streams need Send). It can still be sent, and received with recv_into.
+impl_sendable_shared!(Arc, Rc);
+
+// Cow is restricted to 'static because PacketHeader (and streams) need T: 'static.
+// recv always returns Cow::Owned, since there is nothing to borrow from.
+
+impl Sendable for Cow<'static, str> {
+    fn header(&self) -> PacketHeader<Self> {
+        unsafe { PacketHeader::new(self.size()) }
+    }
+
+    fn size(&self) -> u32 {
+        self.len() as u32 + 4 // Same as String.
+    }
+
     fn send(&self) -> Vec<u8> {
         let mut data = Vec::new();
         data.extend((self.len() as u32).send());
@@ -157,88 +777,417 @@ impl Sendable for String {
         data
     }
 
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        send_bytes_chunked(self.as_bytes(), writer)
+    }
+
     fn recv(data: &mut dyn Read) -> Result<Self> {
-        let length = u32::recv(data)?;
-        let mut buffer = vec![0; length as usize];
-        data.read_exact(&mut buffer)?;
-        let string = String::from_utf8(buffer);
-        match string {
-            Ok(s) => {
-                trace!("Received string: {}", s);
-                Ok(s)
+        Ok(Cow::Owned(String::recv(data)?))
+    }
+}
+
+impl Sendable for Cow<'static, [u8]> {
+    fn header(&self) -> PacketHeader<Self> {
+        unsafe { PacketHeader::new(self.size()) }
+    }
+
+    fn size(&self) -> u32 {
+        self.len() as u32 + 4 // Same as Vec<u8>.
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((self.len() as u32).send());
+        data.extend_from_slice(self);
+        data
+    }
+
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        send_bytes_chunked(self, writer)
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        Ok(Cow::Owned(Vec::<u8>::recv(data)?))
+    }
+}
+
+/// A borrowed byte slice that is sent exactly like a `Vec<u8>`, without copying it into a Vec first.
+///
+/// Because it borrows its data, a SendableBytes can't be received. Receive a `Vec<u8>` instead.
+/// Packets need a `'static` type to get their type id from, so use [`Client::send_bytes`](crate::Client::send_bytes)
+/// to send a slice that isn't `'static`. It sends the bytes with the type id of `Vec<u8>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendableBytes<'a>(pub &'a [u8]);
+
+impl Sendable for SendableBytes<'_> {
+    fn size(&self) -> u32 {
+        self.0.len() as u32 + 4 // Same as Vec<u8>.
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.size() as usize);
+        self.send_into(&mut data);
+        data
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        (self.0.len() as u32).send_into(buf);
+        buf.extend_from_slice(self.0);
+    }
+
+    fn send_chunked(&self, writer: &mut dyn Write) -> Result<u64> {
+        send_bytes_chunked(self.0, writer)
+    }
+
+    fn recv(_data: &mut dyn Read) -> Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SendableBytes borrows its data, so it can't be received. Receive a Vec<u8> instead.",
+        )
+        .into())
+    }
+}
+
+macro_rules! impl_canonical_float {
+    ($name:ident, $t:ty) => {
+        #[doc = concat!("A `", stringify!($t), "` that is always sent as the same bytes for equal values.")]
+        ///
+        /// Every NaN is sent as the canonical NaN, and `-0.0` is sent as `0.0`. Everything else is sent exactly like the
+        /// plain float. This is useful for protocols that hash or compare the sent bytes.
+        #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+        pub struct $name(pub $t);
+
+        impl $name {
+            /// Returns the value that is actually sent.
+            pub fn canonical(self) -> $t {
+                if self.0.is_nan() {
+                    <$t>::NAN
+                } else if self.0 == 0.0 {
+                    // -0.0 == 0.0, so this catches both.
+                    0.0
+                } else {
+                    self.0
+                }
+            }
+        }
+
+        impl Sendable for $name {
+            const SIZE_CONST: bool = true;
+
+            fn send(&self) -> Vec<u8> {
+                self.canonical().send()
+            }
+
+            fn send_into(&self, buf: &mut Vec<u8>) {
+                self.canonical().send_into(buf);
+            }
+
+            fn recv(data: &mut dyn Read) -> Result<Self> {
+                Ok($name(<$t>::recv(data)?))
+            }
+        }
+
+        impl From<$t> for $name {
+            fn from(value: $t) -> Self {
+                $name(value)
             }
-            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8").into()),
         }
+    };
+}
+
+impl_canonical_float!(CanonicalF32, f32);
+impl_canonical_float!(CanonicalF64, f64);
+
+/// An integer-backed value (e.g. a set of bit flags) that is sent as an integer with the same width as T.
+///
+/// A `u16`-backed flag type is sent as 2 bytes, instead of the 8 a u64 would take. T's integer must fit in `size_of::<T>()` bytes
+/// (at most 8), which is true for a newtype around an integer. Any higher bits would be cut off when sending,
+/// so this is checked with a debug assertion. When received, the value is converted back with `TryFrom`,
+/// so an invalid bit pattern is an `InvalidData` error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct SendableBits<T>(pub T);
+
+impl<T> SendableBits<T> {
+    /// The number of bytes the value is sent as.
+    const WIDTH: usize = if mem::size_of::<T>() < 8 {
+        mem::size_of::<T>()
+    } else {
+        8
+    };
+}
+
+impl<T> Sendable for SendableBits<T>
+where
+    T: Copy + Into<u64> + TryFrom<u64>,
+{
+    const SIZE_CONST: bool = true;
+
+    fn header(&self) -> PacketHeader<Self> {
+        unsafe { PacketHeader::new(self.size()) }
+    }
+
+    fn size(&self) -> u32 {
+        Self::WIDTH as u32
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::WIDTH);
+        self.send_into(&mut data);
+        data
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        let bits: u64 = self.0.into();
+        // A shift by the full 64 bits (WIDTH == 8) has nothing to cut off.
+        debug_assert!(
+            bits.checked_shr(8 * Self::WIDTH as u32).unwrap_or(0) == 0,
+            "{} converted to {:#x}, which doesn't fit in its {}-byte width",
+            std::any::type_name::<T>(),
+            bits,
+            Self::WIDTH
+        );
+        let bytes = bits.to_be_bytes();
+        buf.extend_from_slice(&bytes[8 - Self::WIDTH..]);
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let mut bytes = [0; 8];
+        data.read_exact(&mut bytes[8 - Self::WIDTH..])?;
+        let bits = u64::from_be_bytes(bytes);
+        T::try_from(bits).map(SendableBits).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid bits for {}: {:#x}",
+                    std::any::type_name::<T>(),
+                    bits
+                ),
+            )
+            .into()
+        })
     }
 }
 
-impl<T> Sendable for Option<T>
+impl<T> From<T> for SendableBits<T> {
+    fn from(value: T) -> Self {
+        SendableBits(value)
+    }
+}
+
+/// Sent as the start followed by the end.
+impl<T> Sendable for Range<T>
 where
     T: Sendable,
 {
+    const SIZE_CONST: bool = T::SIZE_CONST;
+
     fn header(&self) -> PacketHeader<Self> {
-        match self {
-            Some(value) => unsafe { PacketHeader::new(value.size() + 1) },
-            None => unsafe { PacketHeader::new(1) },
+        unsafe { PacketHeader::new(self.size()) }
+    }
+
+    fn size(&self) -> u32 {
+        self.start.size() + self.end.size()
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.size() as usize);
+        self.send_into(&mut data);
+        data
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        self.start.send_into(buf);
+        self.end.send_into(buf);
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        Ok(T::recv(data)?..T::recv(data)?)
+    }
+}
+
+/// Sent as the start followed by the end, like `Range`.
+///
+/// Only the bounds are sent, so a range that has been fully iterated is received as a new range with the same bounds.
+impl<T> Sendable for RangeInclusive<T>
+where
+    T: Sendable,
+{
+    const SIZE_CONST: bool = T::SIZE_CONST;
+
+    fn header(&self) -> PacketHeader<Self> {
+        unsafe { PacketHeader::new(self.size()) }
+    }
+
+    fn size(&self) -> u32 {
+        self.start().size() + self.end().size()
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.size() as usize);
+        self.send_into(&mut data);
+        data
+    }
+
+    fn send_into(&self, buf: &mut Vec<u8>) {
+        self.start().send_into(buf);
+        self.end().send_into(buf);
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        Ok(T::recv(data)?..=T::recv(data)?)
+    }
+}
+
+impl Sendable for Duration {
+    const SIZE_CONST: bool = true;
+
+    fn size(&self) -> u32 {
+        // u64 seconds + u32 nanoseconds
+        12
+    }
+
+    fn send(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(self.as_secs().send());
+        data.extend(self.subsec_nanos().send());
+        data
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let secs = u64::recv(data)?;
+        let nanos = u32::recv(data)?;
+        if nanos >= 1_000_000_000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Duration nanoseconds out of range",
+            )
+            .into());
         }
+        Ok(Duration::new(secs, nanos))
     }
+}
+
+/// SystemTime is sent as the Duration since UNIX_EPOCH.
+///
+/// Times before UNIX_EPOCH can't be represented, so they are clamped to UNIX_EPOCH when sent.
+/// recv returns an `InvalidData` error if the time is too far in the future for this platform's SystemTime.
+impl Sendable for SystemTime {
+    const SIZE_CONST: bool = true;
+
+    fn size(&self) -> u32 {
+        // Same as Duration.
+        12
+    }
+
+    fn send(&self) -> Vec<u8> {
+        self.duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .send()
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        let since_epoch = Duration::recv(data)?;
+        UNIX_EPOCH.checked_add(since_epoch).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "SystemTime out of range").into()
+        })
+    }
+}
+
+impl Sendable for Ipv4Addr {
+    const SIZE_CONST: bool = true;
+
+    fn size(&self) -> u32 {
+        4
+    }
+
+    fn send(&self) -> Vec<u8> {
+        self.octets().to_vec()
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        Ok(Ipv4Addr::from(<[u8; 4]>::recv(data)?))
+    }
+}
+
+impl Sendable for Ipv6Addr {
+    const SIZE_CONST: bool = true;
+
+    fn size(&self) -> u32 {
+        16
+    }
+
+    fn send(&self) -> Vec<u8> {
+        self.octets().to_vec()
+    }
+
+    fn recv(data: &mut dyn Read) -> Result<Self> {
+        Ok(Ipv6Addr::from(<[u8; 16]>::recv(data)?))
+    }
+}
 
+impl Sendable for IpAddr {
     fn size(&self) -> u32 {
         match self {
-            Some(value) => value.size() + 1,
-            None => 1,
+            IpAddr::V4(addr) => addr.size() + 1,
+            IpAddr::V6(addr) => addr.size() + 1,
         }
     }
 
     fn send(&self) -> Vec<u8> {
+        // Tagged like Option: 0 for v4, 1 for v6.
         let mut data = Vec::new();
         match self {
-            Some(value) => {
-                data.extend(true.send());
-                data.extend(value.send());
+            IpAddr::V4(addr) => {
+                data.extend(0u8.send());
+                data.extend(addr.send());
             }
-            None => {
-                data.extend(false.send());
+            IpAddr::V6(addr) => {
+                data.extend(1u8.send());
+                data.extend(addr.send());
             }
         }
         data
     }
 
     fn recv(data: &mut dyn Read) -> Result<Self> {
-        let is_present = bool::recv(data).unwrap();
-        if !is_present {
-            Ok(None)
-        } else {
-            Ok(Some(T::recv(data)?))
+        match u8::recv(data)? {
+            0 => Ok(IpAddr::V4(Ipv4Addr::recv(data)?)),
+            1 => Ok(IpAddr::V6(Ipv6Addr::recv(data)?)),
+            tag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid IpAddr tag: {}", tag),
+            )
+            .into()),
         }
     }
 }
 
-impl<T> Sendable for Box<T>
-where
-    T: Sendable + Copy,
-{
-    fn header(&self) -> PacketHeader<Self> {
-        unsafe { PacketHeader::new(self.size()) }
-    }
-
+/// SocketAddr is sent as an IpAddr followed by the port.
+/// The flowinfo and scope_id of v6 addresses are not sent.
+impl Sendable for SocketAddr {
     fn size(&self) -> u32 {
-        T::size(&**self)
+        self.ip().size() + 2
     }
 
     fn send(&self) -> Vec<u8> {
-        T::send(&**self)
+        let mut data = self.ip().send();
+        data.extend(self.port().send());
+        data
     }
 
     fn recv(data: &mut dyn Read) -> Result<Self> {
-        Ok(Box::new(T::recv(data)?))
+        let ip = IpAddr::recv(data)?;
+        let port = u16::recv(data)?;
+        Ok(SocketAddr::new(ip, port))
     }
 }
 
 macro_rules! impl_sendable_tuple {
     ($($name:ident)+) => {
         #[allow(non_snake_case)]
-        impl<$($name: Sendable + std::fmt::Debug,)*> Sendable for ($($name,)*) {
+        impl<$($name: Sendable,)*> Sendable for ($($name,)*) {
+            const SIZE_CONST: bool = true $(&& $name::SIZE_CONST)*;
+
             fn size(&self) -> u32{
                 let ($(ref $name,)*) = *self;
                 let mut total = 0;
@@ -260,8 +1209,8 @@ macro_rules! impl_sendable_tuple {
         }
     };
 }
-// Implement the Sendable trait for tuples of size 0 to 12.
-// We don't go above 12 because A. A tuple of size 12 is already pretty big, and B. Debug implements up to 12.
+// Implement the Sendable trait for tuples of size 0 to 16.
+// Sendable doesn't require Debug, so this isn't limited to the 12 elements that std implements Debug for.
 impl_sendable_tuple!(A);
 impl_sendable_tuple!(A B);
 impl_sendable_tuple!(A B C);
@@ -274,8 +1223,14 @@ impl_sendable_tuple!(A B C D E F G H I);
 impl_sendable_tuple!(A B C D E F G H I J);
 impl_sendable_tuple!(A B C D E F G H I J K);
 impl_sendable_tuple!(A B C D E F G H I J K L);
+impl_sendable_tuple!(A B C D E F G H I J K L M);
+impl_sendable_tuple!(A B C D E F G H I J K L M N);
+impl_sendable_tuple!(A B C D E F G H I J K L M N O);
+impl_sendable_tuple!(A B C D E F G H I J K L M N O P);
 
 impl Sendable for () {
+    const SIZE_CONST: bool = true;
+
     fn size(&self) -> u32 {
         0
     }
@@ -289,10 +1244,42 @@ impl Sendable for () {
     }
 }
 
+/// PhantomData is sent the same way as `()`, so marker fields don't need special handling in the derive macro.
+impl<T: ?Sized> Sendable for PhantomData<T> {
+    const SIZE_CONST: bool = true;
+
+    fn size(&self) -> u32 {
+        0
+    }
+
+    fn send(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn recv(_reader: &mut dyn std::io::Read) -> Result<Self> {
+        Ok(PhantomData)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     //! Thank god for macros.
     use super::*;
+
+    /// Asserts that `size` matches the number of bytes `send` and `send_into` produce.
+    /// If this doesn't hold, the header's payload_size is wrong and the receiver reads the wrong number of bytes.
+    fn check_size_invariant<T: Sendable + std::fmt::Debug>(value: &T) {
+        let data = value.send();
+        assert_eq!(
+            value.size() as usize,
+            data.len(),
+            "size mismatch for {:?}",
+            value
+        );
+        let mut buf = vec![0xAA];
+        value.send_into(&mut buf);
+        assert_eq!(&buf[1..], &data[..], "send_into mismatch for {:?}", value);
+    }
     macro_rules! test_sendable_number {
         ($t:ty, $name: ident) => {
             #[test]
@@ -314,6 +1301,17 @@ mod tests {
         i16, test_i16, i32, test_i32, i64, test_i64, i128, test_i128, f32, test_f32, f64, test_f64
     );
 
+    #[test]
+    fn test_number_network_byte_order() {
+        // If this fails, the wire format is no longer big-endian.
+        assert_eq!(0x01020304u32.send(), vec![1, 2, 3, 4]);
+        assert_eq!(0x0102u16.send(), vec![1, 2]);
+        assert_eq!((-2i32).send(), vec![0xFF, 0xFF, 0xFF, 0xFE]);
+        assert_eq!(1.0f32.send(), vec![0x3F, 0x80, 0, 0]);
+        let mut reader = std::io::Cursor::new([1, 2, 3, 4]);
+        assert_eq!(u32::recv(&mut reader).unwrap(), 0x01020304);
+    }
+
     macro_rules! test_sendable_vec {
         ($t: ty, $name: ident) => {
             #[test]
@@ -356,6 +1354,18 @@ mod tests {
         test_f64_vec
     );
 
+    #[test]
+    fn test_vec_huge_length() {
+        // A length prefix of u32::MAX with only a few bytes behind it.
+        let mut data = u32::MAX.send();
+        data.extend([1, 2, 3, 4]);
+        let mut reader = std::io::Cursor::new(&data);
+        let err = Vec::<u8>::recv(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Nothing past the length prefix should have been read.
+        assert_eq!(reader.position(), 4);
+    }
+
     #[test]
     fn test_vec_variable_size() {
         let mut vecs = Vec::<Vec<u8>>::new();
@@ -380,6 +1390,155 @@ mod tests {
         assert_eq!(value, result);
     }
 
+    #[test]
+    fn test_vec_deque_send() {
+        let mut value = VecDeque::new();
+        value.push_back(3u32);
+        value.push_back(4);
+        value.push_front(2);
+        value.push_front(1);
+        let data = value.send();
+        assert_eq!(data.len(), value.size() as usize);
+        // A VecDeque is wire-compatible with a Vec.
+        assert_eq!(data, vec![1u32, 2, 3, 4].send());
+        let mut reader = std::io::Cursor::new(&data);
+        let result = VecDeque::<u32>::recv(&mut reader).unwrap();
+        assert!(value.iter().eq(result.iter()));
+    }
+
+    #[test]
+    fn test_array_send() {
+        let value = [1u32, 2, 3, 4];
+        let data = value.send();
+        assert_eq!(data.len(), 16);
+        assert_eq!(value.size(), 16);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = <[u32; 4]>::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_array_variable_size() {
+        let value = ["Hello".to_string(), "World!".to_string()];
+        let data = value.send();
+        assert_eq!(data.len(), value.size() as usize);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = <[String; 2]>::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_array_unexpected_eof() {
+        let data = [1u32, 2].send();
+        let mut reader = std::io::Cursor::new(&data);
+        let err = <[u32; 4]>::recv(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_hashmap_send() {
+        let mut map = HashMap::new();
+        map.insert("one".to_string(), 1u32);
+        map.insert("two".to_string(), 2u32);
+        map.insert("three".to_string(), 3u32);
+        let data = map.send();
+        assert_eq!(data.len(), map.size() as usize);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = HashMap::<String, u32>::recv(&mut reader).unwrap();
+        assert_eq!(map, result);
+    }
+
+    #[test]
+    fn test_btreemap_send() {
+        let mut map = BTreeMap::new();
+        map.insert(3u8, "three".to_string());
+        map.insert(1u8, "one".to_string());
+        map.insert(2u8, "two".to_string());
+        let data = map.send();
+        assert_eq!(data.len(), map.size() as usize);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = BTreeMap::<u8, String>::recv(&mut reader).unwrap();
+        assert_eq!(map, result);
+    }
+
+    #[test]
+    fn test_btreemap_deterministic() {
+        let mut forward = BTreeMap::new();
+        let mut backward = BTreeMap::new();
+        for i in 0..10u32 {
+            forward.insert(i, i * 2);
+        }
+        for i in (0..10u32).rev() {
+            backward.insert(i, i * 2);
+        }
+        assert_eq!(forward.send(), backward.send());
+    }
+
+    #[test]
+    fn test_char_send() {
+        for value in ['a', '😀'] {
+            let data = value.send();
+            assert_eq!(data.len(), 4);
+            let mut reader = std::io::Cursor::new(&data);
+            let result = char::recv(&mut reader).unwrap();
+            assert_eq!(value, result);
+        }
+    }
+
+    #[test]
+    fn test_char_invalid() {
+        let data = 0xD800u32.send();
+        let mut reader = std::io::Cursor::new(&data);
+        let err = char::recv(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_string_huge_length() {
+        let mut data = u32::MAX.send();
+        data.extend(b"abc");
+        let mut reader = std::io::Cursor::new(&data);
+        let err = String::recv(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Held by tests that depend on the max length, because `set_max_length` changes it for every test in the process.
+    static MAX_LENGTH_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn test_set_max_length() {
+        let _lock = MAX_LENGTH_LOCK.lock().unwrap();
+        // Other tests send a few MiB, so don't go below that while the limit is lowered.
+        let limit = 8 * 1024 * 1024;
+        set_max_length(limit);
+        assert_eq!(max_length(), limit);
+        let mut data = (limit + 1).send();
+        data.extend([1, 2, 3, 4]);
+        let vec_err = Vec::<u8>::recv(&mut std::io::Cursor::new(&data)).unwrap_err();
+        let string_err = String::recv(&mut std::io::Cursor::new(&data)).unwrap_err();
+        // At the limit, the prefix is accepted, and the data just runs out.
+        let mut data = limit.send();
+        data.extend([1, 2, 3, 4]);
+        let at_limit_err = Vec::<u8>::recv(&mut std::io::Cursor::new(&data)).unwrap_err();
+        // Restore the limit before asserting, so a failure doesn't leave it lowered for other tests.
+        set_max_length(DEFAULT_MAX_LENGTH);
+        assert_eq!(vec_err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(string_err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(at_limit_err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(max_length(), DEFAULT_MAX_LENGTH);
+    }
+
+    #[test]
+    fn test_string_truncated() {
+        let _lock = MAX_LENGTH_LOCK.lock().unwrap();
+        // Within the limit, but the data never arrives.
+        let mut data = (DEFAULT_MAX_LENGTH - 1).send();
+        data.extend(b"abc");
+        let mut reader = std::io::Cursor::new(&data);
+        let err = String::recv(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
     #[test]
     fn test_option_send_some() {
         let value = Some(42);
@@ -400,6 +1559,134 @@ mod tests {
         assert_eq!(value, result);
     }
 
+    #[test]
+    fn test_option_size() {
+        fn check<T: Sendable + 'static + std::fmt::Debug>(value: Option<T>) {
+            let data = value.send();
+            assert_eq!(value.size() as usize, data.len(), "{:?}", value);
+            assert_eq!(value.header().payload_size as usize, data.len());
+        }
+        check(Some(42u32));
+        check(None::<u32>);
+        check(Some("Hello, World!".to_string()));
+        check(Some(String::new()));
+        check(None::<String>);
+        check(Some(vec![1u8, 2, 3]));
+        check(None::<Vec<u8>>);
+        check(Some(Some(42u32)));
+        check(Some(None::<u32>));
+        check(None::<Option<u32>>);
+    }
+
+    #[test]
+    fn test_send_chunked() {
+        fn check<T: Sendable + std::fmt::Debug>(value: T) {
+            let mut written = Vec::new();
+            let len = value.send_chunked(&mut written).unwrap();
+            assert_eq!(written, value.send(), "{:?}", value);
+            assert_eq!(len, written.len() as u64);
+        }
+        // Large enough to be written in several chunks.
+        check((0..100_000u32).collect::<Vec<_>>());
+        check(vec![7u8; CHUNK_SIZE * 2 + 1]);
+        check(VecDeque::from(vec!["a".to_string(); 100]));
+        check("Hello, World!".repeat(10_000));
+        check(Box::new(vec![1u16, 2, 3]));
+        check(Cow::<'static, str>::Borrowed("cow"));
+        check(Cow::<'static, [u8]>::Owned(vec![1, 2, 3]));
+        check(SendableBytes(&[4, 5, 6]));
+        check(Vec::<u64>::new());
+        // Uses the default implementation.
+        check((1u8, "two".to_string()));
+    }
+
+    #[test]
+    fn test_size_invariant() {
+        check_size_invariant(&0u8);
+        check_size_invariant(&-1i16);
+        check_size_invariant(&u32::MAX);
+        check_size_invariant(&42u64);
+        check_size_invariant(&-42i128);
+        check_size_invariant(&1.5f32);
+        check_size_invariant(&-1.5f64);
+        check_size_invariant(&NonZeroU16::new(3).unwrap());
+        check_size_invariant(&true);
+        check_size_invariant(&'\u{1F980}');
+        check_size_invariant(&String::new());
+        check_size_invariant(&"Hello, World!".to_string());
+        check_size_invariant(&Vec::<u32>::new());
+        check_size_invariant(&vec![1u16, 2, 3]);
+        check_size_invariant(&vec!["a".to_string(), "bcd".to_string()]);
+        check_size_invariant(&VecDeque::from(vec![1u8, 2]));
+        check_size_invariant(&[1u32, 2, 3]);
+        check_size_invariant(&["a".to_string(), "bc".to_string()]);
+        check_size_invariant(&HashMap::from([(1u8, "a".to_string())]));
+        check_size_invariant(&BTreeMap::from([(1u8, 2u64), (3, 4)]));
+        check_size_invariant(&Some(5u32));
+        check_size_invariant(&None::<String>);
+        check_size_invariant(&Some(Some(vec![1u8])));
+        check_size_invariant(&Ok::<u32, String>(1));
+        check_size_invariant(&Err::<u32, String>("err".to_string()));
+        check_size_invariant(&Box::new(5u64));
+        check_size_invariant(&Box::new("boxed".to_string()));
+        check_size_invariant(&Cow::<'static, str>::Borrowed("cow"));
+        check_size_invariant(&Cow::<'static, [u8]>::Owned(vec![1, 2, 3]));
+        check_size_invariant(&SendableBytes(&[1, 2, 3]));
+        check_size_invariant(&Duration::from_millis(1500));
+        check_size_invariant(&Ipv4Addr::LOCALHOST);
+        check_size_invariant(&Ipv6Addr::LOCALHOST);
+        check_size_invariant(&IpAddr::V4(Ipv4Addr::LOCALHOST));
+        check_size_invariant(&IpAddr::V6(Ipv6Addr::LOCALHOST));
+        check_size_invariant(&SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+        check_size_invariant(&SocketAddr::from((Ipv6Addr::LOCALHOST, 80)));
+        check_size_invariant(&(1u8,));
+        check_size_invariant(&(1u8, "two".to_string(), vec![3u32]));
+        check_size_invariant(&(
+            1u8,
+            2u16,
+            3u32,
+            4u64,
+            5i8,
+            6i16,
+            7i32,
+            8i64,
+            true,
+            'a',
+            1.0f32,
+            (),
+        ));
+        check_size_invariant(&());
+        check_size_invariant(&PhantomData::<String>);
+    }
+
+    #[test]
+    fn test_option_truncated() {
+        let mut reader = std::io::Cursor::new(Vec::new());
+        assert!(Option::<u32>::recv(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_result_send_ok() {
+        let value: std::result::Result<u32, String> = Ok(42);
+        let data = value.send();
+        assert_eq!(data[0], 0);
+        assert_eq!(data.len(), value.size() as usize);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = std::result::Result::<u32, String>::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_result_send_err() {
+        let value: std::result::Result<u32, String> = Err("boom".to_string());
+        let data = value.send();
+        assert_eq!(data[0], 1);
+        assert_eq!(data.len(), value.size() as usize);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = std::result::Result::<u32, String>::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+    }
+
     #[test]
     fn test_box_send() {
         let value = Box::new(42);
@@ -409,6 +1696,370 @@ mod tests {
         assert_eq!(value, result);
     }
 
+    #[test]
+    fn test_arc_string_send() {
+        let value = Arc::new("Hello, World!".to_string());
+        let data = value.send();
+        assert_eq!(data.len(), value.size() as usize);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = Arc::<String>::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+        // The received value is a new allocation, not shared with the one that was sent.
+        assert!(!Arc::ptr_eq(&value, &result));
+    }
+
+    #[test]
+    fn test_rc_send() {
+        let value = Rc::new(42u32);
+        let data = value.send();
+        assert_eq!(data, 42u32.send());
+        let mut reader = std::io::Cursor::new(&data);
+        let result = Rc::<u32>::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_type_registry() {
+        let mut registry = TypeRegistry::new();
+        registry.register::<u32>();
+        registry.register::<String>();
+        assert!(registry.is_registered(hash_type_id::<u32>()));
+        assert!(!registry.is_registered(hash_type_id::<u64>()));
+
+        let values: Vec<Box<dyn SendableAny>> = vec![Box::new(7u32), Box::new("seven".to_owned())];
+        let received: Vec<_> = values
+            .iter()
+            .map(|value| {
+                let header = value.header_any();
+                let data = value.send_any();
+                assert_eq!(header.payload_size as usize, data.len());
+                registry
+                    .recv(header.type_id(), &mut std::io::Cursor::new(data))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(received[0].downcast_ref::<u32>(), Some(&7));
+        assert_eq!(received[1].downcast_ref::<String>().unwrap(), "seven");
+
+        let err = registry
+            .recv(hash_type_id::<u64>(), &mut std::io::Cursor::new(vec![0; 8]))
+            .unwrap_err();
+        assert!(matches!(err, LazuliError::UnknownStream(_)));
+    }
+
+    #[test]
+    fn test_duration_send() {
+        let value = Duration::from_millis(1500);
+        let data = value.send();
+        assert_eq!(data.len(), 12);
+        assert_eq!(value.size(), 12);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = Duration::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_system_time_send() {
+        let value = SystemTime::now();
+        let data = value.send();
+        assert_eq!(data.len(), 12);
+        assert_eq!(value.size(), 12);
+        let mut reader = std::io::Cursor::new(&data);
+        let result = SystemTime::recv(&mut reader).unwrap();
+        // The nanoseconds are sent too, so the time is exact.
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_system_time_before_epoch() {
+        let value = UNIX_EPOCH - Duration::from_secs(60);
+        let mut reader = std::io::Cursor::new(value.send());
+        assert_eq!(SystemTime::recv(&mut reader).unwrap(), UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_socket_addr_send() {
+        let values = [
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), 8080),
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 443),
+        ];
+        for value in values {
+            let data = value.send();
+            assert_eq!(data.len(), value.size() as usize);
+            let mut reader = std::io::Cursor::new(&data);
+            let result = SocketAddr::recv(&mut reader).unwrap();
+            assert_eq!(value, result);
+        }
+    }
+
+    #[test]
+    fn test_ip_addr_invalid_tag() {
+        let mut reader = std::io::Cursor::new([2u8, 127, 0, 0, 1]);
+        let err = IpAddr::recv(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_box_string_send() {
+        let value = Box::new("Hello, World!".to_string());
+        let data = value.send();
+        let mut reader = std::io::Cursor::new(&data);
+        let result = Box::<String>::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_box_vec_send() {
+        let value = Box::new(vec![1u32, 2, 3]);
+        let data = value.send();
+        let mut reader = std::io::Cursor::new(&data);
+        let result = Box::<Vec<u32>>::recv(&mut reader).unwrap();
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_cow_str_send() {
+        let value: Cow<'static, str> = Cow::Borrowed("Hello, World!");
+        let data = value.send();
+        assert_eq!(data, "Hello, World!".to_string().send());
+        assert_eq!(value.size() as usize, data.len());
+        let mut reader = std::io::Cursor::new(&data);
+        let result = Cow::<str>::recv(&mut reader).unwrap();
+        assert!(matches!(result, Cow::Owned(_)));
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_cow_bytes_send() {
+        let value: Cow<'static, [u8]> = Cow::Borrowed(&[1, 2, 3, 4]);
+        let data = value.send();
+        assert_eq!(data, vec![1u8, 2, 3, 4].send());
+        assert_eq!(value.size() as usize, data.len());
+        let mut reader = std::io::Cursor::new(&data);
+        let result = Cow::<[u8]>::recv(&mut reader).unwrap();
+        assert!(matches!(result, Cow::Owned(_)));
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn test_size_const() {
+        let constant = [
+            u32::SIZE_CONST,
+            <(u8, char, Duration)>::SIZE_CONST,
+            <[u16; 4]>::SIZE_CONST,
+        ];
+        assert_eq!(constant, [true; 3]);
+        let variable = [
+            String::SIZE_CONST,
+            <(u8, String)>::SIZE_CONST,
+            Vec::<u32>::SIZE_CONST,
+            IpAddr::SIZE_CONST,
+        ];
+        assert_eq!(variable, [false; 4]);
+
+        // The fast path should give the same size as adding up every item.
+        fn slow_size<T: Sendable>(items: &[T]) -> u32 {
+            items.iter().map(T::size).sum::<u32>() + 4
+        }
+        let numbers: Vec<u32> = (0..1_000_000).collect();
+        assert_eq!(numbers.size(), slow_size(&numbers));
+        assert_eq!(numbers.size() as usize, numbers.send().len());
+        let durations = vec![Duration::from_secs(1); 10];
+        assert_eq!(durations.size(), slow_size(&durations));
+        assert_eq!(durations.size() as usize, durations.send().len());
+        let empty: Vec<u64> = vec![];
+        assert_eq!(empty.size(), 4);
+        let deque: VecDeque<(u8, u16)> = (0..10).map(|i| (i, i as u16)).collect();
+        assert_eq!(deque.size() as usize, deque.send().len());
+        let array = [1u64; 7];
+        assert_eq!(array.size() as usize, array.send().len());
+    }
+
+    #[test]
+    fn test_send_into() {
+        fn check<T: Sendable + std::fmt::Debug>(value: T) {
+            // Start with something in the buffer, to make sure send_into appends.
+            let mut buf = vec![0xAA];
+            value.send_into(&mut buf);
+            assert_eq!(buf[0], 0xAA);
+            assert_eq!(buf[1..], value.send(), "{:?}", value);
+        }
+        check(0x1234u16);
+        check(-5i64);
+        check(1.5f32);
+        check(true);
+        check('λ');
+        check("Hello, World!".to_string());
+        check(vec![1u32, 2, 3]);
+        check(vec!["a".to_string(), "bc".to_string()]);
+        check(vec![vec![1u8], vec![]]);
+        // Falls back to the default implementation.
+        check(Some(4u8));
+    }
+
+    #[test]
+    fn test_canonical_float() {
+        let quiet = f64::from_bits(0x7FF8_0000_0000_0001);
+        let negative = f64::from_bits(0xFFF0_0000_0000_0002);
+        assert!(quiet.is_nan() && negative.is_nan());
+        assert_ne!(quiet.send(), negative.send());
+        assert_eq!(CanonicalF64(quiet).send(), CanonicalF64(negative).send());
+        assert_eq!(CanonicalF64(-0.0).send(), CanonicalF64(0.0).send());
+        assert_ne!((-0.0f64).send(), 0.0f64.send());
+        // Other values are sent like the plain float.
+        assert_eq!(CanonicalF64(-1.5).send(), (-1.5f64).send());
+
+        let nan = f32::from_bits(0x7FC0_1234);
+        assert_eq!(CanonicalF32(nan).send(), CanonicalF32(f32::NAN).send());
+        assert_eq!(CanonicalF32(-0.0).send(), CanonicalF32(0.0).send());
+
+        let data = CanonicalF64(2.25).send();
+        let result = CanonicalF64::recv(&mut std::io::Cursor::new(&data)).unwrap();
+        assert_eq!(result.0, 2.25);
+        check_size_invariant(&CanonicalF64(negative));
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Flags(u16);
+
+    impl Flags {
+        const READ: Flags = Flags(0b01);
+        const WRITE: Flags = Flags(0b10);
+    }
+
+    impl From<Flags> for u64 {
+        fn from(flags: Flags) -> u64 {
+            flags.0 as u64
+        }
+    }
+
+    impl TryFrom<u64> for Flags {
+        type Error = ();
+
+        fn try_from(bits: u64) -> std::result::Result<Self, ()> {
+            if bits & !0b11 != 0 {
+                return Err(());
+            }
+            Ok(Flags(bits as u16))
+        }
+    }
+
+    #[test]
+    fn test_sendable_bits() {
+        let flags = SendableBits(Flags(Flags::READ.0 | Flags::WRITE.0));
+        let data = flags.send();
+        assert_eq!(data, vec![0, 0b11]);
+        let result = SendableBits::<Flags>::recv(&mut std::io::Cursor::new(&data)).unwrap();
+        assert_eq!(result, flags);
+        check_size_invariant(&flags);
+
+        let err = SendableBits::<Flags>::recv(&mut std::io::Cursor::new(&[1, 0])).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        // Plain integers work too.
+        assert_eq!(SendableBits(7u8).send(), vec![7]);
+        assert_eq!(SendableBits(u64::MAX).send(), vec![0xFF; 8]);
+    }
+
+    /// A u8-sized type that claims more bits than it has room for.
+    #[derive(Debug, Clone, Copy)]
+    struct Wide(u8);
+
+    impl From<Wide> for u64 {
+        fn from(wide: Wide) -> u64 {
+            (wide.0 as u64) << 8
+        }
+    }
+
+    impl TryFrom<u64> for Wide {
+        type Error = ();
+
+        fn try_from(bits: u64) -> std::result::Result<Self, ()> {
+            Ok(Wide((bits >> 8) as u8))
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "doesn't fit in its 1-byte width")]
+    fn test_sendable_bits_too_wide() {
+        SendableBits(Wide(1)).send();
+    }
+
+    #[test]
+    fn test_reference_send() {
+        let value = (7u32, "seven".to_string());
+        let reference = &value;
+        // Method calls on a reference would pick the impl for T, so the impl for &T is called explicitly.
+        assert_eq!(Sendable::send(&reference), value.send());
+        assert_eq!(Sendable::send(&&reference), value.send());
+        assert_eq!(Sendable::size(&reference), value.size());
+        check_size_invariant(&reference);
+
+        // The peer receives a T, so the header has the type id of T. Headers need a 'static type.
+        static NUMBERS: [u32; 3] = [1, 2, 3];
+        let header = Sendable::header(&&NUMBERS);
+        assert_eq!(header.type_id(), hash_type_id::<[u32; 3]>());
+        assert_eq!(header.payload_size, NUMBERS.size());
+
+        let err = <&u32>::recv(&mut std::io::Cursor::new(7u32.send())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_range_send() {
+        let range = 0u32..10u32;
+        let data = range.send();
+        assert_eq!(data, [0u32.send(), 10u32.send()].concat());
+        let result = Range::<u32>::recv(&mut std::io::Cursor::new(&data)).unwrap();
+        assert_eq!(result, range);
+        check_size_invariant(&range);
+
+        let range = 1u8..=5u8;
+        let data = range.send();
+        assert_eq!(data, vec![1, 5]);
+        let result = RangeInclusive::<u8>::recv(&mut std::io::Cursor::new(&data)).unwrap();
+        assert_eq!(result, range);
+        check_size_invariant(&range);
+
+        let range = "a".to_string().."z".to_string();
+        let result = Range::<String>::recv(&mut std::io::Cursor::new(range.send())).unwrap();
+        assert_eq!(result, range);
+        check_size_invariant(&range);
+    }
+
+    #[test]
+    fn test_sendable_bytes() {
+        let bytes = [1u8, 2, 3, 255];
+        let borrowed = SendableBytes(&bytes);
+        assert_eq!(borrowed.send(), bytes.to_vec().send());
+        assert_eq!(borrowed.size(), bytes.to_vec().size());
+        let mut reader = std::io::Cursor::new(borrowed.send());
+        assert_eq!(Vec::<u8>::recv(&mut reader).unwrap(), bytes);
+        let mut reader = std::io::Cursor::new(borrowed.send());
+        assert!(SendableBytes::recv(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_nonzero_send() {
+        let value = NonZeroU32::new(5).unwrap();
+        let data = value.send();
+        assert_eq!(data, 5u32.send());
+        assert_eq!(value.size() as usize, data.len());
+        let mut reader = std::io::Cursor::new(&data);
+        assert_eq!(NonZeroU32::recv(&mut reader).unwrap(), value);
+
+        let value = NonZeroI64::new(-7).unwrap();
+        let mut reader = std::io::Cursor::new(value.send());
+        assert_eq!(NonZeroI64::recv(&mut reader).unwrap(), value);
+    }
+
+    #[test]
+    fn test_nonzero_zero() {
+        let mut reader = std::io::Cursor::new([0u8; 4]);
+        let err = NonZeroU32::recv(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn test_tuple_send() {
         let t = (1u32, 10.0, String::from("Hello, World!"), vec![1, 2, 3, 4]);
@@ -417,15 +2068,70 @@ mod tests {
         let recv: (u32, f64, String, Vec<i32>) = Sendable::recv(&mut reader).unwrap();
         assert_eq!(t, recv);
     }
+    #[test]
+    fn test_large_tuple_send() {
+        #[allow(clippy::type_complexity)]
+        type Row = (
+            u8,
+            i8,
+            u16,
+            i16,
+            u32,
+            i32,
+            u64,
+            i64,
+            f32,
+            f64,
+            bool,
+            char,
+            String,
+            Vec<u8>,
+        );
+        let row: Row = (
+            1,
+            -2,
+            3,
+            -4,
+            5,
+            -6,
+            7,
+            -8,
+            9.5,
+            10.25,
+            true,
+            'x',
+            "row".to_string(),
+            vec![1, 2],
+        );
+        let data = row.send();
+        assert_eq!(data.len(), row.size() as usize);
+        let mut reader = std::io::Cursor::new(data);
+        let recv = Row::recv(&mut reader).unwrap();
+        // std only implements PartialEq for tuples up to 12 elements, so compare in two halves.
+        let (a, b, c, d, e, f, g, h, i, j, k, l, m, n) = recv;
+        assert_eq!(
+            (a, b, c, d, e, f, g),
+            (row.0, row.1, row.2, row.3, row.4, row.5, row.6)
+        );
+        assert_eq!(
+            (h, i, j, k, l, m, n),
+            (row.7, row.8, row.9, row.10, row.11, row.12, row.13)
+        );
+        let sixteen = (
+            0u8, 1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8, 8u8, 9u8, 10u8, 11u8, 12u8, 13u8, 14u8, 15u8,
+        );
+        assert_eq!(sixteen.send(), (0..16).collect::<Vec<u8>>());
+    }
+
     #[test]
     fn test_recursive_tuple_send() {
+        type Pair = (i32, i32);
         let init = (1, 2);
         let init1 = (init, init);
         let send = (init1, init1);
         let data = send.send();
         let mut reader = std::io::Cursor::new(data);
-        let recv: (((i32, i32), (i32, i32)), ((i32, i32), (i32, i32))) =
-            Sendable::recv(&mut reader).unwrap();
+        let recv: ((Pair, Pair), (Pair, Pair)) = Sendable::recv(&mut reader).unwrap();
         assert_eq!(send, recv);
     }
 }
@@ -4,16 +4,38 @@
 //! Streams are used to receive data from a socket because they allow for type safety and easy access to the data.
 
 use std::{
-    mem::{self, ManuallyDrop},
-    sync::{Arc, Mutex},
+    collections::VecDeque,
+    fmt::Debug,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::Duration,
 };
 
 /// A stream of data received from a socket. This is used to receive data from a socket.
 #[derive(Debug)]
 pub struct Stream<T> {
-    data: Arc<Mutex<Vec<T>>>,
-    grew: Arc<Mutex<usize>>,
-    ptr: Arc<Mutex<*mut T>>,
+    /// The items, oldest first. `get` takes from the back, and `Overflow::DropOldest` drops from the front.
+    data: Arc<Mutex<VecDeque<T>>>,
+    /// Notified by the StreamConnector whenever data is pushed, or when the stream is closed.
+    available: Arc<Condvar>,
+    /// Set by the StreamConnector when nothing more will be pushed. (e.g. the listener stopped because the peer disconnected)
+    closed: Arc<AtomicBool>,
+    /// The maximum number of items the stream holds, and what happens when it is full. None if it is unbounded.
+    capacity: Option<(usize, Overflow)>,
+}
+
+/// What a bounded stream does when an item is received while it is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// The new item is dropped, and receiving it returns a `StreamFull` error.
+    ///
+    /// If the client is listening, the listener logs a warning and keeps running.
+    Error,
+    /// The oldest item in the stream is dropped to make room for the new one.
+    DropOldest,
 }
 
 impl<T> Stream<T>
@@ -23,63 +45,190 @@ where
     /// Creates a new stream.
     pub(crate) fn new() -> Self {
         Stream {
-            data: Arc::new(Mutex::new(vec![])),
-            grew: Arc::new(Mutex::new(0)),
-            ptr: Arc::new(Mutex::new(std::ptr::null_mut())),
+            data: Arc::new(Mutex::new(VecDeque::new())),
+            available: Arc::new(Condvar::new()),
+            closed: Arc::new(AtomicBool::new(false)),
+            capacity: None,
         }
     }
-    /// Checks if the stream has grown and replaces the vec with the new vec.
-    fn check_vec(&mut self) {
-        let mut grew_by = self.grew.lock().unwrap();
-        // Check if the stream was given any data.
-        if *grew_by > 0 {
-            // Get the size and cap from the vector.
-            let mut v = self.data.lock().unwrap();
-            // Grabs the pointer to the vec that has the new elements.
-            let (ptr, len, cap) = (
-                *self.ptr.lock().unwrap(),
-                v.len() + *grew_by,
-                v.capacity() + *grew_by,
-            );
-            // Replace the old vec with a new one with correct values.
-            let replaced = mem::replace(&mut *v, unsafe { Vec::from_raw_parts(ptr, len, cap) });
-            // We need to forget the replaced vec, or else we will double free the data.
-            let _ = ManuallyDrop::new(replaced);
-            // Reset grew_by
-            *grew_by = 0;
+
+    /// Creates a new stream that holds at most `capacity` items.
+    ///
+    /// # Panics
+    /// Panics if capacity is 0.
+    pub(crate) fn with_capacity(capacity: usize, overflow: Overflow) -> Self {
+        assert!(
+            capacity > 0,
+            "A stream must be able to hold at least one item"
+        );
+        Stream {
+            capacity: Some((capacity, overflow)),
+            ..Self::new()
         }
     }
     /// Gets one item from the stream.
     pub fn get(&mut self) -> Option<T> {
-        self.check_vec();
         // We will *always* pop the data. If we retain ownership, things can go very wrong because of the way the stream is designed.
-        self.data.lock().unwrap().pop()
+        self.data.lock().unwrap().pop_back()
+    }
+
+    /// Gets one item from the stream, blocking until one is available.
+    pub fn get_blocking(&mut self) -> T {
+        let mut data = self.data.lock().unwrap();
+        loop {
+            if let Some(item) = data.pop_back() {
+                return item;
+            }
+            data = self.available.wait(data).unwrap();
+        }
+    }
+
+    /// Gets one item from the stream, blocking for at most `timeout` until one is available.
+    /// Returns None if nothing was received in time.
+    pub fn get_timeout(&mut self, timeout: Duration) -> Option<T> {
+        let data = self.data.lock().unwrap();
+        let (mut data, _) = self
+            .available
+            .wait_timeout_while(data, timeout, |data| data.is_empty())
+            .unwrap();
+        data.pop_back()
+    }
+
+    /// Turns the stream into an iterator that blocks until each item is received.
+    ///
+    /// The iterator ends once the stream is empty and closed. A stream is closed when the listener stops because of an error
+    /// (e.g. the peer disconnected), or when the client it belongs to is dropped.
+    /// Unlike `get`, items are yielded in the order they were received, oldest first.
+    pub fn into_blocking_iter(self) -> impl Iterator<Item = T> {
+        std::iter::from_fn(move || {
+            let mut data = self.data.lock().unwrap();
+            loop {
+                if let Some(item) = data.pop_front() {
+                    return Some(item);
+                }
+                // The connector sets this while holding the lock, so the notification can't be missed.
+                if self.closed.load(Ordering::Acquire) {
+                    return None;
+                }
+                data = self.available.wait(data).unwrap();
+            }
+        })
+    }
+
+    /// Returns true if nothing more will be pushed to the stream. (see [`Stream::into_blocking_iter`])
+    ///
+    /// Items that were received before it was closed can still be taken from it.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the item that `get` would return next, without removing it.
+    ///
+    /// The returned guard holds the lock on the stream, so nothing can be pushed to the stream until it is dropped.
+    pub fn peek(&mut self) -> Option<Peek<'_, T>> {
+        let data = self.data.lock().unwrap();
+        if data.is_empty() {
+            None
+        } else {
+            Some(Peek { data })
+        }
+    }
+
+    /// Takes every item currently in the stream, in the order they were received.
+    /// This leaves the stream empty.
+    pub fn drain(&mut self) -> Vec<T> {
+        std::mem::take(&mut *self.data.lock().unwrap()).into()
+    }
+
+    /// Removes every item in the stream.
+    ///
+    /// The stream stays connected, so anything received afterward is still pushed to it.
+    pub fn clear(&mut self) {
+        self.data.lock().unwrap().clear();
+    }
+
+    /// Returns an iterator that gets items from the stream until it is empty.
+    /// Items are yielded in the same order as `get`.
+    pub fn iter(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.get())
     }
 
     /// Gets the count of items in the stream.
+    ///
+    /// The StreamConnector pushes received items straight into the stream's vec, so this always counts every item
+    /// that `get` can return. Nothing is pending anywhere else.
     pub fn len(&self) -> usize {
-        self.data.lock().unwrap().len() + *self.grew.lock().unwrap()
+        self.data.lock().unwrap().len()
+    }
+
+    /// Returns true if there are no items in the stream. This is equivalent to `len() == 0`.
+    pub fn is_empty(&self) -> bool {
+        self.data.lock().unwrap().is_empty()
     }
-    /// Gets the underlying vec.
-    pub fn get_vec(&self) -> Arc<Mutex<Vec<T>>> {
+    /// Gets the underlying items, oldest first.
+    pub fn get_vec(&self) -> Arc<Mutex<VecDeque<T>>> {
         self.data.clone()
     }
-    pub fn get_ptr(&self) -> Arc<Mutex<*mut T>> {
-        self.ptr.clone()
+    /// Gets the maximum number of items the stream holds, and what happens when it is full.
+    pub(crate) fn get_capacity(&self) -> Option<(usize, Overflow)> {
+        self.capacity
     }
-    /// Sets the amount of items to grow the buffer by.
-    pub(crate) fn get_grow_by(&self) -> Arc<Mutex<usize>> {
-        self.grew.clone()
+    /// Gets the condvar that is notified when data is pushed.
+    pub(crate) fn get_available(&self) -> Arc<Condvar> {
+        self.available.clone()
+    }
+    /// Gets the flag that is set when the stream is closed.
+    pub(crate) fn get_closed(&self) -> Arc<AtomicBool> {
+        self.closed.clone()
     }
     /// Gets the type id of T
-    pub(crate) fn get_type_id(self) -> u32 {
+    pub(crate) fn get_type_id(self) -> u64 {
         crate::hash_type_id::<T>()
     }
 }
 
+/// Clones share the same items, so each item is taken by only one of them. (e.g. for several threads handling one type of packet)
+///
+/// Every operation takes the lock on the items, so it is safe to take items from several clones at once.
+/// The capacity is part of the items, so a clone has the same capacity as the stream it was cloned from.
+impl<T> Clone for Stream<T> {
+    fn clone(&self) -> Self {
+        Stream {
+            data: self.data.clone(),
+            available: self.available.clone(),
+            closed: self.closed.clone(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+/// A reference to the next item in a stream. Returned by `Stream::peek`.
+pub struct Peek<'a, T> {
+    // Always non-empty.
+    data: MutexGuard<'a, VecDeque<T>>,
+}
+
+impl<T> Deref for Peek<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // get pops from the back, so the next item is the last one.
+        self.data.back().unwrap()
+    }
+}
+
+impl<T: Debug> Debug for Peek<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Peek").field(&**self).finish()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Stream;
+    use std::time::Duration;
+
+    use super::{Overflow, Stream};
+    use crate::{net::StreamConnector, PacketHeader, Sendable};
 
     #[test]
     fn test_new_stream() {
@@ -91,9 +240,204 @@ mod test {
         let mut stream = Stream::<u32>::new();
         let binding = stream.get_vec();
         let mut stream_input = binding.lock().unwrap();
-        stream_input.push(9);
+        stream_input.push_back(9);
         drop(stream_input);
         assert_eq!(stream.get().unwrap(), 9);
         assert_eq!(stream.len(), 0);
     }
+
+    #[test]
+    fn test_len() {
+        let mut stream = Stream::<u32>::new();
+        let mut connector = StreamConnector::new(&stream);
+        assert!(stream.is_empty());
+        for i in 0..3u32 {
+            connector
+                .push(&i.send(), unsafe { PacketHeader::new(4) })
+                .unwrap();
+        }
+        assert_eq!(stream.len(), 3);
+        assert!(!stream.is_empty());
+        stream.get();
+        assert_eq!(stream.len(), 2);
+        stream.drain();
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut stream = Stream::<u32>::new();
+        let mut connector = StreamConnector::new(&stream);
+        for i in 0..5u32 {
+            connector
+                .push(&i.send(), unsafe { PacketHeader::new(4) })
+                .unwrap();
+        }
+        assert_eq!(stream.drain(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(stream.len(), 0);
+        assert_eq!(stream.get(), None);
+        // The connector should still push to the drained stream.
+        connector
+            .push(&5u32.send(), unsafe { PacketHeader::new(4) })
+            .unwrap();
+        assert_eq!(stream.get(), Some(5));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut stream = Stream::<u32>::new();
+        let mut connector = StreamConnector::new(&stream);
+        for i in 0..3u32 {
+            connector
+                .push(&i.send(), unsafe { PacketHeader::new(4) })
+                .unwrap();
+        }
+        stream.clear();
+        assert_eq!(stream.len(), 0);
+        assert_eq!(stream.get(), None);
+        for i in 3..5u32 {
+            connector
+                .push(&i.send(), unsafe { PacketHeader::new(4) })
+                .unwrap();
+        }
+        assert_eq!(stream.drain(), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_capacity_error() {
+        let mut stream = Stream::<String>::with_capacity(2, Overflow::Error);
+        let mut connector = StreamConnector::new(&stream);
+        for s in ["a", "b", "c"] {
+            let s = s.to_string();
+            let result = connector.push(&s.send(), unsafe { PacketHeader::new(s.size()) });
+            assert_eq!(result.is_ok(), s != "c");
+        }
+        assert_eq!(stream.drain(), vec!["a", "b"]);
+        // There is room again once the stream has been emptied.
+        connector
+            .push(&"d".to_string().send(), unsafe { PacketHeader::new(5) })
+            .unwrap();
+        assert_eq!(stream.get(), Some("d".to_string()));
+    }
+
+    #[test]
+    fn test_capacity_drop_oldest() {
+        let mut stream = Stream::<String>::with_capacity(2, Overflow::DropOldest);
+        let mut connector = StreamConnector::new(&stream);
+        for s in ["a", "b", "c", "d"] {
+            let s = s.to_string();
+            connector
+                .push(&s.send(), unsafe { PacketHeader::new(s.size()) })
+                .unwrap();
+        }
+        assert_eq!(stream.drain(), vec!["c", "d"]);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut stream = Stream::<String>::new();
+        let mut connector = StreamConnector::new(&stream);
+        for s in ["a", "b", "c"] {
+            let s = s.to_string();
+            connector
+                .push(&s.send(), unsafe { PacketHeader::new(s.size()) })
+                .unwrap();
+        }
+        assert_eq!(stream.iter().count(), 3);
+        assert_eq!(stream.len(), 0);
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut stream = Stream::<u32>::new();
+        assert!(stream.peek().is_none());
+        let mut connector = StreamConnector::new(&stream);
+        for i in [1u32, 2] {
+            connector
+                .push(&i.send(), unsafe { PacketHeader::new(4) })
+                .unwrap();
+        }
+        assert_eq!(*stream.peek().unwrap(), 2);
+        assert_eq!(*stream.peek().unwrap(), 2);
+        assert_eq!(stream.get(), Some(2));
+        assert_eq!(stream.get(), Some(1));
+    }
+
+    #[test]
+    fn test_get_blocking() {
+        let mut stream = Stream::<u32>::new();
+        let mut connector = StreamConnector::new(&stream);
+        let pusher = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            connector.push(&7u32.send(), unsafe { PacketHeader::new(4) })
+        });
+        assert_eq!(stream.get_blocking(), 7);
+        pusher.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_get_timeout() {
+        let mut stream = Stream::<u32>::new();
+        let start = std::time::Instant::now();
+        assert_eq!(stream.get_timeout(Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        stream.get_vec().lock().unwrap().push_back(3);
+        assert_eq!(stream.get_timeout(Duration::from_millis(50)), Some(3));
+    }
+
+    #[test]
+    fn test_into_blocking_iter() {
+        let stream = Stream::<u32>::new();
+        let mut connector = StreamConnector::new(&stream);
+        let pusher = std::thread::spawn(move || {
+            for i in 0..3u32 {
+                std::thread::sleep(Duration::from_millis(10));
+                connector
+                    .push(&i.send(), unsafe { PacketHeader::new(4) })
+                    .unwrap();
+            }
+            // Dropping the connector closes the stream.
+        });
+        let items: Vec<u32> = stream.into_blocking_iter().collect();
+        assert_eq!(items, vec![0, 1, 2]);
+        pusher.join().unwrap();
+
+        // Items that were queued before iterating are also yielded oldest first.
+        let stream = Stream::<u32>::new();
+        let mut connector = StreamConnector::new(&stream);
+        for i in 0..3u32 {
+            connector
+                .push(&i.send(), unsafe { PacketHeader::new(4) })
+                .unwrap();
+        }
+        drop(connector);
+        let items: Vec<u32> = stream.into_blocking_iter().collect();
+        assert_eq!(items, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_clone_consumers() {
+        let stream = Stream::<u32>::new();
+        let mut connector = StreamConnector::new(&stream);
+        let consumers: Vec<_> = (0..2)
+            .map(|_| {
+                let stream = stream.clone();
+                std::thread::spawn(move || stream.into_blocking_iter().collect::<Vec<u32>>())
+            })
+            .collect();
+        drop(stream);
+        for i in 0..1000u32 {
+            connector
+                .push(&i.send(), unsafe { PacketHeader::new(4) })
+                .unwrap();
+        }
+        // Dropping the connector closes the stream, which ends both iterators.
+        drop(connector);
+        let mut items: Vec<u32> = consumers
+            .into_iter()
+            .flat_map(|consumer| consumer.join().unwrap())
+            .collect();
+        items.sort_unstable();
+        assert_eq!(items, (0..1000).collect::<Vec<u32>>());
+    }
 }
@@ -1,10 +1,10 @@
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 
-use quote::{quote, ToTokens};
-use syn::{Data, Field, Ident, Index, Type};
+use quote::{format_ident, quote, ToTokens};
+use syn::{parse_quote, Data, Field, Ident, Index, Type};
 
-#[proc_macro_derive(Sendable)]
+#[proc_macro_derive(Sendable, attributes(error_type))]
 pub fn derive_sendable(input: TokenStream) -> TokenStream {
     // Parse the input tokens into a syntax tree
     let input = syn::parse(input);
@@ -15,23 +15,20 @@ pub fn derive_sendable(input: TokenStream) -> TokenStream {
     // Build the impl
     let expanded = impl_sendable(&input.unwrap());
     // Return the generated impl
-    TokenStream::from(expanded)
+    expanded
 }
 
 fn impl_sendable(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
-    let name = &ast.ident;
-    // Get the fields of the struct
-    let fields = match &ast.data {
-        syn::Data::Struct(data) => &data.fields,
-        _ => panic!("Sendable can only be derived for structs"),
+    let error_type = match parse_error_type(ast) {
+        Ok(error_type) => error_type,
+        Err(e) => return e.to_compile_error().into(),
     };
-
-    let data = {
-        if let Data::Struct(data) = &ast.data {
-            data
-        } else {
-            unreachable!()
-        }
+    let name = &ast.ident;
+    // Get the fields of the struct, or the fields of every variant of the enum.
+    let fields: Vec<&Field> = match &ast.data {
+        Data::Struct(data) => data.fields.iter().collect(),
+        Data::Enum(data) => data.variants.iter().flat_map(|v| v.fields.iter()).collect(),
+        Data::Union(_) => panic!("Sendable can only be derived for structs and enums"),
     };
 
     let mut type_count: Vec<(Type, u32)> = Vec::new();
@@ -52,9 +49,18 @@ fn impl_sendable(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
         }
     }
 
+    // Every type parameter has to be Sendable for the fields that use it to be sendable.
+    let mut generics = ast.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(lazuli_core::Sendable));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
     // Check that all fields implement Sendable.
+    // Fields of generic types can't be checked outside of the impl, so the bounds above cover those.
     let field_impl_check: TokenStream2 = type_count
         .iter()
+        .filter(|_| ast.generics.params.is_empty())
         .map(|(ty, _)| {
             quote! {
                 const _: fn() = || {
@@ -64,18 +70,73 @@ fn impl_sendable(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
             }
         })
         .collect();
-    // Generate the size function. (Take the size of each field and sum them up)
-    let field_size: TokenStream2 = generate_size(&data);
 
-    // Generate the send fn. (Serialize each field and append them to a Vec<u8>)
-    let send_gen: TokenStream2 = generate_send(&data);
-    // Generate the recv fn. (Deserialize each field from a dyn Read)
-    let recv_gen: TokenStream2 = generate_recv(&data, &name);
+    // A struct is constant size if every field is. An enum is only constant size if it has no fields at all,
+    // because otherwise the size depends on the variant.
+    let size_const = match &ast.data {
+        Data::Struct(_) => {
+            let types = type_count.iter().map(|(ty, _)| ty);
+            quote! { true #(&& <#types as lazuli_core::Sendable>::SIZE_CONST)* }
+        }
+        Data::Enum(data) => {
+            let unit_only = data.variants.iter().all(|v| v.fields.is_empty());
+            quote! { #unit_only }
+        }
+        Data::Union(_) => unreachable!(),
+    };
+
+    let (field_size, send_gen, recv_gen) = match &ast.data {
+        Data::Struct(data) => (
+            // Generate the size function. (Take the size of each field and sum them up)
+            generate_size(data),
+            // Generate the send fn. (Serialize each field and append them to a Vec<u8>)
+            generate_send(data),
+            // Generate the recv fn. (Deserialize each field from a dyn Read)
+            generate_recv(&data.fields, &name.to_token_stream()),
+        ),
+        Data::Enum(data) => {
+            let tagged = enum_tag(ast).and_then(|tag| {
+                let discriminants = enum_discriminants(data, &tag)?;
+                Ok((tag, discriminants))
+            });
+            let (tag, discriminants) = match tagged {
+                Ok(tagged) => tagged,
+                Err(e) => return e.to_compile_error().into(),
+            };
+            (
+                generate_enum_size(data, name, &tag),
+                generate_enum_send(data, name, &discriminants),
+                generate_enum_recv(data, name, &tag, &discriminants),
+            )
+        }
+        Data::Union(_) => unreachable!(),
+    };
+
+    // With an error_type, the same fields are also received by an inherent method that returns that error instead.
+    let recv_with_error = error_type.map(|error_type| {
+        let doc = format!(
+            "Receives the value like `Sendable::recv`, but returns a `{}`, converted from the `LazuliError` with `From`.",
+            quote! {#error_type}
+        );
+        quote! {
+            impl #impl_generics #name #ty_generics #where_clause {
+                #[doc = #doc]
+                pub fn recv_with_error(
+                    data: &mut dyn std::io::Read,
+                ) -> ::std::result::Result<Self, #error_type> {
+                    Ok(
+                        #recv_gen
+                    )
+                }
+            }
+        }
+    });
     quote! {
 
         #field_impl_check // Check that all fields implement Sendable
 
-        impl lazuli_core::Sendable for #name {
+        impl #impl_generics lazuli_core::Sendable for #name #ty_generics #where_clause {
+            const SIZE_CONST: bool = #size_const;
 
             fn size(&self) -> u32 {
                 let mut size = 0;
@@ -89,15 +150,35 @@ fn impl_sendable(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
                 data
             }
 
-            fn recv(data: &mut dyn std::io::Read) -> Result<Self, ::std::io::Error> {
+            fn recv(data: &mut dyn std::io::Read) -> lazuli_core::Result<Self> {
                 Ok(
                     #recv_gen
                 )
             }
         }
+
+        #recv_with_error
     }
     .into()
 }
+/// Gets the type from the `error_type` attribute, if there is one.
+///
+/// `Sendable::recv` always returns `LazuliError`, so the error type is used for a separate `recv_with_error` method.
+/// The type must implement `From<LazuliError>`, which is what the errors from each field are converted with.
+fn parse_error_type(ast: &syn::DeriveInput) -> syn::Result<Option<Type>> {
+    let mut error_type = None;
+    for attr in ast.attrs.iter().filter(|a| a.path().is_ident("error_type")) {
+        if error_type.is_some() {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "error_type can only be specified once",
+            ));
+        }
+        error_type = Some(attr.parse_args()?);
+    }
+    Ok(error_type)
+}
+
 /// Gets the identifier for each field and executes transform on it.
 fn field_struct_gen(
     transform: fn(&TokenStream2, &Field) -> TokenStream2,
@@ -150,9 +231,10 @@ fn generate_send(input: &syn::DataStruct) -> TokenStream2 {
     )
 }
 
-fn generate_recv(input: &syn::DataStruct, name: &Ident) -> TokenStream2 {
+/// Generates an expression that constructs `path` from fields read out of `data`.
+fn generate_recv(fields: &syn::Fields, path: &TokenStream2) -> TokenStream2 {
     // we cant use field_struct_gen here because named and unnamed fields are handled differently
-    match &input.fields {
+    match fields {
         syn::Fields::Named(ref named) => {
             let fields: TokenStream2 = named
                 .named
@@ -166,7 +248,7 @@ fn generate_recv(input: &syn::DataStruct, name: &Ident) -> TokenStream2 {
                 })
                 .collect();
             quote! {
-                #name {
+                #path {
                     #fields
                 }
             }
@@ -175,8 +257,7 @@ fn generate_recv(input: &syn::DataStruct, name: &Ident) -> TokenStream2 {
             let fields: TokenStream2 = unnamed
                 .unnamed
                 .iter()
-                .enumerate()
-                .map(|(_, field)| {
+                .map(|field| {
                     let ty = &field.ty;
                     quote! {
                         <#ty as lazuli_core::Sendable>::recv(data)?,
@@ -184,14 +265,231 @@ fn generate_recv(input: &syn::DataStruct, name: &Ident) -> TokenStream2 {
                 })
                 .collect();
             quote! {
-                #name (
+                #path (
                     #fields
                 )
             }
         }
         syn::Fields::Unit => {
             quote! {
-                #name
+                #path
+            }
+        }
+    }
+}
+
+/// Generates a pattern that binds every field of a variant, along with the bound identifiers.
+fn variant_bindings(variant: &syn::Variant, name: &Ident) -> (TokenStream2, Vec<Ident>) {
+    let variant_name = &variant.ident;
+    match &variant.fields {
+        syn::Fields::Named(named) => {
+            let idents: Vec<Ident> = named
+                .named
+                .iter()
+                .map(|field| field.ident.clone().unwrap())
+                .collect();
+            (quote! { #name::#variant_name { #(#idents),* } }, idents)
+        }
+        syn::Fields::Unnamed(unnamed) => {
+            let idents: Vec<Ident> = (0..unnamed.unnamed.len())
+                .map(|i| format_ident!("field_{}", i))
+                .collect();
+            (quote! { #name::#variant_name ( #(#idents),* ) }, idents)
+        }
+        syn::Fields::Unit => (quote! { #name::#variant_name }, Vec::new()),
+    }
+}
+
+/// The integer type that the discriminant of an enum is sent as.
+struct Tag {
+    ty: Ident,
+    min: i128,
+    max: i128,
+}
+
+/// Gets the tag of an enum from its integer `#[repr]` (e.g. a `#[repr(u8)]` enum sends its discriminant as a u8).
+///
+/// Enums without an integer repr send their discriminant as a u32.
+fn enum_tag(ast: &syn::DeriveInput) -> syn::Result<Tag> {
+    let mut tag = None;
+    for attr in ast.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            let Some(ident) = meta.path.get_ident() else {
+                return Ok(());
+            };
+            let range = match ident.to_string().as_str() {
+                "u8" => Some((0, u8::MAX as i128)),
+                "u16" => Some((0, u16::MAX as i128)),
+                "u32" => Some((0, u32::MAX as i128)),
+                "u64" => Some((0, u64::MAX as i128)),
+                "i8" => Some((i8::MIN as i128, i8::MAX as i128)),
+                "i16" => Some((i16::MIN as i128, i16::MAX as i128)),
+                "i32" => Some((i32::MIN as i128, i32::MAX as i128)),
+                "i64" => Some((i64::MIN as i128, i64::MAX as i128)),
+                "usize" | "isize" | "u128" | "i128" => {
+                    return Err(meta.error(
+                        "Sendable enums can't use this repr, because its size on the wire would depend on the platform or not fit in a u64",
+                    ))
+                }
+                _ => None,
+            };
+            if let Some((min, max)) = range {
+                tag = Some(Tag {
+                    ty: ident.clone(),
+                    min,
+                    max,
+                });
+            }
+            // Skip the arguments of anything else in the repr. (e.g. align(8))
+            if meta.input.peek(syn::token::Paren) {
+                let _args;
+                syn::parenthesized!(_args in meta.input);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(tag.unwrap_or_else(|| Tag {
+        ty: format_ident!("u32"),
+        min: 0,
+        max: u32::MAX as i128,
+    }))
+}
+
+/// Gets the on-wire discriminant of each variant, as a literal of the tag type.
+///
+/// Like Rust, a variant without an explicit discriminant takes the one after the previous variant's, starting at 0.
+/// This means the discriminants written on an enum are the ones that are sent, so variants can be reordered without
+/// changing the protocol.
+fn enum_discriminants(input: &syn::DataEnum, tag: &Tag) -> syn::Result<Vec<syn::LitInt>> {
+    let mut next = 0;
+    let mut discriminants = Vec::with_capacity(input.variants.len());
+    for variant in &input.variants {
+        let discriminant = match &variant.discriminant {
+            Some((_, expr)) => parse_discriminant(expr)?,
+            None => next,
+        };
+        if discriminant < tag.min || discriminant > tag.max {
+            return Err(syn::Error::new_spanned(
+                variant,
+                format!(
+                    "The discriminant of this variant does not fit in a {}",
+                    tag.ty
+                ),
+            ));
+        }
+        discriminants.push(syn::LitInt::new(
+            &format!("{}{}", discriminant, tag.ty),
+            variant.ident.span(),
+        ));
+        next = discriminant + 1;
+    }
+    Ok(discriminants)
+}
+
+/// Parses an explicit discriminant, which must be an integer or byte literal, optionally negated.
+fn parse_discriminant(expr: &syn::Expr) -> syn::Result<i128> {
+    let error = || {
+        syn::Error::new_spanned(
+            expr,
+            "Sendable only supports discriminants that are integer or byte literals",
+        )
+    };
+    match expr {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Int(int),
+            ..
+        }) => int.base10_parse::<i128>().map_err(|_| error()),
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Byte(byte),
+            ..
+        }) => Ok(byte.value() as i128),
+        syn::Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        }) => Ok(-parse_discriminant(expr)?),
+        syn::Expr::Group(group) => parse_discriminant(&group.expr),
+        _ => Err(error()),
+    }
+}
+
+fn generate_enum_size(input: &syn::DataEnum, name: &Ident, tag: &Tag) -> TokenStream2 {
+    let tag = &tag.ty;
+    let arms: TokenStream2 = input
+        .variants
+        .iter()
+        .map(|variant| {
+            let (pattern, idents) = variant_bindings(variant, name);
+            quote! {
+                #pattern => {
+                    #(size += lazuli_core::Sendable::size(#idents);)*
+                }
+            }
+        })
+        .collect();
+    quote! {
+        size += ::std::mem::size_of::<#tag>() as u32;
+        match self {
+            #arms
+        }
+    }
+}
+
+fn generate_enum_send(
+    input: &syn::DataEnum,
+    name: &Ident,
+    discriminants: &[syn::LitInt],
+) -> TokenStream2 {
+    let arms: TokenStream2 = input
+        .variants
+        .iter()
+        .zip(discriminants)
+        .map(|(variant, discriminant)| {
+            let (pattern, idents) = variant_bindings(variant, name);
+            quote! {
+                #pattern => {
+                    data.extend(lazuli_core::Sendable::send(&#discriminant));
+                    #(data.extend(lazuli_core::Sendable::send(#idents));)*
+                }
+            }
+        })
+        .collect();
+    quote! {
+        match self {
+            #arms
+        }
+    }
+}
+
+fn generate_enum_recv(
+    input: &syn::DataEnum,
+    name: &Ident,
+    tag: &Tag,
+    discriminants: &[syn::LitInt],
+) -> TokenStream2 {
+    let tag = &tag.ty;
+    let arms: TokenStream2 = input
+        .variants
+        .iter()
+        .zip(discriminants)
+        .map(|(variant, discriminant)| {
+            let variant_name = &variant.ident;
+            let construct = generate_recv(&variant.fields, &quote! { #name::#variant_name });
+            quote! {
+                #discriminant => #construct,
+            }
+        })
+        .collect();
+    let error = format!("Invalid discriminant for {}: {{}}", name);
+    quote! {
+        match <#tag as lazuli_core::Sendable>::recv(data)? {
+            #arms
+            discriminant => {
+                return Err(lazuli_core::LazuliError::from(::std::io::Error::new(
+                    ::std::io::ErrorKind::InvalidData,
+                    format!(#error, discriminant),
+                ))
+                .into())
             }
         }
     }
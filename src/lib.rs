@@ -7,7 +7,7 @@ mod tests {
 
     use lazuli_core::Sendable;
 
-    #[derive(lazuli_derive::Sendable, PartialEq, Debug)]
+    #[derive(lazuli_derive::Sendable, PartialEq, Debug, Clone)]
     struct TestSendable {
         uint32_1: u32,
         uint32_2: u32,
@@ -106,6 +106,56 @@ mod tests {
         let test2 = TupleTest::recv(&mut p).unwrap();
         assert_eq!(test, test2);
     }
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    enum UnitEnum {
+        A,
+        B,
+    }
+
+    #[test]
+    fn test_size_const() {
+        // SIZE_CONST is an associated const, so it can be used in const contexts.
+        const _: () = assert!(TestSendable::SIZE_CONST && !Wrapper::<String>::SIZE_CONST);
+        let constant = [
+            TestSendable::SIZE_CONST,
+            TupleTest::SIZE_CONST,
+            TestZST::SIZE_CONST,
+            Wrapper::<u32>::SIZE_CONST,
+            UnitEnum::SIZE_CONST,
+        ];
+        assert_eq!(constant, [true; 5]);
+        let variable = [
+            TestSendable2::SIZE_CONST,
+            Wrapper::<String>::SIZE_CONST,
+            TestEnum::SIZE_CONST,
+        ];
+        assert_eq!(variable, [false; 3]);
+        let items = vec![UnitEnum::A, UnitEnum::B];
+        assert_eq!(items.size() as usize, items.send().len());
+        let items = vec![TestSendable::new(1, 2); 3];
+        assert_eq!(items.size() as usize, items.send().len());
+    }
+
+    #[test]
+    fn test_truncated_recv() {
+        // Every field recv should return its error instead of panicking.
+        let data = TestSendable3 {
+            a: TestSendable2::new(3),
+            b: vec![1, 2, 3],
+            c: 4,
+            d: vec![TestSendable::new(1, 2)],
+        }
+        .send();
+        for len in 0..data.len() {
+            let mut p = Cursor::new(&data[..len]);
+            assert!(TestSendable3::recv(&mut p).is_err(), "len = {}", len);
+        }
+        let data = TupleTest(1, 2).send();
+        let mut p = Cursor::new(&data[..6]);
+        let err = TupleTest::recv(&mut p).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
     #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
     struct TestZST();
 
@@ -117,4 +167,221 @@ mod tests {
         let test2 = TestZST::recv(&mut p).unwrap();
         assert_eq!(test, test2);
     }
+
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    struct TestMarker {
+        value: u32,
+        marker: std::marker::PhantomData<String>,
+    }
+
+    #[test]
+    fn test_phantom_data() {
+        let test = TestMarker {
+            value: 7,
+            marker: std::marker::PhantomData,
+        };
+        let data = test.send();
+        assert_eq!(data, 7u32.send());
+        assert_eq!(test.size() as usize, data.len());
+        let mut p = Cursor::new(data);
+        let test2 = TestMarker::recv(&mut p).unwrap();
+        assert_eq!(test, test2);
+    }
+
+    /// An application error, which lazuli errors are converted into.
+    #[derive(Debug)]
+    enum MyError {
+        Lazuli(lazuli_core::LazuliError),
+    }
+
+    impl From<lazuli_core::LazuliError> for MyError {
+        fn from(e: lazuli_core::LazuliError) -> Self {
+            MyError::Lazuli(e)
+        }
+    }
+
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    #[error_type(MyError)]
+    struct CustomError {
+        a: u32,
+        b: String,
+    }
+
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    #[error_type(MyError)]
+    enum CustomErrorEnum {
+        A(u32),
+        B,
+    }
+
+    #[test]
+    fn test_error_type() {
+        let value = CustomError {
+            a: 5,
+            b: "five".to_string(),
+        };
+        let data = value.send();
+        let received = CustomError::recv_with_error(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(received, value);
+        // recv still returns a LazuliError.
+        assert_eq!(CustomError::recv(&mut Cursor::new(&data)).unwrap(), value);
+
+        let err: MyError = CustomError::recv_with_error(&mut Cursor::new(&data[..2])).unwrap_err();
+        let MyError::Lazuli(err) = err;
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let data = CustomErrorEnum::A(3).send();
+        let received = CustomErrorEnum::recv_with_error(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(received, CustomErrorEnum::A(3));
+        let err = CustomErrorEnum::recv_with_error(&mut Cursor::new(7u32.send())).unwrap_err();
+        let MyError::Lazuli(err) = err;
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    struct Wrapper<T>(T);
+
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    struct GenericPair<A, B>
+    where
+        A: Clone,
+    {
+        first: A,
+        second: Vec<B>,
+    }
+
+    #[test]
+    fn test_generic_tuple() {
+        let test = Wrapper(42u32);
+        let data = test.send();
+        assert_eq!(data, 42u32.send());
+        let mut p = Cursor::new(data);
+        let test2 = Wrapper::<u32>::recv(&mut p).unwrap();
+        assert_eq!(test, test2);
+    }
+
+    #[test]
+    fn test_generic_struct() {
+        let test = GenericPair {
+            first: "Hello, World!".to_string(),
+            second: vec![Wrapper(1u8), Wrapper(2u8)],
+        };
+        let data = test.send();
+        assert_eq!(data.len(), test.size() as usize);
+        let mut p = Cursor::new(data);
+        let test2 = GenericPair::<String, Wrapper<u8>>::recv(&mut p).unwrap();
+        assert!(p.position() == p.get_ref().len() as u64);
+        assert_eq!(test, test2);
+    }
+
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    enum TestEnum {
+        Unit,
+        Tuple(u32, String),
+        Struct { a: i32, b: Vec<u8> },
+    }
+
+    #[test]
+    fn test_enum() {
+        let values = [
+            TestEnum::Unit,
+            TestEnum::Tuple(1, "Hello, World!".to_string()),
+            TestEnum::Struct {
+                a: -4,
+                b: vec![1, 2, 3],
+            },
+        ];
+        for (i, value) in values.into_iter().enumerate() {
+            let data = value.send();
+            assert_eq!(data.len(), value.size() as usize);
+            assert_eq!(data[..4], (i as u32).to_be_bytes());
+            let mut p = Cursor::new(data);
+            let received = TestEnum::recv(&mut p).unwrap();
+            assert!(p.position() == p.get_ref().len() as u64);
+            assert_eq!(value, received);
+        }
+    }
+
+    #[test]
+    fn test_enum_invalid_discriminant() {
+        let mut p = Cursor::new(7u32.send());
+        let err = TestEnum::recv(&mut p).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_derived_stream() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = lazuli_core::Client::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = lazuli_core::Client::from_stream(listener.accept().unwrap().0);
+        let mut stream = client.stream::<TestSendable2>();
+        server.send(&TestSendable2::new(3)).unwrap();
+        client.recv().unwrap();
+        assert_eq!(stream.get().unwrap(), TestSendable2::new(3));
+    }
+
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    #[repr(u8)]
+    enum Op {
+        A = 1,
+        B = 7,
+        C,
+        D = b'd',
+    }
+
+    // The same as Op, with the variants in a different order.
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    #[repr(u8)]
+    enum ReorderedOp {
+        D = b'd',
+        B = 7,
+        C,
+        A = 1,
+    }
+
+    #[test]
+    fn test_explicit_discriminants() {
+        let pairs = [
+            (Op::A, ReorderedOp::A, 1u8),
+            (Op::B, ReorderedOp::B, 7),
+            (Op::C, ReorderedOp::C, 8),
+            (Op::D, ReorderedOp::D, b'd'),
+        ];
+        for (op, reordered, discriminant) in pairs {
+            let data = op.send();
+            // repr(u8), so the discriminant is sent as a single byte.
+            assert_eq!(data, vec![discriminant]);
+            assert_eq!(op.size(), 1);
+            assert_eq!(data, reordered.send());
+            assert_eq!(Op::recv(&mut Cursor::new(data)).unwrap(), op);
+        }
+        // 0 would be A's discriminant if they were positional.
+        let err = Op::recv(&mut Cursor::new(vec![0])).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[derive(lazuli_derive::Sendable, Debug, PartialEq)]
+    #[repr(i16)]
+    enum Signed {
+        Low = -300,
+        Minus,
+        Zero = 0,
+        High(u8) = 2,
+    }
+
+    #[test]
+    fn test_signed_discriminants() {
+        let values = [
+            (Signed::Low, -300i16),
+            (Signed::Minus, -299),
+            (Signed::Zero, 0),
+            (Signed::High(5), 2),
+        ];
+        for (value, discriminant) in values {
+            let data = value.send();
+            assert_eq!(data[..2], discriminant.to_be_bytes());
+            assert_eq!(data.len(), value.size() as usize);
+            assert_eq!(Signed::recv(&mut Cursor::new(data)).unwrap(), value);
+        }
+    }
 }